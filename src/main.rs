@@ -1,390 +1,9723 @@
-#[cfg(not(debug_assertions))]
+#[cfg(all(windows, not(debug_assertions)))]
 #[macro_use]
 extern crate windows_service;
 
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::env::{args, var};
 use std::error::Error;
 use std::ffi::OsStr;
-use std::fs::{read_dir, read_to_string};
-use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::mpsc;
+use std::fmt;
+use std::fs::{
+    canonicalize, copy, create_dir_all, read, read_dir, read_to_string, remove_file, rename, File,
+    OpenOptions, TryLockError,
+};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{absolute, Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use notify::event::{
+    AccessKind, AccessMode, CreateKind, DataChange, ModifyKind, RemoveKind, RenameMode,
+};
 use notify::{
-    recommended_watcher, Event, EventKind, RecursiveMode, Result as NotifyResult, Watcher,
+    recommended_watcher, Config as NotifyConfig, ErrorKind as NotifyErrorKind, Event, EventKind,
+    PollWatcher, RecursiveMode, Result as NotifyResult, Watcher,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sheet_wizard::matching::{
+    day_ganzhi, expected_hidden_path, generate_tiangan_map, get_filename_from_event,
+    get_filename_with_largest_tiangan, get_top_n_files, get_versioned_files, has_non_utf8_name,
+    has_unrecognized_version, is_same_file, matches_filename_pattern, FileQuery, LatestFileCache,
+    NumericMatcher, Selection, TianganMatcher, UnrecognizedVersion, VersionMatcher,
+};
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
 use toml::from_str;
-use win_toast_notify::{Duration as ToastDuration, WinToastNotify};
-
-#[cfg(not(debug_assertions))]
-use std::env::var;
+#[cfg(windows)]
+use win_toast_notify::{CropCircle, Duration as ToastDuration, WinToastNotify};
 
-#[cfg(not(debug_assertions))]
+#[cfg(all(windows, not(debug_assertions)))]
 use std::ffi::OsString;
 
-#[cfg(not(debug_assertions))]
-use std::time::Duration;
+#[cfg(all(windows, not(debug_assertions)))]
+use std::sync::OnceLock;
 
-#[cfg(not(debug_assertions))]
+#[cfg(all(windows, not(debug_assertions)))]
 use windows_service::service::{
-    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
 };
 
-#[cfg(not(debug_assertions))]
-use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+#[cfg(all(windows, not(debug_assertions)))]
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
 
-#[cfg(not(debug_assertions))]
+#[cfg(all(windows, not(debug_assertions)))]
 use windows_service::service_dispatcher;
 
-#[cfg(debug_assertions)]
-fn print_debug(message: &str) {
-    println!("[DEBUG]: {}", message);
+#[cfg(all(windows, not(debug_assertions)))]
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+const CHILD_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const CLOSE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_SUMMARY_CHARS: usize = 200;
+const MAX_WATCHER_RESTARTS: u32 = 5;
+const WATCHER_RESTART_DELAY: Duration = Duration::from_secs(5);
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_HISTORY_COUNT: usize = 10;
+#[cfg(windows)]
+const GBK_CODE_PAGE: u32 = 936;
+const WATCH_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+#[cfg(all(windows, not(debug_assertions)))]
+const STOP_WAIT_HINT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+enum SheetWizardError {
+    Config(String),
+    MissingConfig(PathBuf),
+    Io(io::Error),
+    Watcher(notify::Error),
+    Script(String),
+    NoMatch,
+    Panic(String),
 }
 
-#[cfg(not(debug_assertions))]
-fn print_debug(_: &str) {}
+impl fmt::Display for SheetWizardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SheetWizardError::Config(message) => write!(f, "Configuration error: {}", message),
+            SheetWizardError::MissingConfig(path) => {
+                write!(f, "Configuration file {} was not found", path.display())
+            }
+            SheetWizardError::Io(e) => write!(f, "I/O error: {}", e),
+            SheetWizardError::Watcher(e) => write!(f, "Watcher error: {}", e),
+            SheetWizardError::Script(message) => write!(f, "Script error: {}", message),
+            SheetWizardError::NoMatch => write!(f, "No matching file was found"),
+            SheetWizardError::Panic(message) => write!(f, "Watcher panicked: {}", message),
+        }
+    }
+}
 
-#[derive(Deserialize)]
-struct Settings {
-    listened_directory: String,
-    filename_prefix: String,
-    hidden_filename_prefix: String,
-    ext_name: String,
-    script_directory: String,
-    script_filename: String,
-    env_name: String,
+impl Error for SheetWizardError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SheetWizardError::Io(e) => Some(e),
+            SheetWizardError::Watcher(e) => Some(e),
+            SheetWizardError::Config(_)
+            | SheetWizardError::MissingConfig(_)
+            | SheetWizardError::Script(_)
+            | SheetWizardError::NoMatch
+            | SheetWizardError::Panic(_) => None,
+        }
+    }
 }
 
-#[derive(Deserialize)]
-struct PathConfig {
-    settings: Settings,
+impl From<io::Error> for SheetWizardError {
+    fn from(e: io::Error) -> Self {
+        SheetWizardError::Io(e)
+    }
 }
 
-fn load_config(file_path: &str) -> Result<PathConfig, Box<dyn Error>> {
-    let content = read_to_string(file_path)?;
-    let config = from_str::<PathConfig>(&content)?;
+impl From<notify::Error> for SheetWizardError {
+    fn from(e: notify::Error) -> Self {
+        SheetWizardError::Watcher(e)
+    }
+}
 
-    Ok(config)
+impl From<toml::de::Error> for SheetWizardError {
+    fn from(e: toml::de::Error) -> Self {
+        SheetWizardError::Config(e.to_string())
+    }
 }
 
-fn generate_tiangan_map() -> HashMap<String, usize> {
-    let tiangan = vec!["甲", "乙", "丙", "丁", "戊", "己", "庚", "辛", "壬", "癸"];
+static IS_SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static IS_PAUSED: AtomicBool = AtomicBool::new(false);
 
-    tiangan
-        .into_iter()
-        .enumerate()
-        .map(|(i, v)| (v.to_string(), i))
-        .collect()
+thread_local! {
+    static CORRELATION_ID: RefCell<Option<String>> = const { RefCell::new(None) };
 }
 
-fn get_tiangan_from_filename(
-    filename: &str,
-    filename_prefix: &str,
-    tiangan_order: &HashMap<String, usize>,
-) -> Option<usize> {
-    if let Some(pos) = filename.strip_prefix(filename_prefix) {
-        tiangan_order.get(pos).cloned()
-    } else {
-        None
+fn set_correlation_id(correlation_id: Option<String>) {
+    CORRELATION_ID.with(|cur_id| *cur_id.borrow_mut() = correlation_id);
+}
+
+struct FileLog {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: u32,
+}
+
+static FILE_LOG: Mutex<Option<FileLog>> = Mutex::new(None);
+static LOG_LEVEL: Mutex<LogLevel> = Mutex::new(LogLevel::Error);
+static LOG_FORMAT: Mutex<LogFormat> = Mutex::new(LogFormat::Text);
+
+fn init_file_log(settings: &Settings) {
+    if let Ok(mut cur_level) = LOG_LEVEL.lock() {
+        *cur_level = settings.log_level;
+    }
+
+    if let Ok(mut cur_format) = LOG_FORMAT.lock() {
+        *cur_format = settings.log_format;
+    }
+
+    let file_log = settings.log_path.as_ref().map(|path| FileLog {
+        path: PathBuf::from(path),
+        max_bytes: settings.log_max_bytes,
+        max_files: settings.log_max_files,
+    });
+
+    if let Ok(mut cur_log) = FILE_LOG.lock() {
+        *cur_log = file_log;
     }
 }
 
-fn get_filename_with_largest_tiangan(
-    folder_path: &str,
-    filename_prefix: &str,
-    hidden_filename_prefix: &str,
-    ext_name: &str,
-    tiangan_order: &HashMap<String, usize>,
-    is_hidden_file: bool,
-) -> Option<PathBuf> {
-    read_dir(folder_path)
-        .ok()?
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| {
-            entry
-                .path()
-                .extension()
-                .map(|cur_ext| cur_ext == ext_name)
-                .unwrap_or(false)
-        })
-        .filter_map(|entry| {
-            if let Some(version) = get_tiangan_from_filename(
-                &entry.path().file_stem()?.to_string_lossy(),
-                filename_prefix,
-                tiangan_order,
-            ) {
-                Some((version, entry.path()))
-            } else {
-                None
-            }
-        })
-        .max_by_key(|(version, _)| *version)
-        .map(|(_, path)| {
-            if !is_hidden_file {
-                return path;
-            }
+fn init_fallback_log() {
+    let Some(directory) = std::env::current_exe()
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+    else {
+        return;
+    };
 
-            let new_filename = path
-                .file_name()
-                .unwrap_or(OsStr::new(""))
-                .to_string_lossy()
-                .to_string();
+    if let Ok(mut cur_log) = FILE_LOG.lock() {
+        cur_log.get_or_insert(FileLog {
+            path: directory.join("SheetWizard.log"),
+            max_bytes: default_log_max_bytes(),
+            max_files: default_log_max_files(),
+        });
+    }
+}
 
-            if new_filename.starts_with(filename_prefix) {
-                path.with_file_name(new_filename.replace(filename_prefix, hidden_filename_prefix))
-            } else {
-                path.with_file_name("")
-            }
-        })
+#[derive(Clone)]
+#[cfg_attr(not(windows), allow(dead_code))]
+struct ToastOptions {
+    title: String,
+    duration: NotificationDuration,
+    app_id: Option<String>,
+    icon_path: Option<String>,
 }
 
-fn is_expected_file(
-    event: &Event,
-    folder_path: &str,
-    filename_prefix: &str,
-    hidden_filename_prefix: &str,
-    ext_name: &str,
-    tiangan_order: &HashMap<String, usize>,
-    is_hidden_file: bool,
-) -> bool {
-    if let Some(expected_hidden_filename) = get_filename_with_largest_tiangan(
-        folder_path,
-        filename_prefix,
-        hidden_filename_prefix,
-        ext_name,
-        tiangan_order,
-        is_hidden_file,
-    ) {
-        event
-            .paths
-            .iter()
-            .any(|path| path == &expected_hidden_filename)
-    } else {
-        false
+struct Notifications {
+    is_enabled: bool,
+    toast: ToastOptions,
+    fallback: NotificationFallback,
+    dedupe_window: Duration,
+    last_shown: Option<(String, Instant)>,
+}
+
+impl Notifications {
+    fn is_duplicate(&mut self, dedupe_key: &str) -> bool {
+        let key = format!("{}\n{}", self.toast.title, dedupe_key);
+        let is_duplicate = self
+            .last_shown
+            .as_ref()
+            .is_some_and(|(last_key, shown_at)| {
+                *last_key == key && shown_at.elapsed() < self.dedupe_window
+            });
+
+        if !is_duplicate {
+            self.last_shown = Some((key, Instant::now()));
+        }
+
+        is_duplicate
+    }
+}
+
+static NOTIFICATIONS: Mutex<Option<Notifications>> = Mutex::new(None);
+
+fn init_notifications(settings: &Settings) {
+    if let Ok(mut cur_notifications) = NOTIFICATIONS.lock() {
+        *cur_notifications = Some(Notifications {
+            is_enabled: settings.notifications_enabled,
+            toast: ToastOptions {
+                title: settings.notification_title().to_string(),
+                duration: settings.notification_duration,
+                app_id: settings.notification_app_id.clone(),
+                icon_path: settings.notification_icon.clone(),
+            },
+            fallback: settings.notification_fallback,
+            dedupe_window: Duration::from_secs(settings.notification_dedupe_secs),
+            last_shown: None,
+        });
     }
 }
 
-fn is_same_file(event: &Event, expected_filename: &str) -> bool {
-    get_filename_from_event(event).map_or(false, |filename| filename == expected_filename)
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
 }
 
-fn run_script(directory: &str, filename: &str, env_name: &str) -> bool {
-    if !Path::new(directory).exists() {
-        return false;
+fn rotate_file_log(file_log: &FileLog) {
+    let rotated_path = |index: u32| {
+        let mut path = file_log.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    };
+
+    if file_log.max_files == 0 {
+        let _ = remove_file(&file_log.path);
+        return;
     }
 
-    if !Path::new(directory).join(filename).exists() {
-        return false;
+    let _ = remove_file(rotated_path(file_log.max_files));
+
+    for index in (1..file_log.max_files).rev() {
+        let _ = rename(rotated_path(index), rotated_path(index + 1));
     }
 
-    print_debug(&format!("Running {}", filename));
-    match Command::new("cmd")
-        .arg("/C")
-        .arg(format!(
-            "conda activate {} && python {} -m SheetWizard",
-            env_name, filename
-        ))
-        .current_dir(directory)
-        .status()
+    let _ = rename(&file_log.path, rotated_path(1));
+}
+
+fn format_log_line(
+    format: LogFormat,
+    label: &str,
+    event: &str,
+    message: &str,
+    fields: serde_json::Value,
+) -> String {
+    let timestamp = format_timestamp(SystemTime::now());
+    let correlation_id = CORRELATION_ID.with(|cur_id| cur_id.borrow().clone());
+
+    if format == LogFormat::Json {
+        let mut record = json!({
+            "timestamp": timestamp,
+            "level": label,
+            "event": event,
+            "message": message,
+        });
+
+        if let Some(correlation_id) = correlation_id {
+            record["correlation_id"] = json!(correlation_id);
+        }
+
+        if let (Some(record), serde_json::Value::Object(fields)) = (record.as_object_mut(), fields)
+        {
+            record.extend(fields);
+        }
+
+        return record.to_string();
+    }
+
+    match correlation_id {
+        Some(correlation_id) => {
+            format!("{} [{}] [{}] {}", timestamp, correlation_id, label, message)
+        }
+        None => format!("{} [{}] {}", timestamp, label, message),
+    }
+}
+
+fn log_format() -> LogFormat {
+    LOG_FORMAT
+        .lock()
+        .map(|cur_format| *cur_format)
+        .unwrap_or_default()
+}
+
+fn write_file_log(line: &str) {
+    let Ok(cur_log) = FILE_LOG.lock() else {
+        return;
+    };
+    let Some(file_log) = cur_log.as_ref() else {
+        return;
+    };
+
+    if file_log
+        .path
+        .metadata()
+        .is_ok_and(|metadata| metadata.len() >= file_log.max_bytes)
     {
-        Ok(exit_status) => {
-            if exit_status.success() {
-                print_debug("Executed script successfully");
+        rotate_file_log(file_log);
+    }
 
-                true
-            } else {
-                print_debug(&format!(
-                    "Executed script failed with exit code: {}",
-                    exit_status.code().unwrap_or(-1)
-                ));
+    if let Ok(mut file) = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&file_log.path)
+    {
+        let _ = writeln!(file, "{}", line);
+    }
+}
 
-                false
-            }
+fn log_event(level: LogLevel, event: &str, message: &str, fields: serde_json::Value) {
+    if LOG_LEVEL.lock().is_ok_and(|cur_level| level > *cur_level) {
+        return;
+    }
+
+    write_file_log(&format_log_line(
+        log_format(),
+        level.label(),
+        event,
+        message,
+        fields,
+    ));
+
+    #[cfg(any(not(windows), debug_assertions))]
+    CORRELATION_ID.with(|cur_id| match &*cur_id.borrow() {
+        Some(correlation_id) => println!("[{}] [{}]: {}", level.label(), correlation_id, message),
+        None => println!("[{}]: {}", level.label(), message),
+    });
+}
+
+fn log_message(level: LogLevel, message: &str) {
+    log_event(level, "message", message, serde_json::Value::Null);
+}
+
+fn print_error(message: &str) {
+    log_message(LogLevel::Error, message);
+}
+
+fn print_info(message: &str) {
+    log_message(LogLevel::Info, message);
+}
+
+fn print_debug(message: &str) {
+    log_message(LogLevel::Debug, message);
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn values(&self) -> Vec<&str> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values.iter().map(|value| value.as_str()).collect(),
+        }
+    }
+
+    fn values_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values.iter_mut().collect(),
         }
-        Err(_) => false,
     }
 }
 
-fn show_notification(title: &str, message: &str) {
-    WinToastNotify::new()
-        .set_title(title)
-        .set_messages(vec![message])
-        .set_duration(ToastDuration::Short)
-        .show()
-        .expect("Failed to show toast notification")
+fn default_filename_prefix() -> OneOrMany {
+    OneOrMany::One("sheet".to_string())
 }
 
-fn get_filename_from_event(event: &Event) -> Option<String> {
-    event.paths.iter().find_map(|path| {
-        path.file_name()
-            .map(|name| name.to_string_lossy().to_string())
-    })
+fn default_hidden_filename_prefix() -> OneOrMany {
+    OneOrMany::Many(Vec::new())
 }
 
-fn run_watcher(
-    config_path: &str,
-    tx: mpsc::Sender<NotifyResult<Event>>,
-    rx: &mpsc::Receiver<NotifyResult<Event>>,
-) -> Result<(), Box<dyn Error>> {
-    let path_config = load_config(config_path)?;
-    let mut watcher = recommended_watcher(tx)?;
-    let tiangan_order = generate_tiangan_map();
-    let mut is_expected_hidden_file_opened = false;
-    let mut is_expected_file_modified = false;
-    let mut cur_expected_hidden_filename = "".to_string();
-
-    watcher
-        .watch(
-            Path::new(&path_config.settings.listened_directory),
-            RecursiveMode::Recursive,
-        )
-        .unwrap_or(());
+fn default_ext_name() -> OneOrMany {
+    OneOrMany::One("xlsx".to_string())
+}
 
-    for res in rx {
-        match res {
-            Ok(event) => match event.kind {
-                EventKind::Create(_) => {
-                    if is_expected_file(
-                        &event,
-                        &path_config.settings.listened_directory,
-                        &path_config.settings.filename_prefix,
-                        &path_config.settings.hidden_filename_prefix,
-                        &path_config.settings.ext_name,
-                        &tiangan_order,
-                        true,
-                    ) {
-                        cur_expected_hidden_filename =
-                            get_filename_from_event(&event).unwrap_or("".to_string());
-                        is_expected_hidden_file_opened = true;
-                        is_expected_file_modified = false;
-                        print_debug(&format!("{} opened", cur_expected_hidden_filename));
-                    }
-                }
-                EventKind::Modify(_) => {
-                    if is_expected_file(
-                        &event,
-                        &path_config.settings.listened_directory,
-                        &path_config.settings.filename_prefix,
-                        &path_config.settings.hidden_filename_prefix,
-                        &path_config.settings.ext_name,
-                        &tiangan_order,
-                        false,
-                    ) {
-                        is_expected_file_modified = true;
-                    }
-                }
-                EventKind::Remove(_) => {
-                    if is_expected_hidden_file_opened
-                        && is_expected_file_modified
-                        && is_same_file(&event, &cur_expected_hidden_filename)
-                    {
-                        cur_expected_hidden_filename = "".to_string();
-                        is_expected_hidden_file_opened = false;
-                        is_expected_file_modified = false;
-
-                        let success = run_script(
-                            &path_config.settings.script_directory,
-                            &path_config.settings.script_filename,
-                            &path_config.settings.env_name,
-                        );
+const DEFAULT_SCRIPT_FILENAME: &str = "main.py";
 
-                        print_debug(&format!("{} closed", cur_expected_hidden_filename));
+fn default_env_name() -> String {
+    "base".to_string()
+}
 
-                        if success {
-                            show_notification("Sheet Wizard", "Processed successfully.");
-                        } else {
-                            show_notification(
-                                "Sheet Wizard",
-                                "Processing failed, the file may not have changed.",
-                            );
-                        }
-                    }
-                }
-                EventKind::Access(_) => {}
-                _ => {
-                    break;
-                }
+fn default_debounce_ms() -> u64 {
+    300
+}
+
+fn default_archive_filename() -> String {
+    "{filename}".to_string()
+}
+
+fn default_retry_delay_ms() -> u64 {
+    1000
+}
+
+fn default_rerun_exit_code() -> i32 {
+    75
+}
+
+fn default_max_reruns() -> u32 {
+    3
+}
+
+fn default_daily_reset_grace_secs() -> u64 {
+    3600
+}
+
+fn default_failure_window_secs() -> u64 {
+    600
+}
+
+fn default_failure_cooldown_secs() -> u64 {
+    300
+}
+
+fn default_log_max_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_log_max_files() -> u32 {
+    2
+}
+
+fn default_recursive() -> bool {
+    true
+}
+
+fn default_use_hidden_file() -> bool {
+    true
+}
+
+fn default_check_conda_env() -> bool {
+    true
+}
+
+fn default_poll_interval_ms() -> u64 {
+    2000
+}
+
+fn default_stability_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_notifications_enabled() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct Settings {
+    listened_directory: OneOrMany,
+    #[serde(default = "default_filename_prefix")]
+    filename_prefix: OneOrMany,
+    #[serde(default = "default_hidden_filename_prefix")]
+    hidden_filename_prefix: OneOrMany,
+    #[serde(default = "default_ext_name")]
+    ext_name: OneOrMany,
+    #[serde(default)]
+    script_path: Option<String>,
+    #[serde(default)]
+    script_directory: String,
+    #[serde(default)]
+    script_filename: Option<String>,
+    #[serde(default = "default_env_name")]
+    env_name: String,
+    #[serde(default)]
+    stable_file_count_ms: u64,
+    #[serde(default)]
+    selection: Selection,
+    #[serde(default)]
+    scripts: Vec<ScriptStep>,
+    #[serde(default)]
+    ext_scripts: HashMap<String, String>,
+    #[serde(default)]
+    continue_on_error: bool,
+    #[serde(default)]
+    dry_run: bool,
+    syslog: Option<SyslogSettings>,
+    #[serde(default)]
+    skip_on_battery: bool,
+    active_hours: Option<ActiveHours>,
+    #[serde(default)]
+    watch_retry_secs: u64,
+    #[serde(default)]
+    watch_retry_attempts: u32,
+    #[serde(default = "default_recursive")]
+    recursive: bool,
+    #[serde(default = "default_use_hidden_file")]
+    use_hidden_file: bool,
+    #[serde(default)]
+    max_depth: Option<usize>,
+    #[serde(skip)]
+    listened_roots: Vec<String>,
+    #[serde(default)]
+    exclude_subdirectories: Vec<String>,
+    #[serde(default)]
+    watch_mode: WatchMode,
+    #[serde(default = "default_poll_interval_ms")]
+    poll_interval_ms: u64,
+    #[serde(default)]
+    dedupe_by_content_hash: bool,
+    #[serde(default)]
+    skip_unchanged: bool,
+    #[serde(default)]
+    process_priority: ProcessPriority,
+    #[serde(default)]
+    run_as: Option<RunAsSettings>,
+    #[serde(default)]
+    verify_write_access: bool,
+    #[serde(default)]
+    on_overlap: OverlapBehavior,
+    #[serde(default)]
+    trigger_target: TriggerTarget,
+    #[serde(default)]
+    trigger_mode: TriggerMode,
+    #[serde(default)]
+    trigger_file: Option<String>,
+    #[serde(default)]
+    max_concurrent_runs: usize,
+    #[serde(default)]
+    include_correlation_id: bool,
+    #[serde(default)]
+    unrecognized_version: UnrecognizedVersion,
+    #[serde(default)]
+    simulate_port: Option<u16>,
+    #[serde(default)]
+    tiangan_order: Option<Vec<String>>,
+    #[serde(default)]
+    min_version: Option<String>,
+    #[serde(default)]
+    matcher: MatcherKind,
+    #[serde(default)]
+    ignore_patterns: Vec<String>,
+    #[serde(default)]
+    pass_file: Option<PassFileSettings>,
+    #[serde(default)]
+    post_close_delay_ms: u64,
+    #[serde(default)]
+    stability_ms: u64,
+    #[serde(default = "default_stability_timeout_ms")]
+    stability_timeout_ms: u64,
+    #[serde(default)]
+    max_file_bytes: Option<u64>,
+    #[serde(default)]
+    close_detection: CloseDetection,
+    #[serde(default)]
+    event_phases: EventPhases,
+    #[serde(default)]
+    archive_directory: Option<String>,
+    #[serde(default)]
+    archive_mode: ArchiveMode,
+    #[serde(default = "default_archive_filename")]
+    archive_filename: String,
+    #[serde(default)]
+    sweep_interval_secs: u64,
+    #[serde(default)]
+    startup_grace_secs: u64,
+    #[serde(default)]
+    batch_window_secs: u64,
+    #[serde(default)]
+    metrics_interval_secs: u64,
+    #[serde(default)]
+    daily_reset: bool,
+    #[serde(default = "default_daily_reset_grace_secs")]
+    daily_reset_grace_secs: u64,
+    #[serde(default = "default_debounce_ms")]
+    debounce_ms: u64,
+    #[serde(default)]
+    script_timeout_secs: u64,
+    #[serde(default)]
+    script_output_encoding: OutputEncoding,
+    #[serde(default)]
+    min_interval_secs: u64,
+    #[serde(default)]
+    max_retries: u32,
+    #[serde(default = "default_retry_delay_ms")]
+    retry_delay_ms: u64,
+    #[serde(default = "default_rerun_exit_code")]
+    rerun_exit_code: i32,
+    #[serde(default = "default_max_reruns")]
+    max_reruns: u32,
+    #[serde(default)]
+    failure_threshold: usize,
+    #[serde(default = "default_failure_window_secs")]
+    failure_window_secs: u64,
+    #[serde(default = "default_failure_cooldown_secs")]
+    failure_cooldown_secs: u64,
+    #[serde(default)]
+    interpreter: Interpreter,
+    #[serde(default)]
+    command_template: Option<String>,
+    #[serde(default)]
+    fast_activation: bool,
+    #[serde(default = "default_check_conda_env")]
+    check_conda_env: bool,
+    #[serde(default)]
+    script_env: HashMap<String, String>,
+    #[serde(default)]
+    script_working_directory: Option<String>,
+    #[serde(default)]
+    log_path: Option<String>,
+    #[serde(default)]
+    log_level: LogLevel,
+    #[serde(default)]
+    log_format: LogFormat,
+    #[serde(default)]
+    history_path: Option<String>,
+    #[serde(default = "default_log_max_bytes")]
+    history_max_bytes: u64,
+    #[serde(default = "default_log_max_files")]
+    history_max_files: u32,
+    #[serde(default)]
+    status_path: Option<String>,
+    #[serde(default)]
+    webhook_url: Option<String>,
+    #[serde(default = "default_log_max_bytes")]
+    log_max_bytes: u64,
+    #[serde(default = "default_log_max_files")]
+    log_max_files: u32,
+    #[serde(default = "default_notifications_enabled")]
+    notifications_enabled: bool,
+    #[serde(default)]
+    startup_notification: bool,
+    #[serde(default)]
+    language: Language,
+    #[serde(default)]
+    notification_title: Option<String>,
+    #[serde(default)]
+    notification_duration: NotificationDuration,
+    #[serde(default)]
+    notification_dedupe_secs: u64,
+    #[serde(default)]
+    notification_fallback: NotificationFallback,
+    #[serde(default)]
+    notification_app_id: Option<String>,
+    #[serde(default)]
+    notification_icon: Option<String>,
+    #[serde(default)]
+    success_template: Option<String>,
+    #[serde(default)]
+    failure_template: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum Language {
+    Zh,
+    #[default]
+    #[serde(other)]
+    En,
+}
+
+struct Messages {
+    title: &'static str,
+    success: &'static str,
+    failure: &'static str,
+    watching: &'static str,
+}
+
+impl Language {
+    fn messages(self) -> Messages {
+        match self {
+            Language::En => Messages {
+                title: "Sheet Wizard",
+                success: "{source}: Processed successfully in {duration}.",
+                failure: "{source}: Processing failed, {reason}.",
+                watching: "Watching {targets}",
+            },
+            Language::Zh => Messages {
+                title: "表格精灵",
+                success: "{source}：处理成功，耗时 {duration}。",
+                failure: "{source}：处理失败，{reason}。",
+                watching: "正在监听 {targets}",
             },
-            Err(e) => {
-                print_debug(&format!("Error occurred in watcher: {:?}", e));
-            }
         }
     }
+}
 
-    Ok(())
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum CloseDetection {
+    #[default]
+    Event,
+    Handle,
 }
 
-#[cfg(debug_assertions)]
-fn run_debug_service() -> Result<(), Box<dyn Error>> {
-    let (tx, rx) = mpsc::channel::<NotifyResult<Event>>();
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Weekday {
+    Sun,
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+}
 
-    run_watcher("./path.toml", tx, &rx)
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum OutsideHours {
+    #[default]
+    Defer,
+    Skip,
 }
 
-#[cfg(not(debug_assertions))]
-fn run_service() -> Result<(), Box<dyn Error>> {
-    let (tx, rx) = mpsc::channel::<NotifyResult<Event>>();
-    let tx_clone = tx.clone();
-    let status_handle = service_control_handler::register(
-        "SheetWizard",
-        move |control_event| -> ServiceControlHandlerResult {
-            match control_event {
-                ServiceControl::Stop => {
-                    if let Err(_) = tx_clone.send(Ok(Event::new(EventKind::Other))) {}
+#[derive(Deserialize)]
+struct ActiveHours {
+    start: String,
+    end: String,
+    #[serde(default)]
+    weekdays: Vec<Weekday>,
+    #[serde(default)]
+    outside: OutsideHours,
+}
 
-                    ServiceControlHandlerResult::NoError
-                }
-                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
-                _ => ServiceControlHandlerResult::NotImplemented,
-            }
-        },
-    )?;
+fn parse_clock_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
 
-    status_handle.set_service_status(ServiceStatus {
-        service_type: ServiceType::OWN_PROCESS,
-        current_state: ServiceState::Running,
-        controls_accepted: ServiceControlAccept::STOP,
-        exit_code: ServiceExitCode::Win32(0),
-        checkpoint: 0,
-        wait_hint: Duration::default(),
-        process_id: None,
-    })?;
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
 
-    let path_config_directory = var("SW_TOML_PATH").unwrap_or("./".to_string());
+impl ActiveHours {
+    fn window(&self) -> Result<(u32, u32), String> {
+        let parse = |time: &str| {
+            parse_clock_time(time)
+                .ok_or_else(|| format!("active_hours time {} is not in HH:MM format", time))
+        };
 
-    run_watcher(&format!("{}\\path.toml", path_config_directory), tx, &rx)?;
+        let (start, end) = (parse(&self.start)?, parse(&self.end)?);
 
-    status_handle.set_service_status(ServiceStatus {
-        service_type: ServiceType::OWN_PROCESS,
-        current_state: ServiceState::Stopped,
-        controls_accepted: ServiceControlAccept::empty(),
-        exit_code: ServiceExitCode::Win32(0),
-        checkpoint: 0,
-        wait_hint: Duration::default(),
-        process_id: None,
-    })?;
+        if start == end {
+            return Err(format!(
+                "active_hours start and end are both {}, the window would be empty",
+                self.start
+            ));
+        }
 
-    Ok(())
+        Ok((start, end))
+    }
+
+    fn contains(&self, weekday: u32, minute_of_day: u32) -> bool {
+        let Ok((start, end)) = self.window() else {
+            return true;
+        };
+
+        if !self.weekdays.is_empty()
+            && !self
+                .weekdays
+                .iter()
+                .any(|active_day| *active_day as u32 == weekday)
+        {
+            return false;
+        }
+
+        if start <= end {
+            (start..end).contains(&minute_of_day)
+        } else {
+            minute_of_day >= start || minute_of_day < end
+        }
+    }
 }
 
-#[cfg(not(debug_assertions))]
-fn run_service_entry(_: Vec<OsString>) {
-    if let Err(_) = run_service() {}
+#[cfg(windows)]
+fn local_time() -> (u32, u32) {
+    use windows_sys::Win32::Foundation::SYSTEMTIME;
+    use windows_sys::Win32::System::SystemInformation::GetLocalTime;
+
+    let mut time: SYSTEMTIME = unsafe { std::mem::zeroed() };
+
+    unsafe { GetLocalTime(&mut time) };
+
+    (
+        u32::from(time.wDayOfWeek),
+        u32::from(time.wHour) * 60 + u32::from(time.wMinute),
+    )
 }
-#[cfg(not(debug_assertions))]
-define_windows_service!(ffi_service_main, run_service_entry);
 
-#[cfg(not(debug_assertions))]
-fn main() -> Result<(), Box<dyn Error>> {
-    service_dispatcher::start("SheetWizard", ffi_service_main)?;
+#[cfg(windows)]
+fn local_date() -> (i64, i64, i64) {
+    use windows_sys::Win32::Foundation::SYSTEMTIME;
+    use windows_sys::Win32::System::SystemInformation::GetLocalTime;
 
-    Ok(())
+    let mut time: SYSTEMTIME = unsafe { std::mem::zeroed() };
+
+    unsafe { GetLocalTime(&mut time) };
+
+    (
+        i64::from(time.wYear),
+        i64::from(time.wMonth),
+        i64::from(time.wDay),
+    )
 }
 
-#[cfg(debug_assertions)]
-fn main() -> Result<(), Box<dyn Error>> {
-    run_debug_service()?;
+#[cfg(not(windows))]
+fn local_date() -> (i64, i64, i64) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as libc::time_t;
+    let mut time: libc::tm = unsafe { std::mem::zeroed() };
 
-    Ok(())
+    unsafe { libc::localtime_r(&now, &mut time) };
+
+    (
+        i64::from(time.tm_year) + 1900,
+        i64::from(time.tm_mon) + 1,
+        i64::from(time.tm_mday),
+    )
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(not(windows))]
+fn local_time() -> (u32, u32) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as libc::time_t;
+    let mut time: libc::tm = unsafe { std::mem::zeroed() };
+
+    unsafe { libc::localtime_r(&now, &mut time) };
+
+    (
+        time.tm_wday as u32,
+        (time.tm_hour * 60 + time.tm_min) as u32,
+    )
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ArchiveMode {
+    #[default]
+    Copy,
+    Move,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum EventKindName {
+    Create,
+    Modify,
+    ModifyData,
+    ModifyMetadata,
+    Remove,
+    Access,
+    AccessClose,
+    AccessCloseWrite,
+}
+
+impl EventKindName {
+    fn matches(self, kind: &EventKind) -> bool {
+        match self {
+            EventKindName::Create => matches!(kind, EventKind::Create(_)),
+            EventKindName::Modify => matches!(kind, EventKind::Modify(_)),
+            EventKindName::ModifyData => matches!(kind, EventKind::Modify(ModifyKind::Data(_))),
+            EventKindName::ModifyMetadata => {
+                matches!(kind, EventKind::Modify(ModifyKind::Metadata(_)))
+            }
+            EventKindName::Remove => matches!(kind, EventKind::Remove(_)),
+            EventKindName::Access => matches!(kind, EventKind::Access(AccessKind::Open(_))),
+            EventKindName::AccessClose => matches!(kind, EventKind::Access(AccessKind::Close(_))),
+            EventKindName::AccessCloseWrite => matches!(
+                kind,
+                EventKind::Access(AccessKind::Close(AccessMode::Write))
+            ),
+        }
+    }
+}
+
+fn default_open_events() -> Vec<EventKindName> {
+    vec![EventKindName::Create]
+}
+
+fn default_modify_events() -> Vec<EventKindName> {
+    vec![EventKindName::Modify]
+}
+
+fn default_close_events() -> Vec<EventKindName> {
+    vec![EventKindName::Remove]
+}
+
+#[derive(Deserialize)]
+struct EventPhases {
+    #[serde(default = "default_open_events")]
+    open: Vec<EventKindName>,
+    #[serde(default = "default_modify_events")]
+    modify: Vec<EventKindName>,
+    #[serde(default = "default_close_events")]
+    close: Vec<EventKindName>,
+}
+
+impl Default for EventPhases {
+    fn default() -> Self {
+        EventPhases {
+            open: default_open_events(),
+            modify: default_modify_events(),
+            close: default_close_events(),
+        }
+    }
+}
+
+impl EventPhases {
+    fn is_open(&self, kind: &EventKind) -> bool {
+        self.open.iter().any(|name| name.matches(kind))
+    }
+
+    fn is_modify(&self, kind: &EventKind) -> bool {
+        self.modify.iter().any(|name| name.matches(kind))
+    }
+
+    fn is_close(&self, kind: &EventKind) -> bool {
+        self.close.iter().any(|name| name.matches(kind))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum Interpreter {
+    #[default]
+    Python,
+    Powershell,
+    Cmd,
+    Raw,
+}
+
+impl Interpreter {
+    fn command_template(self) -> &'static str {
+        match self {
+            Interpreter::Python if cfg!(windows) => {
+                "conda activate {env} && python \"{script_path}\" -m SheetWizard"
+            }
+            Interpreter::Python => "conda run -n {env} python \"{script_path}\" -m SheetWizard",
+            Interpreter::Powershell => {
+                "powershell -NoProfile -ExecutionPolicy Bypass -File \"{script_path}\""
+            }
+            Interpreter::Cmd => "call \"{script_path}\"",
+            Interpreter::Raw => "\"{script_path}\"",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+enum LogLevel {
+    #[default]
+    Error,
+    Info,
+    Debug,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl LogLevel {
+    fn label(self) -> &'static str {
+        match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum NotificationDuration {
+    #[default]
+    Short,
+    Long,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum NotificationFallback {
+    #[default]
+    Log,
+    None,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum WatchMode {
+    #[default]
+    Recommended,
+    Poll,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum MatcherKind {
+    #[default]
+    Tiangan,
+    Numeric,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TriggerTarget {
+    #[default]
+    Latest,
+    Event,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum OutputEncoding {
+    #[default]
+    Utf8,
+    Gbk,
+    Ansi,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TriggerMode {
+    #[default]
+    Versions,
+    File,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum OverlapBehavior {
+    #[default]
+    Ignore,
+    Requeue,
+    Restart,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum ProcessPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Deserialize, Clone)]
+struct ScriptStep {
+    filename: String,
+    #[serde(default)]
+    env_name: Option<String>,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+}
+
+impl ScriptStep {
+    fn env_name<'a>(&'a self, settings: &'a Settings) -> &'a str {
+        self.env_name.as_deref().unwrap_or(&settings.env_name)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SyslogSeverity {
+    Emerg,
+    Alert,
+    Crit,
+    Err,
+    Warning,
+    Notice,
+    Info,
+    Debug,
+}
+
+fn default_syslog_facility() -> String {
+    "user".to_string()
+}
+
+fn default_success_severity() -> SyslogSeverity {
+    SyslogSeverity::Info
+}
+
+fn default_failure_severity() -> SyslogSeverity {
+    SyslogSeverity::Err
+}
+
+#[derive(Deserialize)]
+struct SyslogSettings {
+    #[serde(default = "default_syslog_facility")]
+    facility: String,
+    #[serde(default = "default_success_severity")]
+    success_severity: SyslogSeverity,
+    #[serde(default = "default_failure_severity")]
+    failure_severity: SyslogSeverity,
+    server: Option<String>,
+}
+
+fn default_path_arg() -> String {
+    "--file".to_string()
+}
+
+fn default_version_arg() -> String {
+    "--version".to_string()
+}
+
+fn default_path_env() -> String {
+    "SW_FILE".to_string()
+}
+
+fn default_version_env() -> String {
+    "SW_VERSION".to_string()
+}
+
+#[derive(Deserialize)]
+struct PassFileSettings {
+    #[serde(default)]
+    as_args: bool,
+    #[serde(default)]
+    as_env: bool,
+    #[serde(default = "default_path_arg")]
+    path_arg: String,
+    #[serde(default = "default_version_arg")]
+    version_arg: String,
+    #[serde(default = "default_path_env")]
+    path_env: String,
+    #[serde(default = "default_version_env")]
+    version_env: String,
+}
+
+#[derive(Deserialize)]
+struct RunAsSettings {
+    user: String,
+    #[serde(default)]
+    credential: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FileEntry {
+    path: String,
+    hidden_path: Option<String>,
+    #[serde(default)]
+    scripts: Vec<ScriptStep>,
+}
+
+impl FileEntry {
+    fn hidden_path(&self, settings: &Settings) -> Option<PathBuf> {
+        if let Some(hidden_path) = &self.hidden_path {
+            return Some(PathBuf::from(hidden_path));
+        }
+
+        let path = Path::new(&self.path);
+        let filename = path.file_name()?.to_str()?;
+        let (prefix, hidden_prefix) = settings
+            .prefix_pairs()
+            .into_iter()
+            .filter(|(prefix, _)| filename.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())?;
+
+        expected_hidden_path(path, prefix, &hidden_prefix)
+    }
+}
+
+#[derive(Deserialize)]
+struct PathConfig {
+    settings: Settings,
+    #[serde(default)]
+    file: Vec<FileEntry>,
+}
+
+fn lookup_env_var(name: &str) -> Result<String, String> {
+    var(name).map_err(|_| format!("Environment variable {} is not set", name))
+}
+
+fn expand_env_vars(value: &str) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find(['%', '$']) {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some((name, tail)) = rest
+            .strip_prefix("${")
+            .and_then(|tail| tail.split_once('}'))
+        {
+            expanded.push_str(&lookup_env_var(name)?);
+            rest = tail;
+        } else if let Some((name, tail)) = rest
+            .strip_prefix('%')
+            .and_then(|tail| tail.split_once('%'))
+            .filter(|(name, _)| !name.is_empty() && !name.contains(char::is_whitespace))
+        {
+            expanded.push_str(&lookup_env_var(name)?);
+            rest = tail;
+        } else {
+            expanded.push_str(&rest[..1]);
+            rest = &rest[1..];
+        }
+    }
+
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+impl PathConfig {
+    fn path_fields_mut(&mut self) -> Vec<&mut String> {
+        let settings = &mut self.settings;
+        let mut paths = settings.listened_directory.values_mut();
+
+        paths.extend(settings.script_path.as_mut());
+        paths.push(&mut settings.script_directory);
+        paths.extend(settings.script_working_directory.as_mut());
+        paths.extend(settings.log_path.as_mut());
+        paths.extend(settings.history_path.as_mut());
+        paths.extend(settings.status_path.as_mut());
+        paths.extend(settings.archive_directory.as_mut());
+        paths.extend(settings.notification_icon.as_mut());
+
+        for entry in &mut self.file {
+            paths.push(&mut entry.path);
+            paths.extend(entry.hidden_path.as_mut());
+        }
+
+        paths
+    }
+
+    fn trim_whitespace(&mut self) {
+        for path in self.path_fields_mut() {
+            *path = path.trim().to_string();
+        }
+
+        let settings = &mut self.settings;
+        let mut fields = settings.ext_name.values_mut();
+
+        fields.extend(settings.filename_prefix.values_mut());
+        fields.extend(settings.hidden_filename_prefix.values_mut());
+        fields.extend(settings.script_filename.as_mut());
+        fields.extend(settings.exclude_subdirectories.iter_mut());
+        fields.push(&mut settings.env_name);
+
+        fields.extend(settings.ext_scripts.values_mut());
+
+        for step in &mut settings.scripts {
+            fields.push(&mut step.filename);
+            fields.extend(step.env_name.as_mut());
+        }
+
+        for field in fields {
+            *field = field.trim().to_string();
+        }
+    }
+
+    fn expand_env_vars(&mut self) -> Result<(), String> {
+        for path in self.path_fields_mut() {
+            *path = expand_env_vars(path)?;
+        }
+
+        Ok(())
+    }
+
+    fn resolve_relative_paths(&mut self, base_directory: &Path) {
+        for path in self.path_fields_mut() {
+            if Path::new(path).is_relative() {
+                *path = base_directory.join(&*path).to_string_lossy().to_string();
+            }
+        }
+    }
+}
+
+fn load_config(file_path: &str) -> Result<PathConfig, SheetWizardError> {
+    let content = match read_to_string(file_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(SheetWizardError::MissingConfig(absolute(file_path)?));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let content = content.strip_prefix('\u{feff}').unwrap_or(&content);
+    let mut config = from_str::<PathConfig>(&content.replace("\r\n", "\n"))?;
+
+    config.trim_whitespace();
+    config.expand_env_vars().map_err(SheetWizardError::Config)?;
+    config
+        .settings
+        .split_script_path()
+        .map_err(SheetWizardError::Config)?;
+
+    if let Some(base_directory) = absolute(file_path)?.parent() {
+        config.resolve_relative_paths(base_directory);
+    }
+
+    config.settings.expand_subdirectories();
+
+    for entry in &config.file {
+        if entry.hidden_path(&config.settings).is_none() {
+            return Err(SheetWizardError::Config(format!(
+                "Cannot derive the hidden file of {}, please set hidden_path",
+                entry.path
+            )));
+        }
+    }
+
+    config
+        .settings
+        .check_prefixes()
+        .map_err(SheetWizardError::Config)?;
+
+    if let Some(active_hours) = &config.settings.active_hours {
+        active_hours.window().map_err(SheetWizardError::Config)?;
+    }
+
+    if !cfg!(windows)
+        && matches!(
+            config.settings.interpreter,
+            Interpreter::Powershell | Interpreter::Cmd
+        )
+    {
+        return Err(SheetWizardError::Config(
+            "interpreter = \"powershell\" and \"cmd\" are only available on Windows".to_string(),
+        ));
+    }
+
+    if config.settings.daily_reset && matches!(config.settings.matcher, MatcherKind::Numeric) {
+        return Err(SheetWizardError::Config(
+            "daily_reset needs the tiangan matcher".to_string(),
+        ));
+    }
+
+    if config.settings.trigger_mode == TriggerMode::File
+        && config
+            .settings
+            .trigger_file
+            .as_deref()
+            .unwrap_or("")
+            .is_empty()
+    {
+        return Err(SheetWizardError::Config(
+            "trigger_mode = \"file\" needs trigger_file".to_string(),
+        ));
+    }
+    if let Some(webhook_url) = &config.settings.webhook_url {
+        parse_http_url(webhook_url).map_err(SheetWizardError::Config)?;
+    }
+
+    if config.settings.command_template().contains("{file}") {
+        print_error("{file} in command_template is deprecated, use {script_path} instead");
+    }
+
+    let matcher = build_version_matcher(&config.settings).map_err(SheetWizardError::Config)?;
+
+    min_version_index(&config.settings, matcher.as_ref()).map_err(SheetWizardError::Config)?;
+
+    Ok(config)
+}
+
+fn min_version_index(
+    settings: &Settings,
+    matcher: &dyn VersionMatcher,
+) -> Result<Option<usize>, String> {
+    settings
+        .min_version
+        .as_deref()
+        .map(|token| {
+            matcher
+                .version_of(token, "")
+                .ok_or_else(|| format!("min_version {} is not a known version token", token))
+        })
+        .transpose()
+}
+
+fn build_version_matcher(settings: &Settings) -> Result<Box<dyn VersionMatcher>, String> {
+    match settings.matcher {
+        MatcherKind::Tiangan => Ok(Box::new(TianganMatcher {
+            tiangan_order: generate_tiangan_map(settings.tiangan_order.as_deref())?,
+        })),
+        MatcherKind::Numeric => Ok(Box::new(NumericMatcher)),
+    }
+}
+
+impl Settings {
+    fn notification_title(&self) -> &str {
+        self.notification_title
+            .as_deref()
+            .unwrap_or(self.language.messages().title)
+    }
+
+    fn success_template(&self) -> &str {
+        self.success_template
+            .as_deref()
+            .unwrap_or(self.language.messages().success)
+    }
+
+    fn failure_template(&self) -> &str {
+        self.failure_template
+            .as_deref()
+            .unwrap_or(self.language.messages().failure)
+    }
+
+    fn script_filename(&self) -> &str {
+        self.script_filename
+            .as_deref()
+            .unwrap_or(DEFAULT_SCRIPT_FILENAME)
+    }
+
+    fn split_script_path(&mut self) -> Result<(), String> {
+        let Some(script_path) = &self.script_path else {
+            return if self.script_directory.is_empty() {
+                Err("Set either script_path or script_directory".to_string())
+            } else {
+                Ok(())
+            };
+        };
+
+        if !self.script_directory.is_empty() || self.script_filename.is_some() {
+            return Err(
+                "Set either script_path or script_directory and script_filename, not both"
+                    .to_string(),
+            );
+        }
+
+        let path = Path::new(script_path);
+        let filename = path
+            .file_name()
+            .ok_or_else(|| format!("script_path {} has no filename", script_path))?;
+
+        self.script_filename = Some(filename.to_string_lossy().to_string());
+        self.script_directory = path
+            .parent()
+            .unwrap_or(Path::new(""))
+            .to_string_lossy()
+            .to_string();
+
+        Ok(())
+    }
+
+    fn root_directories(&self) -> Vec<&str> {
+        if self.listened_roots.is_empty() {
+            self.listened_directory.values()
+        } else {
+            self.listened_roots.iter().map(String::as_str).collect()
+        }
+    }
+
+    fn expand_subdirectories(&mut self) {
+        let Some(max_depth) = self.max_depth else {
+            return;
+        };
+
+        self.listened_roots = self
+            .listened_directory
+            .values()
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+
+        let mut directories = Vec::new();
+        let mut level: Vec<PathBuf> = self.listened_roots.iter().map(PathBuf::from).collect();
+
+        for depth in 0..=max_depth {
+            directories.extend(level.iter().map(|path| path.to_string_lossy().to_string()));
+
+            if depth == max_depth {
+                break;
+            }
+
+            level = level
+                .iter()
+                .flat_map(|path| {
+                    let mut subdirectories: Vec<PathBuf> = read_dir(path)
+                        .map(|entries| {
+                            entries
+                                .filter_map(|entry| entry.ok())
+                                .map(|entry| entry.path())
+                                .filter(|path| path.is_dir() && !self.is_excluded(path))
+                                .collect()
+                        })
+                        .unwrap_or_default();
+
+                    subdirectories.sort();
+                    subdirectories
+                })
+                .collect();
+        }
+
+        self.listened_directory = OneOrMany::Many(directories);
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        self.root_directories().into_iter().any(|directory| {
+            self.exclude_subdirectories
+                .iter()
+                .any(|subdirectory| path.starts_with(Path::new(directory).join(subdirectory)))
+        })
+    }
+
+    fn script_working_directory(&self) -> &str {
+        self.script_working_directory
+            .as_deref()
+            .unwrap_or(&self.script_directory)
+    }
+
+    fn command_template(&self) -> &str {
+        self.command_template
+            .as_deref()
+            .unwrap_or(self.interpreter.command_template())
+    }
+
+    fn prefix_pairs(&self) -> Vec<(&str, String)> {
+        let hidden_prefixes = self.hidden_filename_prefix.values();
+
+        self.filename_prefix
+            .values()
+            .into_iter()
+            .enumerate()
+            .map(|(index, prefix)| {
+                let hidden_prefix = hidden_prefixes
+                    .get(index)
+                    .map_or_else(|| format!("~${}", prefix), |hidden| hidden.to_string());
+
+                (prefix, hidden_prefix)
+            })
+            .collect()
+    }
+
+    fn check_prefixes(&self) -> Result<(), String> {
+        for (prefix, hidden_prefix) in self.prefix_pairs() {
+            if prefix.is_empty() || hidden_prefix.is_empty() {
+                continue;
+            }
+
+            if prefix == hidden_prefix {
+                return Err(format!(
+                    "hidden_filename_prefix {} is the same as filename_prefix, lock files can't be told apart from workbooks",
+                    hidden_prefix
+                ));
+            }
+
+            if prefix.starts_with(&hidden_prefix) || hidden_prefix.starts_with(prefix) {
+                return Err(format!(
+                    "hidden_filename_prefix {} and filename_prefix {} overlap, neither may start with the other",
+                    hidden_prefix, prefix
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn file_queries(&self) -> Vec<FileQuery<'_>> {
+        let prefix_pairs = self.prefix_pairs();
+
+        self.listened_directory
+            .values()
+            .into_iter()
+            .flat_map(|folder_path| {
+                prefix_pairs
+                    .iter()
+                    .map(move |(prefix, hidden_prefix)| FileQuery {
+                        folder_path,
+                        filename_prefix: prefix,
+                        hidden_filename_prefix: hidden_prefix.clone(),
+                        ext_names: self.ext_name.values(),
+                        ignore_patterns: &self.ignore_patterns,
+                        selection: self.selection,
+                        unrecognized_version: self.unrecognized_version,
+                    })
+            })
+            .collect()
+    }
+
+    fn writable_directories(&self) -> Vec<&str> {
+        let mut directories = vec![self.script_directory.as_str()];
+
+        for directory in [
+            Some(self.script_working_directory()),
+            self.archive_directory.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if !directories.contains(&directory) {
+                directories.push(directory);
+            }
+        }
+
+        directories
+    }
+
+    fn ext_script(&self, path: &Path) -> Option<&str> {
+        let ext = path.extension()?.to_string_lossy();
+
+        self.ext_scripts
+            .iter()
+            .find(|(ext_name, _)| {
+                ext_name
+                    .trim()
+                    .trim_start_matches('.')
+                    .eq_ignore_ascii_case(&ext)
+            })
+            .map(|(_, filename)| filename.as_str())
+    }
+
+    fn script_steps(&self) -> Vec<ScriptStep> {
+        if !self.scripts.is_empty() {
+            return self.scripts.clone();
+        }
+
+        vec![ScriptStep {
+            filename: self.script_filename().to_string(),
+            env_name: None,
+            args: Vec::new(),
+            env: HashMap::new(),
+        }]
+    }
+}
+
+fn warn_unrecognized_versions(paths: &[PathBuf], query: &FileQuery, matcher: &dyn VersionMatcher) {
+    if query.unrecognized_version == UnrecognizedVersion::Ignore {
+        return;
+    }
+
+    for path in paths
+        .iter()
+        .filter(|path| has_unrecognized_version(path, query, matcher))
+    {
+        let message = format!(
+            "Unrecognized version token in {}",
+            path.file_name().unwrap_or(OsStr::new("")).to_string_lossy()
+        );
+
+        print_error(&message);
+        show_notification(&message);
+    }
+}
+
+fn warn_non_utf8_names(paths: &[PathBuf], query: &FileQuery) {
+    for path in paths.iter().filter(|path| has_non_utf8_name(path, query)) {
+        print_error(&format!(
+            "{} is skipped, its filename is not valid UTF-8",
+            path.display()
+        ));
+    }
+}
+
+fn is_within_size_limit(settings: &Settings, path: &Path) -> bool {
+    let Some(max_file_bytes) = settings.max_file_bytes else {
+        return true;
+    };
+    let Ok(metadata) = path.metadata() else {
+        return true;
+    };
+
+    if metadata.len() <= max_file_bytes {
+        return true;
+    }
+
+    let message = format!(
+        "{} is {} bytes, larger than max_file_bytes ({}), processing skipped",
+        path.display(),
+        metadata.len(),
+        max_file_bytes
+    );
+
+    print_error(&message);
+    show_notification(&message);
+
+    false
+}
+
+fn size_and_mtime(path: &Path) -> Option<(u64, Option<SystemTime>)> {
+    path.metadata()
+        .ok()
+        .map(|metadata| (metadata.len(), metadata.modified().ok()))
+}
+
+fn quote_arg(arg: &str) -> String {
+    if arg.is_empty() || arg.contains(char::is_whitespace) {
+        format!("\"{}\"", arg)
+    } else {
+        arg.to_string()
+    }
+}
+
+#[cfg(windows)]
+fn priority_class(priority: ProcessPriority) -> u32 {
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+    const HIGH_PRIORITY_CLASS: u32 = 0x0000_0080;
+
+    match priority {
+        ProcessPriority::Low => BELOW_NORMAL_PRIORITY_CLASS,
+        ProcessPriority::Normal => 0,
+        ProcessPriority::High => HIGH_PRIORITY_CLASS,
+    }
+}
+
+#[cfg(windows)]
+fn apply_process_priority(command: &mut Command, priority: ProcessPriority) {
+    use std::os::windows::process::CommandExt;
+
+    if priority_class(priority) != 0 {
+        command.creation_flags(priority_class(priority));
+    }
+}
+
+#[cfg(unix)]
+fn apply_process_priority(command: &mut Command, priority: ProcessPriority) {
+    use std::os::unix::process::CommandExt;
+
+    let increment = match priority {
+        ProcessPriority::Low => 10,
+        ProcessPriority::Normal => return,
+        ProcessPriority::High => -5,
+    };
+
+    // Raising the priority needs privileges, so a failing nice() is ignored.
+    unsafe {
+        command.pre_exec(move || {
+            libc::nice(increment);
+
+            Ok(())
+        });
+    }
+}
+
+enum ChildWait {
+    Exited(ExitStatus),
+    Cancelled,
+    TimedOut,
+    Failed(io::Error),
+}
+
+enum ScriptProcess {
+    Child(Child),
+    #[cfg(windows)]
+    User(UserProcess),
+}
+
+impl ScriptProcess {
+    fn id(&self) -> u32 {
+        match self {
+            ScriptProcess::Child(child) => child.id(),
+            #[cfg(windows)]
+            ScriptProcess::User(process) => process.id,
+        }
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        match self {
+            ScriptProcess::Child(child) => child.try_wait(),
+            #[cfg(windows)]
+            ScriptProcess::User(process) => process.try_wait(),
+        }
+    }
+
+    fn kill(&mut self) {
+        match self {
+            ScriptProcess::Child(child) => {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            #[cfg(windows)]
+            ScriptProcess::User(process) => process.kill(),
+        }
+    }
+}
+
+#[cfg(windows)]
+struct UserProcess {
+    handle: std::os::windows::io::OwnedHandle,
+    id: u32,
+    token: std::os::windows::io::OwnedHandle,
+    profile: usize,
+}
+
+#[cfg(windows)]
+impl Drop for UserProcess {
+    fn drop(&mut self) {
+        use std::os::windows::io::AsRawHandle;
+        use windows_sys::Win32::UI::Shell::UnloadUserProfile;
+
+        unsafe { UnloadUserProfile(self.token.as_raw_handle(), self.profile as _) };
+    }
+}
+
+#[cfg(windows)]
+impl UserProcess {
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        use std::os::windows::io::AsRawHandle;
+        use std::os::windows::process::ExitStatusExt;
+        use windows_sys::Win32::Foundation::{WAIT_OBJECT_0, WAIT_TIMEOUT};
+        use windows_sys::Win32::System::Threading::{GetExitCodeProcess, WaitForSingleObject};
+
+        match unsafe { WaitForSingleObject(self.handle.as_raw_handle(), 0) } {
+            WAIT_OBJECT_0 => {
+                let mut exit_code = 0;
+
+                if unsafe { GetExitCodeProcess(self.handle.as_raw_handle(), &mut exit_code) } == 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                Ok(Some(ExitStatus::from_raw(exit_code)))
+            }
+            WAIT_TIMEOUT => Ok(None),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn kill(&mut self) {
+        use std::os::windows::io::AsRawHandle;
+        use windows_sys::Win32::System::Threading::{
+            TerminateProcess, WaitForSingleObject, INFINITE,
+        };
+
+        unsafe {
+            TerminateProcess(self.handle.as_raw_handle(), 1);
+            WaitForSingleObject(self.handle.as_raw_handle(), INFINITE);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn read_credential(target_name: &str) -> io::Result<Vec<u16>> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Security::Credentials::{
+        CredFree, CredReadW, CREDENTIALW, CRED_TYPE_GENERIC,
+    };
+
+    let name: Vec<u16> = OsStr::new(target_name).encode_wide().chain([0]).collect();
+    let mut credential: *mut CREDENTIALW = std::ptr::null_mut();
+
+    if unsafe { CredReadW(name.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential) } == 0 {
+        return Err(io::Error::other(format!(
+            "the credential {} could not be read: {}",
+            target_name,
+            io::Error::last_os_error()
+        )));
+    }
+
+    // Generic credentials stored with cmdkey hold the password as UTF-16.
+    let password = unsafe {
+        let blob = std::slice::from_raw_parts(
+            (*credential).CredentialBlob,
+            (*credential).CredentialBlobSize as usize,
+        );
+
+        blob.chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .chain([0])
+            .collect()
+    };
+
+    unsafe { CredFree(credential.cast()) };
+
+    Ok(password)
+}
+
+#[cfg(windows)]
+fn spawn_as_user(
+    command: &Command,
+    run_as: &RunAsSettings,
+    priority: ProcessPriority,
+) -> io::Result<(UserProcess, File, File)> {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
+    use windows_sys::Win32::Foundation::{SetHandleInformation, HANDLE, HANDLE_FLAG_INHERIT};
+    use windows_sys::Win32::Security::{LogonUserW, LOGON32_LOGON_BATCH, LOGON32_PROVIDER_DEFAULT};
+    use windows_sys::Win32::System::Environment::{
+        CreateEnvironmentBlock, DestroyEnvironmentBlock,
+    };
+    use windows_sys::Win32::System::Pipes::CreatePipe;
+    use windows_sys::Win32::System::Threading::{
+        CreateProcessAsUserW, DeleteProcThreadAttributeList, InitializeProcThreadAttributeList,
+        UpdateProcThreadAttribute, CREATE_UNICODE_ENVIRONMENT, EXTENDED_STARTUPINFO_PRESENT,
+        PROCESS_INFORMATION, PROC_THREAD_ATTRIBUTE_HANDLE_LIST, STARTF_USESTDHANDLES,
+        STARTUPINFOEXW,
+    };
+    use windows_sys::Win32::UI::Shell::{LoadUserProfileW, UnloadUserProfile, PROFILEINFOW};
+
+    const PI_NOUI: u32 = 1;
+
+    let to_wide = |value: &OsStr| -> Vec<u16> { value.encode_wide().chain([0]).collect() };
+    let credential = run_as
+        .credential
+        .as_deref()
+        .ok_or_else(|| io::Error::other("run_as needs a credential to log on with on Windows"))?;
+    let mut password = read_credential(credential)?;
+    let (domain, mut user) = match run_as.user.split_once('\\') {
+        Some((domain, user)) => (Some(to_wide(OsStr::new(domain))), to_wide(OsStr::new(user))),
+        None => (None, to_wide(OsStr::new(&run_as.user))),
+    };
+    let mut token = std::ptr::null_mut();
+    let is_logged_on = unsafe {
+        LogonUserW(
+            user.as_ptr(),
+            domain
+                .as_ref()
+                .map_or(std::ptr::null(), |domain| domain.as_ptr()),
+            password.as_ptr(),
+            LOGON32_LOGON_BATCH,
+            LOGON32_PROVIDER_DEFAULT,
+            &mut token,
+        )
+    } != 0;
+
+    for unit in password.iter_mut() {
+        unsafe { std::ptr::write_volatile(unit, 0) };
+    }
+
+    if !is_logged_on {
+        return Err(io::Error::other(format!(
+            "{} could not log on: {}",
+            run_as.user,
+            io::Error::last_os_error()
+        )));
+    }
+
+    let token = unsafe { OwnedHandle::from_raw_handle(token) };
+    let mut profile_info: PROFILEINFOW = unsafe { std::mem::zeroed() };
+
+    profile_info.dwSize = std::mem::size_of::<PROFILEINFOW>() as u32;
+    profile_info.dwFlags = PI_NOUI;
+    profile_info.lpUserName = user.as_mut_ptr();
+
+    if unsafe { LoadUserProfileW(token.as_raw_handle(), &mut profile_info) } == 0 {
+        return Err(io::Error::other(format!(
+            "the profile of {} could not be loaded: {}",
+            run_as.user,
+            io::Error::last_os_error()
+        )));
+    }
+
+    let unload_profile = || unsafe {
+        UnloadUserProfile(token.as_raw_handle(), profile_info.hProfile);
+    };
+
+    // Pipes start out private so that concurrent spawns can't inherit them.
+    let create_pipe = || -> io::Result<(File, OwnedHandle)> {
+        let (mut read, mut write) = (std::ptr::null_mut(), std::ptr::null_mut());
+
+        if unsafe { CreatePipe(&mut read, &mut write, std::ptr::null(), 0) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(unsafe {
+            (
+                File::from_raw_handle(read),
+                OwnedHandle::from_raw_handle(write),
+            )
+        })
+    };
+    let pipes = create_pipe().and_then(|stdout| Ok((stdout, create_pipe()?)));
+    let ((stdout, stdout_write), (stderr, stderr_write)) = match pipes {
+        Ok(pipes) => pipes,
+        Err(e) => {
+            unload_profile();
+            return Err(e);
+        }
+    };
+
+    let mut command_line = command.get_program().to_os_string();
+
+    for arg in command.get_args() {
+        command_line.push(" ");
+        command_line.push(arg);
+    }
+
+    let mut command_line = to_wide(&command_line);
+    let mut variables: Vec<(OsString, OsString)> = Vec::new();
+    let mut user_environment = std::ptr::null_mut();
+
+    if unsafe { CreateEnvironmentBlock(&mut user_environment, token.as_raw_handle(), 0) } != 0 {
+        let mut cursor = user_environment as *const u16;
+
+        loop {
+            let length = (0..)
+                .take_while(|&offset| unsafe { *cursor.add(offset) } != 0)
+                .count();
+
+            if length == 0 {
+                break;
+            }
+
+            let variable =
+                OsString::from_wide(unsafe { std::slice::from_raw_parts(cursor, length) });
+            let variable = variable.to_string_lossy();
+
+            // Per-drive variables such as `=C:` start with the separator itself.
+            if let Some(index) = variable.get(1..).and_then(|rest| rest.find('=')) {
+                variables.push((
+                    OsString::from(&variable[..index + 1]),
+                    OsString::from(&variable[index + 2..]),
+                ));
+            }
+
+            cursor = unsafe { cursor.add(length + 1) };
+        }
+
+        unsafe { DestroyEnvironmentBlock(user_environment) };
+    }
+
+    for (key, value) in command.get_envs() {
+        variables.retain(|(cur_key, _)| !cur_key.eq_ignore_ascii_case(key));
+        variables.extend(value.map(|value| (key.to_os_string(), value.to_os_string())));
+    }
+
+    variables.sort_by_key(|(key, _)| key.to_ascii_uppercase());
+
+    let environment: Vec<u16> = variables
+        .iter()
+        .flat_map(|(key, value)| {
+            let mut variable = key.clone();
+
+            variable.push("=");
+            variable.push(value);
+            to_wide(&variable)
+        })
+        .chain([0])
+        .collect();
+    let current_dir = command
+        .get_current_dir()
+        .map(|directory| to_wide(directory.as_os_str()));
+    let inherited_handles: [HANDLE; 2] =
+        [stdout_write.as_raw_handle(), stderr_write.as_raw_handle()];
+    let mut attribute_list_size = 0;
+
+    unsafe {
+        InitializeProcThreadAttributeList(std::ptr::null_mut(), 1, 0, &mut attribute_list_size)
+    };
+
+    let mut attribute_list = vec![0u8; attribute_list_size];
+    let mut startup_info: STARTUPINFOEXW = unsafe { std::mem::zeroed() };
+    let mut information: PROCESS_INFORMATION = unsafe { std::mem::zeroed() };
+
+    startup_info.StartupInfo.cb = std::mem::size_of::<STARTUPINFOEXW>() as u32;
+    startup_info.StartupInfo.dwFlags = STARTF_USESTDHANDLES;
+    startup_info.StartupInfo.hStdOutput = stdout_write.as_raw_handle();
+    startup_info.StartupInfo.hStdError = stderr_write.as_raw_handle();
+    startup_info.lpAttributeList = attribute_list.as_mut_ptr().cast();
+
+    let is_created = unsafe {
+        InitializeProcThreadAttributeList(
+            startup_info.lpAttributeList,
+            1,
+            0,
+            &mut attribute_list_size,
+        ) != 0
+            && UpdateProcThreadAttribute(
+                startup_info.lpAttributeList,
+                0,
+                PROC_THREAD_ATTRIBUTE_HANDLE_LIST as usize,
+                inherited_handles.as_ptr().cast(),
+                std::mem::size_of_val(&inherited_handles),
+                std::ptr::null_mut(),
+                std::ptr::null(),
+            ) != 0
+            && inherited_handles.iter().all(|&handle| {
+                SetHandleInformation(handle, HANDLE_FLAG_INHERIT, HANDLE_FLAG_INHERIT) != 0
+            })
+            && CreateProcessAsUserW(
+                token.as_raw_handle(),
+                std::ptr::null(),
+                command_line.as_mut_ptr(),
+                std::ptr::null(),
+                std::ptr::null(),
+                1,
+                CREATE_UNICODE_ENVIRONMENT
+                    | EXTENDED_STARTUPINFO_PRESENT
+                    | priority_class(priority),
+                environment.as_ptr().cast(),
+                current_dir
+                    .as_ref()
+                    .map_or(std::ptr::null(), |directory| directory.as_ptr()),
+                &startup_info.StartupInfo,
+                &mut information,
+            ) != 0
+    };
+    let error = io::Error::last_os_error();
+
+    unsafe { DeleteProcThreadAttributeList(startup_info.lpAttributeList) };
+
+    if !is_created {
+        unload_profile();
+        return Err(error);
+    }
+
+    drop(unsafe { OwnedHandle::from_raw_handle(information.hThread) });
+
+    let process = UserProcess {
+        handle: unsafe { OwnedHandle::from_raw_handle(information.hProcess) },
+        id: information.dwProcessId,
+        profile: profile_info.hProfile as usize,
+        token,
+    };
+
+    Ok((process, stdout, stderr))
+}
+
+#[cfg(unix)]
+struct UnixUser {
+    uid: u32,
+    gid: u32,
+    home: PathBuf,
+    groups: Vec<libc::gid_t>,
+}
+
+#[cfg(unix)]
+fn lookup_user(name: &str) -> io::Result<UnixUser> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let name = std::ffi::CString::new(name).map_err(io::Error::other)?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0; 16384];
+    let mut result = std::ptr::null_mut();
+    let code = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+
+    if result.is_null() {
+        return Err(match code {
+            0 => io::Error::new(io::ErrorKind::NotFound, "the run_as user does not exist"),
+            code => io::Error::from_raw_os_error(code),
+        });
+    }
+
+    let home = unsafe { std::ffi::CStr::from_ptr(passwd.pw_dir) };
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let code = unsafe {
+            libc::getgrouplist(
+                name.as_ptr(),
+                passwd.pw_gid,
+                groups.as_mut_ptr(),
+                &mut count,
+            )
+        };
+
+        groups.resize(count as usize, 0);
+
+        if code >= 0 {
+            break;
+        }
+    }
+
+    Ok(UnixUser {
+        uid: passwd.pw_uid,
+        gid: passwd.pw_gid,
+        home: PathBuf::from(OsStr::from_bytes(home.to_bytes())),
+        groups,
+    })
+}
+
+fn spawn_script(
+    command: &mut Command,
+    settings: &Settings,
+) -> io::Result<(ScriptProcess, JoinHandle<String>, JoinHandle<String>)> {
+    let encoding = settings.script_output_encoding;
+
+    #[cfg(windows)]
+    if let Some(run_as) = &settings.run_as {
+        let (process, stdout, stderr) = spawn_as_user(command, run_as, settings.process_priority)?;
+
+        return Ok((
+            ScriptProcess::User(process),
+            collect_output(Some(stdout), encoding),
+            collect_output(Some(stderr), encoding),
+        ));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        command.process_group(0);
+
+        if let Some(run_as) = &settings.run_as {
+            let user = lookup_user(&run_as.user)?;
+
+            command
+                .env("HOME", &user.home)
+                .env("USER", &run_as.user)
+                .env("LOGNAME", &run_as.user);
+
+            // std can't set supplementary groups yet, so switch identities by hand.
+            unsafe {
+                command.pre_exec(move || {
+                    if libc::setgroups(user.groups.len() as _, user.groups.as_ptr()) != 0
+                        || libc::setgid(user.gid) != 0
+                        || libc::setuid(user.uid) != 0
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+
+                    Ok(())
+                });
+            }
+        }
+    }
+
+    let mut child = command.spawn()?;
+    let stdout = collect_output(child.stdout.take(), encoding);
+    let stderr = collect_output(child.stderr.take(), encoding);
+
+    Ok((ScriptProcess::Child(child), stdout, stderr))
+}
+
+fn kill_process_tree(child: &mut ScriptProcess) {
+    #[cfg(windows)]
+    let _ = Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &child.id().to_string()])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+
+    #[cfg(unix)]
+    unsafe {
+        libc::killpg(child.id() as libc::pid_t, libc::SIGKILL);
+    }
+
+    child.kill();
+}
+
+fn wait_for_child(
+    mut child: ScriptProcess,
+    cancel: &AtomicBool,
+    timeout: Option<Duration>,
+) -> ChildWait {
+    let started_at = Instant::now();
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(exit_status)) => return ChildWait::Exited(exit_status),
+            Ok(None) if cancel.load(Ordering::SeqCst) => {
+                print_info("Killing the running script");
+                kill_process_tree(&mut child);
+
+                return ChildWait::Cancelled;
+            }
+            Ok(None) if timeout.is_some_and(|timeout| started_at.elapsed() >= timeout) => {
+                print_error("Script timed out, killing it");
+                kill_process_tree(&mut child);
+
+                return ChildWait::TimedOut;
+            }
+            Ok(None) => sleep(CHILD_POLL_INTERVAL),
+            Err(e) => return ChildWait::Failed(e),
+        }
+    }
+}
+
+struct RunContext {
+    file: Option<(PathBuf, Option<usize>)>,
+    cancel: Arc<AtomicBool>,
+    correlation_id: Option<String>,
+    source: String,
+    filename_prefix: Option<String>,
+}
+
+impl RunContext {
+    fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::SeqCst)
+    }
+}
+
+fn generate_correlation_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = DefaultHasher::new();
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .hash(&mut hasher);
+    std::process::id().hash(&mut hasher);
+    COUNTER.fetch_add(1, Ordering::SeqCst).hash(&mut hasher);
+
+    format!("{:08x}", hasher.finish() as u32)
+}
+
+enum ScriptFailure {
+    NotFound(String),
+    Exited(i32),
+    TimedOut(Duration),
+    Interrupted,
+    TooManyReruns(u32),
+    Locked(String),
+    Error(String),
+}
+
+impl ScriptFailure {
+    fn describe(&self) -> String {
+        match self {
+            ScriptFailure::NotFound(path) => {
+                format!(
+                    "{} does not exist, check the script settings in path.toml",
+                    path
+                )
+            }
+            ScriptFailure::Exited(exit_code) => {
+                format!("the script exited with code {}", exit_code)
+            }
+            ScriptFailure::TimedOut(timeout) => {
+                format!("the script timed out after {}s", timeout.as_secs())
+            }
+            ScriptFailure::Interrupted => "the script was interrupted".to_string(),
+            ScriptFailure::TooManyReruns(max_reruns) => {
+                format!(
+                    "the script asked to run again more than {} times",
+                    max_reruns
+                )
+            }
+            ScriptFailure::Locked(message) | ScriptFailure::Error(message) => message.clone(),
+        }
+    }
+}
+
+struct ScriptOutcome {
+    success: bool,
+    summary: Option<String>,
+    attempts: u32,
+    missing_environment: Option<String>,
+    failed_step: Option<String>,
+    exit_code: Option<i32>,
+    failure: Option<ScriptFailure>,
+}
+
+impl ScriptOutcome {
+    fn failed(failure: ScriptFailure) -> Self {
+        ScriptOutcome {
+            success: false,
+            summary: None,
+            attempts: 1,
+            missing_environment: None,
+            failed_step: None,
+            exit_code: None,
+            failure: Some(failure),
+        }
+    }
+}
+
+#[cfg(windows)]
+const COMMAND_NOT_FOUND_EXIT_CODE: i32 = 9009;
+#[cfg(not(windows))]
+const COMMAND_NOT_FOUND_EXIT_CODE: i32 = 127;
+
+fn detect_missing_environment(env_name: &str, stderr: &str, exit_code: i32) -> Option<String> {
+    if stderr.contains("EnvironmentNameNotFound")
+        || stderr.contains("Could not find conda environment")
+    {
+        return Some(format!(
+            "The conda environment \"{}\" does not exist, check env_name.",
+            env_name
+        ));
+    }
+
+    if let Some(line) = stderr
+        .lines()
+        .find(|line| line.contains("is not recognized as an internal or external command"))
+    {
+        let command_name = line.split('\'').nth(1).unwrap_or("A command");
+
+        return Some(format!(
+            "{} was not found, check that it is installed and on the PATH of the service.",
+            command_name
+        ));
+    }
+
+    (exit_code == COMMAND_NOT_FOUND_EXIT_CODE).then(|| {
+        "A command in command_template was not found, check that conda is installed and on the PATH of the service."
+            .to_string()
+    })
+}
+
+#[cfg(windows)]
+fn decode_code_page(bytes: &[u8], code_page: u32) -> String {
+    use windows_sys::Win32::Globalization::MultiByteToWideChar;
+
+    let Ok(byte_count) = i32::try_from(bytes.len()) else {
+        return String::from_utf8_lossy(bytes).into_owned();
+    };
+    let wide_count = unsafe {
+        MultiByteToWideChar(
+            code_page,
+            0,
+            bytes.as_ptr(),
+            byte_count,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+
+    if wide_count <= 0 {
+        return String::from_utf8_lossy(bytes).into_owned();
+    }
+
+    let mut wide = vec![0u16; wide_count as usize];
+
+    unsafe {
+        MultiByteToWideChar(
+            code_page,
+            0,
+            bytes.as_ptr(),
+            byte_count,
+            wide.as_mut_ptr(),
+            wide_count,
+        )
+    };
+
+    String::from_utf16_lossy(&wide)
+}
+
+#[cfg(windows)]
+fn decode_output(bytes: &[u8], encoding: OutputEncoding) -> String {
+    use windows_sys::Win32::Globalization::CP_ACP;
+
+    match encoding {
+        OutputEncoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+        OutputEncoding::Gbk => decode_code_page(bytes, GBK_CODE_PAGE),
+        OutputEncoding::Ansi => decode_code_page(bytes, CP_ACP),
+    }
+}
+
+#[cfg(not(windows))]
+fn decode_output(bytes: &[u8], _encoding: OutputEncoding) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+fn collect_output<R: Read + Send + 'static>(
+    pipe: Option<R>,
+    encoding: OutputEncoding,
+) -> JoinHandle<String> {
+    spawn(move || {
+        let mut output = Vec::new();
+
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+
+        decode_output(&output, encoding)
+    })
+}
+
+fn summarize_output(output: &str, line_count: usize) -> Option<String> {
+    let lines: Vec<&str> = output
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    if lines.is_empty() {
+        return None;
+    }
+
+    let summary = lines[lines.len().saturating_sub(line_count)..].join("\n");
+    let char_count = summary.chars().count();
+
+    if char_count <= MAX_SUMMARY_CHARS {
+        return Some(summary);
+    }
+
+    Some(format!(
+        "...{}",
+        summary
+            .chars()
+            .skip(char_count - MAX_SUMMARY_CHARS)
+            .collect::<String>()
+    ))
+}
+
+struct ScriptLock {
+    file: File,
+}
+
+impl Drop for ScriptLock {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+fn acquire_script_lock(directory: &str) -> Result<Option<ScriptLock>, String> {
+    let lock_path = Path::new(directory).join(".sheetwizard.lock");
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+    {
+        Ok(file) => file,
+        Err(e) => {
+            print_error(&format!(
+                "Cannot open {}, running without a lock: {}",
+                lock_path.display(),
+                e
+            ));
+            return Ok(None);
+        }
+    };
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(format!(
+                "{} is held by another run, skipped",
+                lock_path.display()
+            ))
+        }
+        Err(TryLockError::Error(e)) => {
+            print_error(&format!(
+                "Cannot lock {}, running without a lock: {}",
+                lock_path.display(),
+                e
+            ));
+            return Ok(None);
+        }
+    }
+
+    let mut holder = String::new();
+
+    if file.read_to_string(&mut holder).is_ok() && !holder.trim().is_empty() {
+        print_info(&format!(
+            "Reclaimed a stale lock left by process {}",
+            holder.trim()
+        ));
+    }
+
+    let _ = file.set_len(0);
+    let _ = file.seek(SeekFrom::Start(0));
+    let _ = write!(file, "{}", std::process::id());
+
+    Ok(Some(ScriptLock { file }))
+}
+
+type SharedScriptLock = Arc<Option<ScriptLock>>;
+
+static SHARED_SCRIPT_LOCKS: Mutex<BTreeMap<String, Weak<Option<ScriptLock>>>> =
+    Mutex::new(BTreeMap::new());
+
+fn acquire_shared_script_lock(directory: &str) -> Result<SharedScriptLock, String> {
+    let mut shared = SHARED_SCRIPT_LOCKS
+        .lock()
+        .map_err(|_| "The script lock is poisoned".to_string())?;
+
+    shared.retain(|_, lock| lock.strong_count() > 0);
+
+    if let Some(lock) = shared.get(directory).and_then(Weak::upgrade) {
+        return Ok(lock);
+    }
+
+    let lock = Arc::new(acquire_script_lock(directory)?);
+
+    shared.insert(directory.to_string(), Arc::downgrade(&lock));
+    Ok(lock)
+}
+
+#[cfg(windows)]
+fn shell_command(command_line: String) -> Command {
+    let mut command = Command::new("cmd");
+
+    command.arg("/C").arg(command_line);
+    command
+}
+
+#[cfg(not(windows))]
+fn shell_command(command_line: String) -> Command {
+    let mut command = Command::new("sh");
+
+    command.arg("-c").arg(command_line);
+    command
+}
+
+static PYTHON_EXECUTABLES: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+fn resolve_python_executable(env_name: &str) -> Option<String> {
+    let lookup = if cfg!(windows) {
+        "where python"
+    } else {
+        "which python"
+    };
+    let output = shell_command(format!("conda run -n {} {}", quote_arg(env_name), lookup))
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+}
+
+fn init_python_executables(path_config: &PathConfig) {
+    let settings = &path_config.settings;
+    let resolved = PYTHON_EXECUTABLES
+        .lock()
+        .map(|cur_executables| cur_executables.clone())
+        .unwrap_or_default();
+    let mut executables: Vec<(String, String)> = Vec::new();
+
+    if settings.fast_activation
+        && settings.command_template.is_none()
+        && matches!(settings.interpreter, Interpreter::Python)
+    {
+        let step_env_names = settings
+            .scripts
+            .iter()
+            .chain(path_config.file.iter().flat_map(|entry| &entry.scripts))
+            .filter_map(|step| step.env_name.as_deref());
+
+        for env_name in std::iter::once(settings.env_name.as_str()).chain(step_env_names) {
+            if executables.iter().any(|(name, _)| name == env_name) {
+                continue;
+            }
+
+            // A reload keeps what earlier loads resolved instead of spawning conda again.
+            if let Some(executable) = resolved.iter().find(|(name, _)| name == env_name) {
+                executables.push(executable.clone());
+                continue;
+            }
+
+            let started_at = Instant::now();
+
+            match resolve_python_executable(env_name) {
+                Some(python) => {
+                    print_info(&format!(
+                        "Resolved the python of {} to {} in {:?}",
+                        env_name,
+                        python,
+                        started_at.elapsed()
+                    ));
+                    executables.push((env_name.to_string(), python));
+                }
+                None => print_error(&format!(
+                    "Cannot resolve the python of {}, falling back to the command template",
+                    env_name
+                )),
+            }
+        }
+    }
+
+    if let Ok(mut cur_executables) = PYTHON_EXECUTABLES.lock() {
+        *cur_executables = executables;
+    }
+}
+
+fn parse_conda_env_list(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .flat_map(|line| {
+            line.split_whitespace()
+                .filter(|token| *token != "*")
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn check_conda_environments(path_config: &PathConfig) {
+    let settings = &path_config.settings;
+
+    if !settings.check_conda_env || !settings.command_template().contains("{env}") {
+        return;
+    }
+
+    let output = match shell_command("conda env list".to_string()).output() {
+        Ok(output) if output.status.success() => output,
+        _ => {
+            print_error("conda env list failed, cannot check that env_name exists");
+            return;
+        }
+    };
+    let environments = parse_conda_env_list(&String::from_utf8_lossy(&output.stdout));
+    let step_env_names = settings
+        .scripts
+        .iter()
+        .chain(path_config.file.iter().flat_map(|entry| &entry.scripts))
+        .filter_map(|step| step.env_name.as_deref());
+    let mut checked: Vec<&str> = Vec::new();
+
+    for env_name in std::iter::once(settings.env_name.as_str()).chain(step_env_names) {
+        if checked.contains(&env_name) {
+            continue;
+        }
+
+        checked.push(env_name);
+
+        if !environments
+            .iter()
+            .any(|environment| environment == env_name)
+        {
+            let message = format!(
+                "The conda environment \"{}\" does not exist, check env_name.",
+                env_name
+            );
+
+            print_error(&message);
+            show_notification(&message);
+        }
+    }
+}
+
+fn python_executable(env_name: &str) -> Option<String> {
+    PYTHON_EXECUTABLES
+        .lock()
+        .ok()?
+        .iter()
+        .find(|(name, _)| name == env_name)
+        .map(|(_, python)| python.clone())
+}
+
+fn build_script_command(
+    settings: &Settings,
+    step: &ScriptStep,
+    context: &RunContext,
+    command_line: String,
+) -> Command {
+    let mut command = shell_command(command_line);
+
+    command
+        .envs(&settings.script_env)
+        .envs(&step.env)
+        .current_dir(settings.script_working_directory())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    if let Some(correlation_id) = &context.correlation_id {
+        command.env("SW_CORRELATION_ID", correlation_id);
+    }
+
+    if let Some(filename_prefix) = &context.filename_prefix {
+        command.env("SW_FILENAME_PREFIX", filename_prefix);
+    }
+
+    if let Some((pass_file, (path, version))) =
+        settings.pass_file.as_ref().zip(context.file.as_ref())
+    {
+        if pass_file.as_env {
+            command.env(&pass_file.path_env, path);
+
+            if let Some(version) = version {
+                command.env(&pass_file.version_env, version.to_string());
+            }
+        }
+    }
+
+    apply_process_priority(&mut command, settings.process_priority);
+
+    command
+}
+
+fn run_script(settings: &Settings, step: &ScriptStep, context: &RunContext) -> ScriptOutcome {
+    let directory = &settings.script_directory;
+
+    if !Path::new(directory).exists() {
+        return ScriptOutcome::failed(ScriptFailure::NotFound(directory.clone()));
+    }
+
+    let script_path = Path::new(directory).join(&step.filename);
+
+    if !script_path.exists() {
+        print_error(&format!("Script {} does not exist", script_path.display()));
+
+        return ScriptOutcome::failed(ScriptFailure::NotFound(
+            script_path.to_string_lossy().to_string(),
+        ));
+    }
+
+    if !Path::new(settings.script_working_directory()).exists() {
+        return ScriptOutcome::failed(ScriptFailure::NotFound(
+            settings.script_working_directory().to_string(),
+        ));
+    }
+
+    let command_template = match python_executable(step.env_name(settings)) {
+        Some(python) => format!("{} \"{{script_path}}\" -m SheetWizard", quote_arg(&python)),
+        None => settings.command_template().to_string(),
+    };
+    let mut command_line = command_template
+        .replace("{env}", step.env_name(settings))
+        .replace("{script}", &step.filename)
+        .replace("{dir}", directory)
+        .replace("{script_path}", &script_path.to_string_lossy())
+        .replace("{file}", &script_path.to_string_lossy());
+
+    for arg in &step.args {
+        command_line.push(' ');
+        command_line.push_str(&quote_arg(arg));
+    }
+
+    let pass_file = settings.pass_file.as_ref().zip(context.file.as_ref());
+
+    if let Some((pass_file, (path, version))) = pass_file {
+        if pass_file.as_args {
+            command_line.push(' ');
+            command_line.push_str(&pass_file.path_arg);
+            command_line.push(' ');
+            command_line.push_str(&quote_arg(&path.to_string_lossy()));
+
+            if let Some(version) = version {
+                command_line.push_str(&format!(" {} {}", pass_file.version_arg, version));
+            }
+        }
+    }
+
+    if settings.dry_run {
+        let message = format!(
+            "Dry run, would run `{}` in {}{}",
+            command_line,
+            settings.script_working_directory(),
+            context
+                .file
+                .as_ref()
+                .map(|(path, _)| format!(" for {}", path.display()))
+                .unwrap_or_default()
+        );
+
+        print_info(&message);
+
+        return ScriptOutcome {
+            success: true,
+            summary: Some(message),
+            attempts: 1,
+            missing_environment: None,
+            failed_step: None,
+            exit_code: None,
+            failure: None,
+        };
+    }
+
+    let mut command = build_script_command(settings, step, context, command_line);
+
+    let _lock = match acquire_shared_script_lock(directory) {
+        Ok(lock) => lock,
+        Err(message) => return ScriptOutcome::failed(ScriptFailure::Locked(message)),
+    };
+
+    log_event(
+        LogLevel::Info,
+        "script_started",
+        &format!("Running {}", step.filename),
+        json!({ "script": step.filename }),
+    );
+
+    let started_at = Instant::now();
+    let (child, stdout, stderr) = match spawn_script(&mut command, settings) {
+        Ok(spawned) => spawned,
+        Err(e) => {
+            return ScriptOutcome::failed(ScriptFailure::Error(format!(
+                "the script could not be started ({})",
+                e
+            )))
+        }
+    };
+    let script_timeout = (settings.script_timeout_secs > 0)
+        .then(|| Duration::from_secs(settings.script_timeout_secs));
+    let child_wait = wait_for_child(child, &context.cancel, script_timeout);
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    let finished = |level: LogLevel, message: &str, exit_code: Option<i32>| {
+        log_event(
+            level,
+            "script_finished",
+            message,
+            json!({
+                "script": step.filename,
+                "exit_code": exit_code,
+                "duration_ms": started_at.elapsed().as_millis() as u64,
+            }),
+        );
+    };
+
+    match child_wait {
+        ChildWait::Exited(exit_status) => {
+            if exit_status.success() {
+                finished(
+                    LogLevel::Info,
+                    "Executed script successfully",
+                    exit_status.code(),
+                );
+
+                ScriptOutcome {
+                    success: true,
+                    summary: summarize_output(&stdout, 1),
+                    attempts: 1,
+                    missing_environment: None,
+                    failed_step: None,
+                    exit_code: exit_status.code(),
+                    failure: None,
+                }
+            } else {
+                let exit_code = exit_status.code().unwrap_or(-1);
+
+                if settings.max_reruns > 0 && exit_code == settings.rerun_exit_code {
+                    finished(
+                        LogLevel::Info,
+                        "Executed script requested another run",
+                        Some(exit_code),
+                    );
+                } else {
+                    finished(
+                        LogLevel::Error,
+                        &format!("Executed script failed with exit code: {}", exit_code),
+                        Some(exit_code),
+                    );
+                }
+
+                ScriptOutcome {
+                    missing_environment: detect_missing_environment(
+                        step.env_name(settings),
+                        &stderr,
+                        exit_code,
+                    ),
+                    exit_code: Some(exit_code),
+                    summary: summarize_output(&stderr, 2).or_else(|| summarize_output(&stdout, 2)),
+                    ..ScriptOutcome::failed(ScriptFailure::Exited(exit_code))
+                }
+            }
+        }
+        ChildWait::TimedOut => {
+            let failure = ScriptFailure::TimedOut(script_timeout.unwrap_or_default());
+
+            finished(
+                LogLevel::Error,
+                &format!("Executed script stopped, {}", failure.describe()),
+                None,
+            );
+            ScriptOutcome::failed(failure)
+        }
+        ChildWait::Failed(e) => {
+            let failure =
+                ScriptFailure::Error(format!("the script could not be waited for ({})", e));
+
+            finished(
+                LogLevel::Error,
+                &format!("Executed script stopped, {}", failure.describe()),
+                None,
+            );
+            ScriptOutcome::failed(failure)
+        }
+        ChildWait::Cancelled => {
+            finished(
+                LogLevel::Error,
+                &format!(
+                    "Executed script stopped, {}",
+                    ScriptFailure::Interrupted.describe()
+                ),
+                None,
+            );
+            ScriptOutcome::failed(ScriptFailure::Interrupted)
+        }
+    }
+}
+
+fn run_script_with_retry(
+    settings: &Settings,
+    step: &ScriptStep,
+    context: &RunContext,
+) -> ScriptOutcome {
+    let mut delay = Duration::from_millis(settings.retry_delay_ms);
+    let mut attempts = 1;
+    let mut reruns = 0;
+
+    loop {
+        let outcome = run_script(settings, step, context);
+
+        if !outcome.success
+            && settings.max_reruns > 0
+            && outcome.exit_code == Some(settings.rerun_exit_code)
+            && !context.is_cancelled()
+            && !IS_SHUTTING_DOWN.load(Ordering::SeqCst)
+        {
+            if reruns < settings.max_reruns {
+                reruns += 1;
+                print_info(&format!(
+                    "{} asked to run again ({}/{})",
+                    step.filename, reruns, settings.max_reruns
+                ));
+                continue;
+            }
+
+            print_error(&format!(
+                "{} asked to run again more than {} times, giving up",
+                step.filename, settings.max_reruns
+            ));
+
+            return ScriptOutcome {
+                attempts,
+                failure: Some(ScriptFailure::TooManyReruns(settings.max_reruns)),
+                ..outcome
+            };
+        }
+
+        if outcome.success
+            || outcome.missing_environment.is_some()
+            || matches!(
+                outcome.failure,
+                Some(ScriptFailure::NotFound(_) | ScriptFailure::Locked(_))
+            )
+            || attempts > settings.max_retries
+            || context.is_cancelled()
+            || IS_SHUTTING_DOWN.load(Ordering::SeqCst)
+        {
+            return ScriptOutcome {
+                attempts,
+                ..outcome
+            };
+        }
+
+        print_info(&format!(
+            "{} failed, retrying in {}ms",
+            step.filename,
+            delay.as_millis()
+        ));
+
+        let retry_at = Instant::now() + delay;
+
+        while Instant::now() < retry_at && !context.is_cancelled() {
+            sleep(CHILD_POLL_INTERVAL);
+        }
+
+        attempts += 1;
+        delay *= 2;
+    }
+}
+
+fn run_scripts(settings: &Settings, steps: &[ScriptStep], context: &RunContext) -> ScriptOutcome {
+    let mut outcome = ScriptOutcome {
+        success: true,
+        summary: None,
+        attempts: 1,
+        missing_environment: None,
+        failed_step: None,
+        exit_code: None,
+        failure: None,
+    };
+
+    for step in steps {
+        let step_outcome = run_script_with_retry(settings, step, context);
+
+        if step_outcome.success {
+            if outcome.success {
+                outcome.summary = step_outcome.summary;
+                outcome.exit_code = step_outcome.exit_code;
+            }
+
+            continue;
+        }
+
+        outcome = ScriptOutcome {
+            failed_step: Some(step.filename.clone()),
+            ..step_outcome
+        };
+
+        if matches!(outcome.failure, Some(ScriptFailure::Locked(_))) {
+            break;
+        }
+
+        if !settings.continue_on_error
+            || outcome.missing_environment.is_some()
+            || context.is_cancelled()
+        {
+            print_error(&format!("Stopped after {} failed", step.filename));
+            break;
+        }
+    }
+
+    outcome
+}
+
+#[cfg(windows)]
+fn is_on_battery() -> bool {
+    use windows_sys::Win32::System::Power::{GetSystemPowerStatus, SYSTEM_POWER_STATUS};
+
+    let mut status: SYSTEM_POWER_STATUS = unsafe { std::mem::zeroed() };
+
+    // ACLineStatus is 0 when offline, 1 when online and 255 when unknown.
+    unsafe { GetSystemPowerStatus(&mut status) != 0 && status.ACLineStatus == 0 }
+}
+
+#[cfg(not(windows))]
+fn is_on_battery() -> bool {
+    false
+}
+
+#[cfg(windows)]
+fn is_file_locked(path: &Path) -> bool {
+    use std::os::windows::fs::OpenOptionsExt;
+    use windows_sys::Win32::Foundation::ERROR_SHARING_VIOLATION;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE,
+    };
+
+    // Sharing everything keeps the probe from locking Excel out. Only an editor that denies
+    // writes, as Excel does while a workbook is open, makes the write open fail.
+    matches!(
+        OpenOptions::new()
+            .write(true)
+            .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE)
+            .open(path),
+        Err(e) if e.raw_os_error() == Some(ERROR_SHARING_VIOLATION as i32)
+    )
+}
+
+#[cfg(not(windows))]
+fn is_file_locked(path: &Path) -> bool {
+    File::open(path).is_ok_and(|file| file.try_lock().is_err())
+}
+
+fn show_notification(message: &str) {
+    show_deduped_notification(message, message);
+}
+
+fn show_deduped_notification(message: &str, dedupe_key: &str) {
+    let (is_enabled, toast, fallback, is_duplicate) = match NOTIFICATIONS.lock().as_deref_mut() {
+        Ok(Some(notifications)) => (
+            notifications.is_enabled,
+            notifications.toast.clone(),
+            notifications.fallback,
+            notifications.is_enabled && notifications.is_duplicate(dedupe_key),
+        ),
+        _ => (
+            true,
+            ToastOptions {
+                title: Language::default().messages().title.to_string(),
+                duration: NotificationDuration::default(),
+                app_id: None,
+                icon_path: None,
+            },
+            NotificationFallback::default(),
+            false,
+        ),
+    };
+
+    if !is_enabled {
+        print_info(&format!("Notification: {}", message));
+        return;
+    }
+
+    if is_duplicate {
+        print_info(&format!("Duplicate notification suppressed: {}", message));
+        return;
+    }
+
+    report_toast_result(&toast, message, fallback, show_toast(&toast, message));
+}
+
+fn report_toast_result(
+    toast: &ToastOptions,
+    message: &str,
+    fallback: NotificationFallback,
+    result: Result<(), String>,
+) {
+    let Err(e) = result else {
+        return;
+    };
+
+    print_error(&format!("Failed to show toast notification: {}", e));
+
+    if fallback == NotificationFallback::Log {
+        write_notification_log(&toast.title, message);
+    }
+}
+
+fn write_notification_log(title: &str, message: &str) {
+    write_file_log(&format_log_line(
+        log_format(),
+        "NOTIFY",
+        "notification",
+        &format!("{}: {}", title, message),
+        json!({ "title": title }),
+    ));
+}
+
+#[cfg(windows)]
+fn show_toast(options: &ToastOptions, message: &str) -> Result<(), String> {
+    let toast = catch_unwind(AssertUnwindSafe(|| {
+        let mut toast = WinToastNotify::new()
+            .set_title(&options.title)
+            .set_messages(vec![message])
+            .set_duration(match options.duration {
+                NotificationDuration::Short => ToastDuration::Short,
+                NotificationDuration::Long => ToastDuration::Long,
+            });
+
+        if let Some(app_id) = &options.app_id {
+            toast = toast.set_app_id(app_id);
+        }
+
+        if let Some(icon_path) = &options.icon_path {
+            toast = toast.set_logo(icon_path, CropCircle::False);
+        }
+
+        toast.show()
+    }));
+
+    match toast {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(format!("{:?}", e)),
+        Err(panic) => Err(format!(
+            "the toast panicked: {}",
+            describe_panic(panic.as_ref())
+        )),
+    }
+}
+
+#[cfg(not(windows))]
+fn show_toast(options: &ToastOptions, message: &str) -> Result<(), String> {
+    println!("{}: {}", options.title, message);
+    write_notification_log(&options.title, message);
+    Ok(())
+}
+
+fn describe_event_kind(kind: &EventKind) -> String {
+    match kind {
+        EventKind::Modify(ModifyKind::Any) => "any".to_string(),
+        EventKind::Modify(ModifyKind::Data(change)) => format!("data: {:?}", change),
+        EventKind::Modify(ModifyKind::Metadata(metadata)) => format!("metadata: {:?}", metadata),
+        EventKind::Modify(ModifyKind::Name(mode)) => format!("name: {:?}", mode),
+        EventKind::Modify(ModifyKind::Other) => "other".to_string(),
+        _ => format!("{:?}", kind),
+    }
+}
+
+fn connect_syslog(
+    syslog_settings: &SyslogSettings,
+) -> syslog::Result<Logger<LoggerBackend, Formatter3164>> {
+    let formatter = Formatter3164 {
+        facility: syslog_settings
+            .facility
+            .parse::<Facility>()
+            .unwrap_or(Facility::LOG_USER),
+        hostname: None,
+        process: "SheetWizard".to_string(),
+        pid: std::process::id(),
+    };
+
+    match &syslog_settings.server {
+        Some(server) => syslog::udp(formatter, "0.0.0.0:0", server.as_str()),
+        None => syslog::unix(formatter),
+    }
+}
+
+fn send_to_syslog(syslog_settings: &SyslogSettings, success: bool, message: &str) {
+    let severity = if success {
+        syslog_settings.success_severity
+    } else {
+        syslog_settings.failure_severity
+    };
+    let result = connect_syslog(syslog_settings).and_then(|mut logger| match severity {
+        SyslogSeverity::Emerg => logger.emerg(message),
+        SyslogSeverity::Alert => logger.alert(message),
+        SyslogSeverity::Crit => logger.crit(message),
+        SyslogSeverity::Err => logger.err(message),
+        SyslogSeverity::Warning => logger.warning(message),
+        SyslogSeverity::Notice => logger.notice(message),
+        SyslogSeverity::Info => logger.info(message),
+        SyslogSeverity::Debug => logger.debug(message),
+    });
+
+    if let Err(e) = result {
+        print_error(&format!("Failed to write to syslog: {:?}", e));
+    }
+}
+
+fn watch_with_retry(
+    watcher: &mut dyn Watcher,
+    path: &Path,
+    recursive_mode: RecursiveMode,
+    retry_for: Duration,
+    max_attempts: u32,
+) -> NotifyResult<()> {
+    let started_at = Instant::now();
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 1;
+
+    loop {
+        match watcher.watch(path, recursive_mode) {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < max_attempts || started_at.elapsed() + delay <= retry_for => {
+                print_info(&format!(
+                    "Failed to watch {} (attempt {}), retrying in {:?}: {:?}",
+                    path.display(),
+                    attempt,
+                    delay,
+                    e
+                ));
+                sleep(delay);
+                delay = (delay * 2).min(WATCH_RETRY_MAX_DELAY);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn check_write_access(directory: &str) -> Result<(), String> {
+    // The archive directory is created on demand, so test the closest folder that exists.
+    let existing = Path::new(directory)
+        .ancestors()
+        .find(|path| path.is_dir())
+        .unwrap_or(Path::new(directory));
+    let test_path = existing.join(format!(".sheetwizard-write-test-{}", std::process::id()));
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&test_path)
+        .and_then(|mut file| file.write_all(b"SheetWizard"))
+        .and_then(|_| remove_file(&test_path))
+        .map_err(|e| format!("Cannot write to {}: {}", directory, e))
+}
+
+fn hash_file_content(path: &Path) -> Option<u64> {
+    let content = read(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+
+    content.hash(&mut hasher);
+
+    Some(hasher.finish())
+}
+
+fn snapshot_file(path: Option<PathBuf>) -> Option<(PathBuf, u64)> {
+    let path = path?;
+    let hash = hash_file_content(&path)?;
+
+    Some((path, hash))
+}
+
+#[derive(Serialize, Deserialize)]
+struct HistoryRecord {
+    timestamp: String,
+    source: String,
+    file: Option<PathBuf>,
+    version: Option<usize>,
+    success: bool,
+    exit_code: Option<i32>,
+    duration_ms: u64,
+}
+
+fn append_history(history: &FileLog, record: &HistoryRecord) -> Result<(), Box<dyn Error>> {
+    if history
+        .path
+        .metadata()
+        .is_ok_and(|metadata| metadata.len() >= history.max_bytes)
+    {
+        rotate_file_log(history);
+    }
+
+    let history_path = &history.path;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(history_path)?;
+
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+
+    Ok(())
+}
+
+fn parse_http_url(url: &str) -> Result<(&str, u16, &str), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or(format!("{} is not an http:// URL", url))?;
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| format!("{} has an invalid port", url))?,
+        ),
+        None => (authority, 80),
+    };
+
+    if host.is_empty() {
+        return Err(format!("{} has no host", url));
+    }
+
+    Ok((host, port, if path.is_empty() { "/" } else { path }))
+}
+
+fn post_webhook(url: &str, body: &str) -> Result<(), Box<dyn Error>> {
+    let (host, port, path) = parse_http_url(url)?;
+    let address = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or(format!("Cannot resolve {}", host))?;
+    let mut stream = TcpStream::connect_timeout(&address, WEBHOOK_TIMEOUT)?;
+
+    stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+    stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        port,
+        body.len(),
+        body
+    )?;
+
+    let mut status_line = String::new();
+    BufReader::new(&stream).read_line(&mut status_line)?;
+
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(format!("unexpected response \"{}\"", status_line.trim_end()).into()),
+    }
+}
+
+fn send_webhook(webhook_url: &str, record: &HistoryRecord) {
+    let webhook_url = webhook_url.to_string();
+    let body = match serde_json::to_string(record) {
+        Ok(body) => body,
+        Err(e) => {
+            print_error(&format!("Failed to encode the webhook payload: {}", e));
+            return;
+        }
+    };
+
+    spawn(move || {
+        if let Err(e) = post_webhook(&webhook_url, &body) {
+            print_error(&format!("Failed to post to {}: {}", webhook_url, e));
+        }
+    });
+}
+
+fn read_last_history_record(history_path: &str) -> Option<HistoryRecord> {
+    read_history_records(history_path, 1).pop()
+}
+
+fn read_history_records(history_path: &str, count: usize) -> Vec<HistoryRecord> {
+    let content = [format!("{}.1", history_path), history_path.to_string()]
+        .iter()
+        .map(|path| read_to_string(path).unwrap_or_default())
+        .collect::<String>();
+    let mut records: Vec<HistoryRecord> = content
+        .lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .take(count)
+        .collect();
+
+    records.reverse();
+    records
+}
+
+fn format_history_table(records: &[HistoryRecord]) -> String {
+    let rows: Vec<[String; 4]> = records
+        .iter()
+        .map(|record| {
+            [
+                record.timestamp.clone(),
+                record
+                    .file
+                    .as_deref()
+                    .map_or(record.source.clone(), |file| file.display().to_string()),
+                if record.success { "ok" } else { "failed" }.to_string(),
+                record
+                    .exit_code
+                    .map_or("-".to_string(), |exit_code| exit_code.to_string()),
+            ]
+        })
+        .collect();
+    let header = ["TIME", "FILE", "RESULT", "EXIT CODE"].map(str::to_string);
+    let widths: Vec<usize> = (0..4)
+        .map(|column| {
+            std::iter::once(&header)
+                .chain(&rows)
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    std::iter::once(&header)
+        .chain(&rows)
+        .map(|row| {
+            row.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn archive_path(
+    directory: &str,
+    template: &str,
+    path: &Path,
+    version: Option<usize>,
+    now: SystemTime,
+) -> PathBuf {
+    let timestamp = format_timestamp(now);
+    let fill = |text: &str| {
+        text.replace("{date}", &timestamp[..10])
+            .replace("{time}", &timestamp[11..].replace(':', ""))
+            .replace(
+                "{filename}",
+                &path.file_name().unwrap_or_default().to_string_lossy(),
+            )
+            .replace(
+                "{stem}",
+                &path.file_stem().unwrap_or_default().to_string_lossy(),
+            )
+            .replace(
+                "{ext}",
+                &path.extension().unwrap_or_default().to_string_lossy(),
+            )
+            .replace(
+                "{version}",
+                &version
+                    .map(|version| version.to_string())
+                    .unwrap_or_default(),
+            )
+    };
+
+    Path::new(&fill(directory)).join(fill(template))
+}
+
+fn archive_file(settings: &Settings, context: &RunContext) {
+    let (Some(directory), Some((path, version))) = (&settings.archive_directory, &context.file)
+    else {
+        return;
+    };
+    let destination = archive_path(
+        directory,
+        &settings.archive_filename,
+        path,
+        *version,
+        SystemTime::now(),
+    );
+
+    if settings.dry_run {
+        print_info(&format!(
+            "Dry run, would archive {} to {}",
+            path.display(),
+            destination.display()
+        ));
+        return;
+    }
+
+    let result = destination
+        .parent()
+        .map_or(Ok(()), create_dir_all)
+        .and_then(|_| match settings.archive_mode {
+            ArchiveMode::Copy => copy(path, &destination).map(|_| ()),
+            ArchiveMode::Move => rename(path, &destination).or_else(|_| {
+                copy(path, &destination)?;
+                remove_file(path)
+            }),
+        });
+
+    match result {
+        Ok(()) => print_info(&format!(
+            "Archived {} to {}",
+            path.display(),
+            destination.display()
+        )),
+        Err(e) => {
+            let message = format!(
+                "Failed to archive {} to {}: {}",
+                path.display(),
+                destination.display(),
+                e
+            );
+
+            print_error(&message);
+            show_notification(&message);
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct RunResult {
+    success: bool,
+    has_run: bool,
+    timed_out: bool,
+    duration: Duration,
+    message: Option<String>,
+    dedupe_key: Option<String>,
+}
+
+fn format_duration(duration: Duration) -> String {
+    match duration.as_secs() {
+        secs if secs < 60 => format!("{:.1}s", duration.as_secs_f64()),
+        secs => format!("{}m {}s", secs / 60, secs % 60),
+    }
+}
+
+fn process_sheet(settings: &Settings, steps: &[ScriptStep], context: &RunContext) -> RunResult {
+    if let Some((path, _)) = &context.file {
+        if !is_within_size_limit(settings, path) {
+            return RunResult::default();
+        }
+    }
+
+    let started_at = Instant::now();
+    let outcome = run_scripts(settings, steps, context);
+    let success = outcome.success;
+
+    if let Some(ScriptFailure::Locked(message)) = &outcome.failure {
+        print_info(message);
+        return RunResult::default();
+    }
+
+    let result = RunResult {
+        success,
+        has_run: true,
+        timed_out: matches!(outcome.failure, Some(ScriptFailure::TimedOut(_))),
+        duration: started_at.elapsed(),
+        message: None,
+        dedupe_key: None,
+    };
+
+    if context.is_cancelled() {
+        print_info("Processing cancelled");
+
+        return RunResult {
+            success: false,
+            has_run: false,
+            ..result
+        };
+    }
+
+    if success {
+        archive_file(settings, context);
+    }
+
+    let record = HistoryRecord {
+        timestamp: format_timestamp(SystemTime::now()),
+        source: context.source.clone(),
+        file: context.file.as_ref().map(|(path, _)| path.clone()),
+        version: context.file.as_ref().and_then(|(_, version)| *version),
+        success,
+        exit_code: outcome.exit_code,
+        duration_ms: result.duration.as_millis() as u64,
+    };
+
+    if let Some(history_path) = &settings.history_path {
+        let history = FileLog {
+            path: PathBuf::from(history_path),
+            max_bytes: settings.history_max_bytes,
+            max_files: settings.history_max_files,
+        };
+
+        if let Err(e) = append_history(&history, &record) {
+            print_error(&format!(
+                "Failed to write history to {}: {}",
+                history_path, e
+            ));
+        }
+    }
+
+    if let Some(webhook_url) = &settings.webhook_url {
+        send_webhook(webhook_url, &record);
+    }
+
+    let template = if success {
+        settings.success_template()
+    } else {
+        settings.failure_template()
+    };
+    let reason = outcome
+        .failure
+        .as_ref()
+        .map_or("the cause is unknown".to_string(), ScriptFailure::describe);
+    let mut message = match (&outcome.missing_environment, &outcome.failure) {
+        (Some(problem), _) => format!("{}: {}", context.source, problem),
+        (None, Some(ScriptFailure::NotFound(_))) => format!("{}: {}", context.source, reason),
+        (None, _) => template
+            .replace("{source}", &context.source)
+            .replace("{reason}", &reason)
+            .replace(
+                "{exit_code}",
+                &outcome
+                    .exit_code
+                    .map_or("none".to_string(), |code| code.to_string()),
+            ),
+    };
+
+    if settings.dry_run {
+        message = format!("[Dry run] {}", message);
+    }
+
+    if let Some(failed_step) = outcome.failed_step.as_ref().filter(|_| steps.len() > 1) {
+        message = format!("{} (failed at {})", message, failed_step);
+    }
+
+    if !success && outcome.attempts > 1 {
+        message = format!("{} ({} attempts)", message, outcome.attempts);
+    }
+
+    if let Some(correlation_id) = &context.correlation_id {
+        message = format!("{} [{}]", message, correlation_id);
+    }
+
+    // The duration differs on every run, so identical results are deduplicated without it.
+    let mut dedupe_key = message.clone();
+
+    message = message.replace("{duration}", &format_duration(result.duration));
+
+    if let Some(summary) = &outcome.summary {
+        message = format!("{}\n{}", message, summary);
+        dedupe_key = format!("{}\n{}", dedupe_key, summary);
+    }
+
+    if let Some(syslog_settings) = &settings.syslog {
+        send_to_syslog(syslog_settings, success, &message);
+    }
+
+    RunResult {
+        message: Some(message),
+        dedupe_key: Some(dedupe_key),
+        ..result
+    }
+}
+
+trait ScriptRunner: Send + Sync {
+    fn run(&self, settings: &Settings, steps: &[ScriptStep], context: &RunContext) -> RunResult;
+}
+
+struct ProcessRunner;
+
+impl ScriptRunner for ProcessRunner {
+    fn run(&self, settings: &Settings, steps: &[ScriptStep], context: &RunContext) -> RunResult {
+        process_sheet(settings, steps, context)
+    }
+}
+
+enum Transition<'a> {
+    Opened {
+        file: &'a str,
+    },
+    Modified {
+        file: &'a str,
+        kind: &'a str,
+    },
+    Closed {
+        file: &'a str,
+    },
+    Triggered {
+        file: Option<&'a Path>,
+        correlation_id: Option<&'a str>,
+    },
+    Succeeded {
+        message: &'a str,
+        dedupe_key: &'a str,
+    },
+    Failed {
+        message: &'a str,
+        dedupe_key: &'a str,
+    },
+}
+
+trait DetectionObserver: Send + Sync {
+    fn observe(&self, source: &str, transition: &Transition);
+}
+
+fn observe_result(observer: &dyn DetectionObserver, source: &str, result: &RunResult) {
+    let Some(message) = result.message.as_deref() else {
+        return;
+    };
+    let dedupe_key = result.dedupe_key.as_deref().unwrap_or(message);
+
+    observer.observe(
+        source,
+        &if result.success {
+            Transition::Succeeded {
+                message,
+                dedupe_key,
+            }
+        } else {
+            Transition::Failed {
+                message,
+                dedupe_key,
+            }
+        },
+    );
+}
+
+struct LogObserver;
+
+impl DetectionObserver for LogObserver {
+    fn observe(&self, _: &str, transition: &Transition) {
+        match *transition {
+            Transition::Opened { file } => log_event(
+                LogLevel::Debug,
+                "opened",
+                &format!("{} opened", file),
+                json!({ "file": file }),
+            ),
+            Transition::Modified { file, kind } => log_event(
+                LogLevel::Debug,
+                "modified",
+                &format!("{} modified ({})", file, kind),
+                json!({ "file": file, "kind": kind }),
+            ),
+            Transition::Closed { file } => log_event(
+                LogLevel::Debug,
+                "closed",
+                &format!("{} closed", file),
+                json!({ "file": file }),
+            ),
+            Transition::Triggered {
+                file,
+                correlation_id,
+            } => {
+                if let Some(correlation_id) = correlation_id {
+                    print_info(&format!("Triggered run {}", correlation_id));
+                }
+
+                if let Some(file) = file {
+                    print_debug(&format!("Processing {}", file.display()));
+                }
+            }
+            Transition::Succeeded {
+                message,
+                dedupe_key,
+            }
+            | Transition::Failed {
+                message,
+                dedupe_key,
+            } => {
+                show_deduped_notification(message, dedupe_key);
+            }
+        }
+    }
+}
+
+enum TargetKind<'a> {
+    Directory(FileQuery<'a>),
+    File { path: PathBuf, hidden_path: PathBuf },
+}
+
+struct ScriptRun {
+    cancel: Arc<AtomicBool>,
+    handle: JoinHandle<RunResult>,
+    content_hash: Option<u64>,
+}
+
+struct PendingClose {
+    event: Event,
+    stage: CloseStage,
+}
+
+enum CloseStage {
+    Debounce(Instant),
+    Delay(Instant),
+    FileCount {
+        count: usize,
+        stable_since: Instant,
+    },
+    Stability {
+        path: PathBuf,
+        snapshot: Option<(u64, Option<SystemTime>)>,
+        stable_since: Instant,
+        started_at: Instant,
+    },
+}
+
+#[derive(Default)]
+struct DetectionState {
+    is_expected_hidden_file_opened: bool,
+    is_expected_file_modified: bool,
+    is_file_locked: bool,
+    is_processing_deferred: bool,
+    is_rerun_requested: bool,
+    cur_expected_hidden_filename: String,
+    last_processed_hash: Option<u64>,
+    swept_hash: Option<u64>,
+    is_run_queued: bool,
+    queued_hash: Option<u64>,
+    last_modified_at: Option<Instant>,
+    handle_checked_at: Option<Instant>,
+    last_succeeded_at: Option<Instant>,
+    recent_failures: Vec<Instant>,
+    cooldown_until: Option<Instant>,
+    opened_snapshot: Option<(PathBuf, u64)>,
+    latest_file: LatestFileCache,
+    grace_until: Option<Instant>,
+    batch_deadline: Option<Instant>,
+    pending_close: Option<PendingClose>,
+    triggered_file: Option<PathBuf>,
+    running: Option<ScriptRun>,
+}
+
+impl DetectionState {
+    fn reset_detection(&mut self) {
+        self.is_expected_hidden_file_opened = false;
+        self.is_expected_file_modified = false;
+        self.is_file_locked = false;
+        self.is_processing_deferred = false;
+        self.cur_expected_hidden_filename = "".to_string();
+        self.last_modified_at = None;
+        self.opened_snapshot = None;
+        self.batch_deadline = None;
+        self.pending_close = None;
+        self.triggered_file = None;
+        self.latest_file.invalidate();
+    }
+
+    fn is_in_grace_period(&self) -> bool {
+        self.grace_until
+            .is_some_and(|grace_until| Instant::now() < grace_until)
+    }
+
+    fn settle_modifications(&mut self, debounce: Duration) {
+        if self
+            .last_modified_at
+            .is_some_and(|modified_at| modified_at.elapsed() >= debounce)
+        {
+            self.last_modified_at = None;
+            self.is_expected_file_modified = true;
+        }
+    }
+}
+
+struct WatchTarget<'a> {
+    kind: TargetKind<'a>,
+    family: Option<&'a str>,
+    steps: Vec<ScriptStep>,
+    has_own_steps: bool,
+    use_hidden_file: bool,
+    observer: Arc<dyn DetectionObserver>,
+    active_runs: Arc<AtomicUsize>,
+    state: DetectionState,
+}
+
+impl WatchTarget<'_> {
+    fn expected_path(&self, matcher: &dyn VersionMatcher, is_hidden_file: bool) -> Option<PathBuf> {
+        let is_hidden_file = is_hidden_file && self.use_hidden_file;
+
+        match &self.kind {
+            TargetKind::Directory(query) => match &self.state.triggered_file {
+                Some(path) if is_hidden_file => {
+                    expected_hidden_path(path, query.filename_prefix, &query.hidden_filename_prefix)
+                }
+                Some(path) => Some(path.clone()),
+                None => self.state.latest_file.get(query, matcher, is_hidden_file),
+            },
+            TargetKind::File { path, hidden_path } => Some(if is_hidden_file {
+                hidden_path.clone()
+            } else {
+                path.clone()
+            }),
+        }
+    }
+
+    fn versioned_file_of_hidden(
+        &self,
+        event: &Event,
+        matcher: &dyn VersionMatcher,
+    ) -> Option<PathBuf> {
+        let TargetKind::Directory(query) = &self.kind else {
+            return None;
+        };
+        let candidates = get_versioned_files(query, matcher)?;
+
+        event.paths.iter().find_map(|path| {
+            let visible = if self.use_hidden_file {
+                let filename = path.file_name()?.to_str()?;
+                let rest = filename.strip_prefix(&query.hidden_filename_prefix)?;
+
+                path.with_file_name(format!("{}{}", query.filename_prefix, rest))
+            } else {
+                path.clone()
+            };
+
+            candidates
+                .iter()
+                .any(|(_, candidate)| *candidate == visible)
+                .then_some(visible)
+        })
+    }
+
+    fn existing_lock_file(&self, matcher: &dyn VersionMatcher) -> Option<PathBuf> {
+        self.expected_path(matcher, true)
+            .filter(|hidden_path| self.use_hidden_file && hidden_path.exists())
+    }
+
+    fn steps_for(&self, settings: &Settings, path: Option<&Path>) -> Option<Vec<ScriptStep>> {
+        if settings.ext_scripts.is_empty() || self.has_own_steps {
+            return Some(self.steps.clone());
+        }
+
+        settings.ext_script(path?).map(|filename| {
+            vec![ScriptStep {
+                filename: filename.to_string(),
+                env_name: None,
+                args: Vec::new(),
+                env: HashMap::new(),
+            }]
+        })
+    }
+
+    fn matches(&self, event: &Event, matcher: &dyn VersionMatcher, is_hidden_file: bool) -> bool {
+        self.expected_path(matcher, is_hidden_file)
+            .is_some_and(|expected| event.paths.iter().any(|path| path == &expected))
+    }
+
+    fn watch_path(&self, settings: &Settings) -> (PathBuf, RecursiveMode) {
+        match &self.kind {
+            TargetKind::Directory(query) => (
+                PathBuf::from(query.folder_path),
+                if settings.recursive && settings.max_depth.is_none() {
+                    RecursiveMode::Recursive
+                } else {
+                    RecursiveMode::NonRecursive
+                },
+            ),
+            TargetKind::File { path, .. } => (
+                path.parent()
+                    .filter(|parent| !parent.as_os_str().is_empty())
+                    .unwrap_or(Path::new("."))
+                    .to_path_buf(),
+                RecursiveMode::NonRecursive,
+            ),
+        }
+    }
+
+    fn resolved_file(&self, matcher: &dyn VersionMatcher) -> Option<(PathBuf, Option<usize>)> {
+        Some(self.resolve_file(matcher, self.expected_path(matcher, false)?))
+    }
+
+    fn resolve_file(
+        &self,
+        matcher: &dyn VersionMatcher,
+        path: PathBuf,
+    ) -> (PathBuf, Option<usize>) {
+        let version = match &self.kind {
+            TargetKind::Directory(query) => path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|stem| matcher.version_of(stem, query.filename_prefix)),
+            TargetKind::File { .. } => None,
+        };
+
+        (path, version)
+    }
+
+    fn run_context(
+        &self,
+        settings: &Settings,
+        matcher: &dyn VersionMatcher,
+        cancel: Arc<AtomicBool>,
+    ) -> RunContext {
+        RunContext {
+            file: self.resolved_file(matcher),
+            cancel,
+            correlation_id: settings
+                .include_correlation_id
+                .then(generate_correlation_id),
+            source: self.source(),
+            filename_prefix: match &self.kind {
+                TargetKind::Directory(query) => Some(query.filename_prefix.to_string()),
+                TargetKind::File { .. } => None,
+            },
+        }
+    }
+
+    fn observe(&self, transition: &Transition) {
+        self.observer.observe(&self.source(), transition);
+    }
+
+    fn source(&self) -> String {
+        match &self.kind {
+            TargetKind::Directory(query) => match self.family {
+                Some(family) => format!("{} ({})", query.folder_path, family),
+                None => query.folder_path.to_string(),
+            },
+            TargetKind::File { path, .. } => path.to_string_lossy().to_string(),
+        }
+    }
+}
+
+fn build_watch_targets(path_config: &PathConfig) -> Vec<WatchTarget<'_>> {
+    let settings = &path_config.settings;
+    let active_runs = Arc::new(AtomicUsize::new(0));
+
+    if path_config.file.is_empty() {
+        let is_family_shown = settings.filename_prefix.values().len() > 1;
+
+        let mut queries = settings.file_queries();
+
+        if settings.trigger_mode == TriggerMode::File {
+            let mut folder_paths = Vec::new();
+
+            queries.retain(|query| {
+                let is_new = !folder_paths.contains(&query.folder_path);
+
+                folder_paths.push(query.folder_path);
+                is_new
+            });
+        }
+
+        return queries
+            .into_iter()
+            .map(|query| WatchTarget {
+                family: is_family_shown.then_some(query.filename_prefix),
+                kind: TargetKind::Directory(query),
+                steps: settings.script_steps(),
+                has_own_steps: false,
+                use_hidden_file: settings.use_hidden_file,
+                observer: Arc::new(LogObserver),
+                active_runs: Arc::clone(&active_runs),
+                state: DetectionState::default(),
+            })
+            .collect();
+    }
+
+    path_config
+        .file
+        .iter()
+        .filter_map(|entry| {
+            Some(WatchTarget {
+                family: None,
+                kind: TargetKind::File {
+                    path: PathBuf::from(&entry.path),
+                    hidden_path: entry.hidden_path(settings)?,
+                },
+                steps: if entry.scripts.is_empty() {
+                    settings.script_steps()
+                } else {
+                    entry.scripts.clone()
+                },
+                has_own_steps: !entry.scripts.is_empty(),
+                use_hidden_file: settings.use_hidden_file,
+                observer: Arc::new(LogObserver),
+                active_runs: Arc::clone(&active_runs),
+                state: DetectionState::default(),
+            })
+        })
+        .collect()
+}
+
+struct RunSlot {
+    active_runs: Arc<AtomicUsize>,
+}
+
+impl RunSlot {
+    fn take(active_runs: &Arc<AtomicUsize>) -> RunSlot {
+        active_runs.fetch_add(1, Ordering::SeqCst);
+        RunSlot {
+            active_runs: Arc::clone(active_runs),
+        }
+    }
+}
+
+impl Drop for RunSlot {
+    fn drop(&mut self) {
+        self.active_runs.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn has_free_run_slot(target: &WatchTarget, settings: &Settings) -> bool {
+    settings.max_concurrent_runs == 0
+        || target.active_runs.load(Ordering::SeqCst) < settings.max_concurrent_runs
+}
+
+fn start_run(
+    target: &mut WatchTarget,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    content_hash: Option<u64>,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    let config = Arc::clone(path_config);
+    let runner = Arc::clone(runner);
+    let observer = Arc::clone(&target.observer);
+    let source = target.source();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let context = target.run_context(&path_config.settings, matcher, Arc::clone(&cancel));
+    let file = context.file.as_ref().map(|(path, _)| path.as_path());
+    let Some(steps) = target.steps_for(&path_config.settings, file) else {
+        print_info(&format!(
+            "No script is mapped to the extension of {}, run skipped",
+            file.unwrap_or(Path::new("")).display()
+        ));
+        return;
+    };
+
+    target.observe(&Transition::Triggered {
+        file: context.file.as_ref().map(|(path, _)| path.as_path()),
+        correlation_id: context.correlation_id.as_deref(),
+    });
+
+    if path_config.settings.sweep_interval_secs > 0 {
+        target.state.swept_hash = content_hash.or_else(|| {
+            target
+                .expected_path(matcher, false)
+                .and_then(|path| hash_file_content(&path))
+        });
+    }
+
+    let slot = RunSlot::take(&target.active_runs);
+
+    target.state.is_processing_deferred = false;
+    target.state.triggered_file = None;
+    target.state.running = Some(ScriptRun {
+        cancel,
+        handle: spawn(move || {
+            let _slot = slot;
+
+            set_correlation_id(context.correlation_id.clone());
+
+            let result = runner.run(&config.settings, &steps, &context);
+
+            // Reported here so that a slow toast never holds up the event loop.
+            observe_result(observer.as_ref(), &source, &result);
+            result
+        }),
+        content_hash,
+    });
+}
+
+fn start_grace_period(
+    target: &mut WatchTarget,
+    settings: &Settings,
+    matcher: &dyn VersionMatcher,
+    grace_until: Instant,
+) {
+    target.state.grace_until = Some(grace_until);
+
+    let Some(hidden_path) = target.existing_lock_file(matcher) else {
+        return;
+    };
+
+    target.state.cur_expected_hidden_filename = hidden_path
+        .file_name()
+        .map(|filename| filename.to_string_lossy().to_string())
+        .unwrap_or_default();
+    target.state.is_expected_hidden_file_opened = true;
+    target.state.opened_snapshot = if settings.skip_unchanged {
+        snapshot_file(target.expected_path(matcher, false))
+    } else {
+        None
+    };
+    print_info(&format!(
+        "{} is already open, waiting for it to close",
+        target.state.cur_expected_hidden_filename
+    ));
+}
+
+fn sweep_target(
+    target: &mut WatchTarget,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    if target.state.running.is_some()
+        || target.state.is_expected_hidden_file_opened
+        || path_config.settings.trigger_mode == TriggerMode::File
+    {
+        return;
+    }
+
+    if !target.state.is_processing_deferred && !target.state.is_run_queued {
+        target.state.triggered_file = None;
+    }
+
+    target.state.latest_file.invalidate();
+
+    let Some(path) = target.expected_path(matcher, false) else {
+        return;
+    };
+
+    if target.existing_lock_file(matcher).is_some() {
+        return;
+    }
+
+    let Some(content_hash) = hash_file_content(&path) else {
+        return;
+    };
+
+    if target.state.is_in_grace_period() {
+        target.state.swept_hash = Some(content_hash);
+        return;
+    }
+
+    if target
+        .state
+        .swept_hash
+        .replace(content_hash)
+        .is_some_and(|swept_hash| swept_hash != content_hash)
+    {
+        print_info(&format!(
+            "{} changed without a close event, catching up",
+            path.display()
+        ));
+
+        if !is_processing_held_back(target, &path_config.settings, matcher) {
+            run_target(target, path_config, matcher, Some(content_hash), runner);
+        }
+    }
+}
+
+fn can_start_run(
+    target: &mut WatchTarget,
+    settings: &Settings,
+    matcher: &dyn VersionMatcher,
+) -> bool {
+    if IS_SHUTTING_DOWN.load(Ordering::SeqCst) {
+        print_info("Shutting down, trigger ignored");
+        return false;
+    }
+
+    if let Some(cooldown_until) = target.state.cooldown_until {
+        if Instant::now() < cooldown_until {
+            print_info("Cooling down after repeated failures, trigger ignored");
+            return false;
+        }
+
+        print_info("Cooldown over, processing resumed");
+        target.state.cooldown_until = None;
+    }
+
+    let path = target.expected_path(matcher, false);
+
+    if target.steps_for(settings, path.as_deref()).is_none() {
+        print_info(&format!(
+            "No script is mapped to the extension of {}, trigger ignored",
+            path.unwrap_or_default().display()
+        ));
+        return false;
+    }
+
+    if let Some((path, Some(version))) = path.map(|path| target.resolve_file(matcher, path)) {
+        if let Ok(Some(min_version)) = min_version_index(settings, matcher) {
+            if version < min_version {
+                print_info(&format!(
+                    "{} is below min_version {}, processing skipped",
+                    path.display(),
+                    settings.min_version.as_deref().unwrap_or_default()
+                ));
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+fn run_target(
+    target: &mut WatchTarget,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    content_hash: Option<u64>,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    if !can_start_run(target, &path_config.settings, matcher) {
+        return;
+    }
+
+    if let Some(run) = &target.state.running {
+        match path_config.settings.on_overlap {
+            OverlapBehavior::Ignore => {
+                print_info("Script is still running, trigger ignored");
+            }
+            OverlapBehavior::Requeue => {
+                print_info("Script is still running, queued another run");
+                target.state.is_rerun_requested = true;
+                target.state.queued_hash = content_hash;
+            }
+            OverlapBehavior::Restart => {
+                print_info("Script is still running, restarting it");
+                run.cancel.store(true, Ordering::SeqCst);
+                target.state.is_rerun_requested = true;
+                target.state.queued_hash = content_hash;
+            }
+        }
+
+        return;
+    }
+
+    if !has_free_run_slot(target, &path_config.settings) {
+        print_info(&format!(
+            "{} runs already active, run queued",
+            target.active_runs.load(Ordering::SeqCst)
+        ));
+        target.state.is_run_queued = true;
+        target.state.queued_hash = content_hash;
+        return;
+    }
+
+    start_run(target, path_config, matcher, content_hash, runner);
+}
+
+fn start_queued_run(
+    target: &mut WatchTarget,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    if !target.state.is_run_queued
+        || target.state.running.is_some()
+        || IS_SHUTTING_DOWN.load(Ordering::SeqCst)
+        || !has_free_run_slot(target, &path_config.settings)
+    {
+        return;
+    }
+
+    target.state.is_run_queued = false;
+
+    if can_start_run(target, &path_config.settings, matcher)
+        && !is_processing_held_back(target, &path_config.settings, matcher)
+    {
+        let content_hash = target.state.queued_hash.take();
+
+        start_run(target, path_config, matcher, content_hash, runner);
+    }
+}
+
+fn poll_run(
+    target: &mut WatchTarget,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+) -> Option<RunResult> {
+    if !target
+        .state
+        .running
+        .as_ref()
+        .is_some_and(|run| run.handle.is_finished())
+    {
+        return None;
+    }
+
+    let run = target.state.running.take()?;
+    let result = run.handle.join().unwrap_or_default();
+    let success = result.success;
+
+    if success {
+        target.state.last_succeeded_at = Some(Instant::now());
+        target.state.recent_failures.clear();
+    } else if result.has_run && !run.cancel.load(Ordering::SeqCst) {
+        record_failure(target, &path_config.settings);
+    }
+
+    if path_config.settings.sweep_interval_secs > 0 {
+        // Scripts that write back into the workbook must not catch up on their own output.
+        target.state.swept_hash = target
+            .expected_path(matcher, false)
+            .and_then(|path| hash_file_content(&path));
+    }
+
+    if success && path_config.settings.dedupe_by_content_hash {
+        target.state.last_processed_hash = run.content_hash.or_else(|| {
+            target
+                .expected_path(matcher, false)
+                .and_then(|path| hash_file_content(&path))
+        });
+    }
+
+    if target.state.is_rerun_requested {
+        target.state.is_rerun_requested = false;
+        target.state.is_run_queued = true;
+    }
+
+    Some(result)
+}
+
+fn record_failure(target: &mut WatchTarget, settings: &Settings) {
+    if settings.failure_threshold == 0 {
+        return;
+    }
+
+    let window = Duration::from_secs(settings.failure_window_secs);
+    let failures = &mut target.state.recent_failures;
+
+    failures.retain(|failed_at| failed_at.elapsed() < window);
+    failures.push(Instant::now());
+
+    if failures.len() < settings.failure_threshold {
+        return;
+    }
+
+    failures.clear();
+    target.state.cooldown_until =
+        Some(Instant::now() + Duration::from_secs(settings.failure_cooldown_secs));
+
+    let message = format!(
+        "{}: Paused for {}s due to repeated failures",
+        target.source(),
+        settings.failure_cooldown_secs
+    );
+
+    print_error(&message);
+    show_notification(&message);
+}
+
+fn split_rename_event(event: &Event, rename_mode: RenameMode) -> Vec<Event> {
+    let renamed_to = |path: &PathBuf| {
+        [
+            Event::new(EventKind::Create(CreateKind::File)).add_path(path.clone()),
+            Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any))).add_path(path.clone()),
+        ]
+    };
+    let renamed_from =
+        |path: &PathBuf| Event::new(EventKind::Remove(RemoveKind::File)).add_path(path.clone());
+
+    match (rename_mode, event.paths.as_slice()) {
+        (RenameMode::Both, [from, to]) => {
+            let mut events = renamed_to(to).to_vec();
+            events.push(renamed_from(from));
+            events
+        }
+        (RenameMode::To, [to]) => renamed_to(to).to_vec(),
+        (RenameMode::From, [from]) => vec![renamed_from(from)],
+        (_, [path]) if path.exists() => renamed_to(path).to_vec(),
+        (_, [path]) => vec![renamed_from(path)],
+        _ => Vec::new(),
+    }
+}
+
+fn handle_event(
+    target: &mut WatchTarget,
+    event: &Event,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    let settings = &path_config.settings;
+
+    if let EventKind::Modify(ModifyKind::Name(rename_mode)) = event.kind {
+        for renamed in split_rename_event(event, rename_mode) {
+            handle_event(target, &renamed, path_config, matcher, runner);
+        }
+
+        return;
+    }
+
+    // Reading the workbook, for example to hash it, closes it without writing.
+    if event.kind == EventKind::Access(AccessKind::Close(AccessMode::Read))
+        && target.matches(event, matcher, false)
+    {
+        return;
+    }
+
+    if let EventKind::Create(_) | EventKind::Remove(_) = event.kind {
+        target.state.latest_file.invalidate();
+    }
+
+    if settings.trigger_mode == TriggerMode::File {
+        handle_trigger_file(target, event, path_config, matcher, runner);
+        return;
+    }
+
+    if let (EventKind::Create(_), TargetKind::Directory(query)) = (&event.kind, &target.kind) {
+        warn_unrecognized_versions(&event.paths, query, matcher);
+        warn_non_utf8_names(&event.paths, query);
+    }
+
+    if settings.batch_window_secs > 0 {
+        if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            && target.matches(event, matcher, false)
+        {
+            print_debug(&format!(
+                "{} changed, batch window restarted",
+                get_filename_from_event(event).unwrap_or("".to_string())
+            ));
+            target.state.batch_deadline =
+                Some(Instant::now() + Duration::from_secs(settings.batch_window_secs));
+        }
+
+        return;
+    }
+
+    let phases = &settings.event_phases;
+
+    if settings.trigger_target == TriggerTarget::Event
+        && phases.is_open(&event.kind)
+        && !target.state.is_expected_hidden_file_opened
+    {
+        target.state.triggered_file = target.versioned_file_of_hidden(event, matcher);
+    }
+
+    let is_close =
+        settings.close_detection == CloseDetection::Event && phases.is_close(&event.kind);
+
+    if is_close
+        && target.state.is_expected_hidden_file_opened
+        && is_same_file(event, &target.state.cur_expected_hidden_filename)
+    {
+        handle_close(target, event, path_config, matcher, runner);
+    } else if is_close
+        && phases.is_modify(&event.kind)
+        && target.state.is_expected_hidden_file_opened
+        && target.matches(event, matcher, false)
+        && {
+            target
+                .state
+                .settle_modifications(Duration::from_millis(settings.debounce_ms));
+            target.state.is_expected_file_modified
+        }
+    {
+        print_debug(&format!(
+            "{} modified again, treated as closed",
+            get_filename_from_event(event).unwrap_or("".to_string())
+        ));
+
+        if let Some(hidden_path) = target.expected_path(matcher, true) {
+            handle_close(
+                target,
+                &Event::new(event.kind).add_path(hidden_path),
+                path_config,
+                matcher,
+                runner,
+            );
+        }
+    } else if phases.is_open(&event.kind) && target.matches(event, matcher, true) {
+        target.state.cur_expected_hidden_filename =
+            get_filename_from_event(event).unwrap_or("".to_string());
+        target.state.is_expected_hidden_file_opened = true;
+        target.state.is_expected_file_modified = false;
+        target.state.last_modified_at = None;
+        target.state.opened_snapshot = if settings.skip_unchanged {
+            snapshot_file(target.expected_path(matcher, false))
+        } else {
+            None
+        };
+        target.observe(&Transition::Opened {
+            file: &target.state.cur_expected_hidden_filename,
+        });
+    } else if phases.is_modify(&event.kind) && target.matches(event, matcher, false) {
+        if target.state.is_in_grace_period() {
+            print_debug(&format!(
+                "{} modified during the startup grace period, ignored",
+                get_filename_from_event(event).unwrap_or("".to_string())
+            ));
+            return;
+        }
+
+        let filename = get_filename_from_event(event).unwrap_or("".to_string());
+        let kind = describe_event_kind(&event.kind);
+
+        target.state.last_modified_at = Some(Instant::now());
+        target.observe(&Transition::Modified {
+            file: &filename,
+            kind: &kind,
+        });
+    }
+}
+
+fn handle_trigger_file(
+    target: &mut WatchTarget,
+    event: &Event,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    let settings = &path_config.settings;
+    let pattern = settings.trigger_file.as_deref().unwrap_or_default();
+    let (watch_path, _) = target.watch_path(settings);
+
+    if !matches!(event.kind, EventKind::Create(_)) {
+        return;
+    }
+
+    let Some(trigger_path) = event
+        .paths
+        .iter()
+        .find(|path| path.starts_with(&watch_path) && matches_filename_pattern(path, pattern))
+    else {
+        return;
+    };
+
+    if target.state.is_in_grace_period() {
+        print_info(&format!(
+            "{} created during the startup grace period, ignored",
+            trigger_path.display()
+        ));
+        return;
+    }
+
+    print_info(&format!(
+        "{} created, running scripts",
+        trigger_path.display()
+    ));
+
+    if let TargetKind::Directory(_) = target.kind {
+        target.state.triggered_file = Some(trigger_path.clone());
+    }
+
+    if !is_processing_held_back(target, settings, matcher) {
+        run_target(target, path_config, matcher, None, runner);
+    }
+}
+
+fn process_events(
+    targets: &mut [WatchTarget],
+    events: impl IntoIterator<Item = NotifyResult<Event>>,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    runner: &Arc<dyn ScriptRunner>,
+) -> bool {
+    let mut is_relevant = false;
+
+    for res in events {
+        let event = match res {
+            Ok(event) => event,
+            Err(e) => {
+                print_error(&format!("Error occurred in watcher: {:?}", e));
+                continue;
+            }
+        };
+
+        for target in targets.iter_mut() {
+            handle_event(target, &event, path_config, matcher, runner);
+        }
+
+        is_relevant |= targets.iter().any(|target| {
+            target.matches(&event, matcher, false) || target.matches(&event, matcher, true)
+        });
+    }
+
+    is_relevant
+}
+
+fn handle_close(
+    target: &mut WatchTarget,
+    event: &Event,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    let settings = &path_config.settings;
+
+    if !(target.state.is_expected_hidden_file_opened
+        && is_same_file(event, &target.state.cur_expected_hidden_filename))
+    {
+        return;
+    }
+
+    if target.state.is_in_grace_period() {
+        print_info(&format!(
+            "{} closed during the startup grace period, ignored",
+            target.state.cur_expected_hidden_filename
+        ));
+        target.state.reset_detection();
+        return;
+    }
+
+    let debounce = Duration::from_millis(settings.debounce_ms);
+
+    if let Some(modified_at) = target
+        .state
+        .last_modified_at
+        .filter(|modified_at| modified_at.elapsed() < debounce)
+    {
+        target.state.pending_close = Some(PendingClose {
+            event: event.clone(),
+            stage: CloseStage::Debounce(modified_at + debounce),
+        });
+        return;
+    }
+
+    settle_close(target, event, path_config, matcher, runner);
+}
+
+fn settle_close(
+    target: &mut WatchTarget,
+    event: &Event,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    let settings = &path_config.settings;
+
+    target
+        .state
+        .settle_modifications(Duration::from_millis(settings.debounce_ms));
+
+    if !target.state.is_expected_file_modified {
+        return;
+    }
+
+    let closed_filename = std::mem::take(&mut target.state.cur_expected_hidden_filename);
+
+    target.state.is_expected_hidden_file_opened = false;
+    target.state.is_expected_file_modified = false;
+    target.observe(&Transition::Closed {
+        file: &closed_filename,
+    });
+
+    let min_interval = Duration::from_secs(settings.min_interval_secs);
+
+    if let Some(succeeded_at) = target
+        .state
+        .last_succeeded_at
+        .filter(|succeeded_at| succeeded_at.elapsed() < min_interval)
+    {
+        print_info(&format!(
+            "Last run succeeded {:?} ago, within min_interval_secs, trigger ignored",
+            succeeded_at.elapsed()
+        ));
+        return;
+    }
+
+    if settings.post_close_delay_ms > 0 {
+        print_info(&format!(
+            "Waiting {}ms after close before processing",
+            settings.post_close_delay_ms
+        ));
+        target.state.pending_close = Some(PendingClose {
+            event: event.clone(),
+            stage: CloseStage::Delay(
+                Instant::now() + Duration::from_millis(settings.post_close_delay_ms),
+            ),
+        });
+        return;
+    }
+
+    start_close_checks(target, event, path_config, matcher, runner);
+}
+
+fn versioned_file_count(target: &WatchTarget, matcher: &dyn VersionMatcher) -> usize {
+    match &target.kind {
+        TargetKind::Directory(query) => get_versioned_files(query, matcher)
+            .map(|files| files.len())
+            .unwrap_or(0),
+        TargetKind::File { .. } => 0,
+    }
+}
+
+fn start_close_checks(
+    target: &mut WatchTarget,
+    event: &Event,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    if matches!(target.kind, TargetKind::Directory(_))
+        && path_config.settings.stable_file_count_ms > 0
+    {
+        target.state.pending_close = Some(PendingClose {
+            event: event.clone(),
+            stage: CloseStage::FileCount {
+                count: versioned_file_count(target, matcher),
+                stable_since: Instant::now(),
+            },
+        });
+        return;
+    }
+
+    start_stability_check(target, event, path_config, matcher, runner);
+}
+
+fn start_stability_check(
+    target: &mut WatchTarget,
+    event: &Event,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    if path_config.settings.stability_ms > 0 {
+        if let Some(path) = target.expected_path(matcher, false) {
+            target.state.pending_close = Some(PendingClose {
+                event: event.clone(),
+                stage: CloseStage::Stability {
+                    snapshot: size_and_mtime(&path),
+                    path,
+                    stable_since: Instant::now(),
+                    started_at: Instant::now(),
+                },
+            });
+            return;
+        }
+    }
+
+    process_close(target, path_config, matcher, runner);
+}
+
+fn flush_pending_close(
+    target: &mut WatchTarget,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    let settings = &path_config.settings;
+    let Some(PendingClose { event, stage }) = target.state.pending_close.take() else {
+        return;
+    };
+
+    match stage {
+        CloseStage::Debounce(deadline) if Instant::now() >= deadline => {
+            settle_close(target, &event, path_config, matcher, runner);
+        }
+        CloseStage::Delay(deadline) if Instant::now() >= deadline => {
+            start_close_checks(target, &event, path_config, matcher, runner);
+        }
+        CloseStage::FileCount {
+            mut count,
+            mut stable_since,
+        } => {
+            let cur_count = versioned_file_count(target, matcher);
+
+            if cur_count != count {
+                print_debug(&format!(
+                    "File count changed from {} to {}, waiting for it to settle",
+                    count, cur_count
+                ));
+                count = cur_count;
+                stable_since = Instant::now();
+            }
+
+            if stable_since.elapsed() < Duration::from_millis(settings.stable_file_count_ms) {
+                target.state.pending_close = Some(PendingClose {
+                    event,
+                    stage: CloseStage::FileCount {
+                        count,
+                        stable_since,
+                    },
+                });
+            } else if !target.matches(&event, matcher, true) {
+                print_info("Latest file changed while settling, skipped");
+            } else {
+                start_stability_check(target, &event, path_config, matcher, runner);
+            }
+        }
+        CloseStage::Stability {
+            path,
+            mut snapshot,
+            mut stable_since,
+            started_at,
+        } => {
+            let cur_snapshot = size_and_mtime(&path);
+
+            if cur_snapshot != snapshot {
+                snapshot = cur_snapshot;
+                stable_since = Instant::now();
+            }
+
+            if stable_since.elapsed() >= Duration::from_millis(settings.stability_ms) {
+                process_close(target, path_config, matcher, runner);
+            } else if started_at.elapsed() >= Duration::from_millis(settings.stability_timeout_ms) {
+                let message = format!(
+                    "{} is still being written, processing skipped",
+                    path.display()
+                );
+
+                print_info(&message);
+                show_notification(&message);
+            } else {
+                target.state.pending_close = Some(PendingClose {
+                    event,
+                    stage: CloseStage::Stability {
+                        path,
+                        snapshot,
+                        stable_since,
+                        started_at,
+                    },
+                });
+            }
+        }
+        stage => target.state.pending_close = Some(PendingClose { event, stage }),
+    }
+}
+
+fn process_close(
+    target: &mut WatchTarget,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    let settings = &path_config.settings;
+
+    if let Some(path) = target.expected_path(matcher, false) {
+        if !is_within_size_limit(settings, &path) {
+            return;
+        }
+    }
+
+    let snapshot = if settings.dedupe_by_content_hash || settings.skip_unchanged {
+        snapshot_file(target.expected_path(matcher, false))
+    } else {
+        None
+    };
+    let opened_snapshot = target.state.opened_snapshot.take();
+
+    if let Some((path, _)) = snapshot
+        .as_ref()
+        .filter(|snapshot| opened_snapshot.as_ref() == Some(*snapshot))
+    {
+        let message = format!(
+            "{}: No changes detected, processing skipped",
+            path.display()
+        );
+
+        print_info(&message);
+        show_notification(&message);
+        return;
+    }
+
+    let content_hash = snapshot
+        .filter(|_| settings.dedupe_by_content_hash)
+        .map(|(_, hash)| hash);
+
+    if content_hash.is_some() && content_hash == target.state.last_processed_hash {
+        print_info("Content already processed, skipped");
+        return;
+    }
+
+    if is_processing_held_back(target, settings, matcher) {
+        return;
+    }
+
+    run_target(target, path_config, matcher, content_hash, runner);
+}
+
+fn flush_batch(
+    target: &mut WatchTarget,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    if target
+        .state
+        .batch_deadline
+        .is_none_or(|deadline| Instant::now() < deadline)
+    {
+        return;
+    }
+
+    target.state.latest_file.invalidate();
+
+    if target.existing_lock_file(matcher).is_some() {
+        return;
+    }
+
+    target.state.batch_deadline = None;
+
+    let Some(path) = target.expected_path(matcher, false) else {
+        return;
+    };
+
+    print_info(&format!(
+        "No changes for {}s, processing {}",
+        path_config.settings.batch_window_secs,
+        path.display()
+    ));
+
+    let content_hash = path_config
+        .settings
+        .dedupe_by_content_hash
+        .then(|| hash_file_content(&path))
+        .flatten();
+
+    if content_hash.is_some() && content_hash == target.state.last_processed_hash {
+        print_info("Content already processed, skipped");
+        return;
+    }
+
+    if !is_processing_held_back(target, &path_config.settings, matcher) {
+        run_target(target, path_config, matcher, content_hash, runner);
+    }
+}
+
+enum HoldBack {
+    OnBattery,
+    OutsideActiveHours(OutsideHours),
+}
+
+fn hold_back_reason(settings: &Settings) -> Option<HoldBack> {
+    if settings.skip_on_battery && is_on_battery() {
+        return Some(HoldBack::OnBattery);
+    }
+
+    let (weekday, minute_of_day) = local_time();
+
+    settings
+        .active_hours
+        .as_ref()
+        .filter(|active_hours| !active_hours.contains(weekday, minute_of_day))
+        .map(|active_hours| HoldBack::OutsideActiveHours(active_hours.outside))
+}
+
+fn is_processing_held_back(
+    target: &mut WatchTarget,
+    settings: &Settings,
+    matcher: &dyn VersionMatcher,
+) -> bool {
+    match hold_back_reason(settings) {
+        None => return false,
+        Some(HoldBack::OnBattery) => {
+            target.state.is_processing_deferred = true;
+            print_info("Running on battery, processing deferred");
+        }
+        Some(HoldBack::OutsideActiveHours(OutsideHours::Defer)) => {
+            target.state.is_processing_deferred = true;
+            print_info("Outside active hours, processing deferred");
+        }
+        Some(HoldBack::OutsideActiveHours(OutsideHours::Skip)) => {
+            let message = format!(
+                "{}: Outside active hours, processing skipped",
+                target.source()
+            );
+
+            print_info(&message);
+            show_notification(&message);
+
+            if settings.sweep_interval_secs > 0 {
+                target.state.swept_hash = target
+                    .expected_path(matcher, false)
+                    .and_then(|path| hash_file_content(&path));
+            }
+        }
+    }
+
+    true
+}
+
+fn check_file_handle(
+    target: &mut WatchTarget,
+    path_config: &Arc<PathConfig>,
+    matcher: &dyn VersionMatcher,
+    runner: &Arc<dyn ScriptRunner>,
+) {
+    if !target.state.is_expected_hidden_file_opened {
+        return;
+    }
+
+    if target
+        .state
+        .handle_checked_at
+        .is_some_and(|checked_at| checked_at.elapsed() < TICK_INTERVAL)
+    {
+        return;
+    }
+
+    target.state.handle_checked_at = Some(Instant::now());
+
+    let Some(path) = target.expected_path(matcher, false) else {
+        return;
+    };
+
+    if is_file_locked(&path) {
+        target.state.is_file_locked = true;
+        return;
+    }
+
+    if !std::mem::take(&mut target.state.is_file_locked) {
+        return;
+    }
+
+    print_debug(&format!("{} is no longer locked", path.display()));
+
+    if let Some(hidden_path) = target.expected_path(matcher, true) {
+        let event = Event::new(EventKind::Remove(RemoveKind::File)).add_path(hidden_path);
+
+        handle_close(target, &event, path_config, matcher, runner);
+    }
+}
+
+#[derive(Default)]
+struct WatcherOptions {
+    max_runtime: Option<Duration>,
+    exit_after_first: bool,
+}
+
+fn parse_watcher_options(arguments: &[String]) -> Result<WatcherOptions, Box<dyn Error>> {
+    let mut options = WatcherOptions::default();
+    let mut iter = arguments.iter();
+
+    while let Some(argument) = iter.next() {
+        match argument.as_str() {
+            "--max-runtime-secs" => {
+                let secs = iter
+                    .next()
+                    .ok_or("--max-runtime-secs requires a value")?
+                    .parse::<u64>()?;
+
+                options.max_runtime = Some(Duration::from_secs(secs));
+            }
+            "--exit-after-first" => options.exit_after_first = true,
+            _ => return Err(format!("Unknown option: {}", argument).into()),
+        }
+    }
+
+    Ok(options)
+}
+
+const MANUAL_RUN_INFO: &str = "manual-run";
+
+fn manual_run_event() -> Event {
+    Event::new(EventKind::Other).set_info(MANUAL_RUN_INFO)
+}
+
+fn simulated_event(action: &str, path: &str) -> Option<Event> {
+    let kind = match action {
+        "open" => EventKind::Create(CreateKind::File),
+        "modify" => EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+        "close" => EventKind::Remove(RemoveKind::File),
+        _ => return None,
+    };
+
+    Some(Event::new(kind).add_path(PathBuf::from(path)))
+}
+
+fn handle_simulate_request(
+    stream: TcpStream,
+    tx: &mpsc::Sender<NotifyResult<Event>>,
+) -> std::io::Result<()> {
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+
+    let event = match request.trim_end() {
+        "run" => Some(manual_run_event()),
+        line => line
+            .split_once(' ')
+            .and_then(|(action, path)| simulated_event(action, path)),
+    };
+    let reply = match event {
+        Some(event) => {
+            print_debug(&format!("Simulated event: {}", request.trim_end()));
+
+            match tx.send(Ok(event)) {
+                Ok(()) => "ok".to_string(),
+                Err(err) => format!("error: {}", err),
+            }
+        }
+        None => format!("error: invalid request: {}", request.trim_end()),
+    };
+
+    writeln!(&stream, "{}", reply)
+}
+
+struct SimulateListener {
+    port: u16,
+    is_stopped: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for SimulateListener {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::SeqCst);
+
+        // Wakes the blocking accept so the thread sees the flag and releases the port.
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn start_simulate_listener(
+    port: u16,
+    tx: mpsc::Sender<NotifyResult<Event>>,
+) -> io::Result<SimulateListener> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let is_stopped = Arc::new(AtomicBool::new(false));
+
+    print_info(&format!("Accepting simulated events on 127.0.0.1:{}", port));
+
+    let handle = spawn({
+        let is_stopped = is_stopped.clone();
+
+        move || {
+            for stream in listener.incoming().filter_map(|stream| stream.ok()) {
+                if is_stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                if let Err(err) = handle_simulate_request(stream, &tx) {
+                    print_error(&format!("Simulate request failed: {}", err));
+                }
+            }
+        }
+    });
+
+    Ok(SimulateListener {
+        port,
+        is_stopped,
+        handle: Some(handle),
+    })
+}
+
+fn update_simulate_listener(
+    simulate_listener: &mut Option<SimulateListener>,
+    settings: &Settings,
+    tx: &mpsc::Sender<NotifyResult<Event>>,
+) {
+    if simulate_listener.as_ref().map(|listener| listener.port) == settings.simulate_port {
+        return;
+    }
+
+    if simulate_listener.take().is_some() {
+        print_info("Stopped accepting simulated events");
+    }
+
+    if let Some(port) = settings.simulate_port {
+        match start_simulate_listener(port, tx.clone()) {
+            Ok(listener) => *simulate_listener = Some(listener),
+            Err(e) => {
+                let message = format!("Failed to accept simulated events on port {}: {}", port, e);
+
+                print_error(&message);
+                show_notification(&message);
+            }
+        }
+    }
+}
+
+fn send_simulate_request(settings: &Settings, arguments: &[String]) -> Result<(), Box<dyn Error>> {
+    let port = settings
+        .simulate_port
+        .ok_or("simulate_port is not configured in path.toml")?;
+    let request = match arguments {
+        [flag, ..] if flag == "--run" => "run".to_string(),
+        [flag, file, ..] if matches!(flag.as_str(), "--open" | "--modify" | "--close") => {
+            format!("{} {}", &flag[2..], absolute(file)?.to_string_lossy())
+        }
+        _ => return Err("Usage: simulate --run | --open|--modify|--close <file>".into()),
+    };
+    let stream = TcpStream::connect(("127.0.0.1", port))?;
+
+    writeln!(&stream, "{}", request)?;
+
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)?;
+
+    match reply.trim_end() {
+        "ok" => Ok(()),
+        message => Err(message.to_string().into()),
+    }
+}
+
+enum WatchOutcome {
+    Stop,
+    Reload(Arc<PathConfig>),
+    Rearm(Arc<PathConfig>),
+}
+
+struct CarriedTargets {
+    active_runs: Arc<AtomicUsize>,
+    states: Vec<(String, DetectionState)>,
+}
+
+struct WatchProgress {
+    started_at: Instant,
+    first_result: Option<bool>,
+    is_startup_notified: bool,
+    status: StatusTracker,
+    daily_check: DailyCheck,
+    carried: Option<CarriedTargets>,
+}
+
+#[derive(Default)]
+struct DailyCheck {
+    day: Option<i64>,
+    is_warned: bool,
+}
+
+fn targets_missing_token(
+    targets: &[WatchTarget],
+    matcher: &dyn VersionMatcher,
+    token: &str,
+) -> Vec<String> {
+    let version = matcher.version_of(token, "");
+
+    targets
+        .iter()
+        .filter(|target| match &target.kind {
+            TargetKind::Directory(query) => !get_versioned_files(query, matcher)
+                .unwrap_or_default()
+                .iter()
+                .any(|(cur_version, _)| Some(*cur_version) == version),
+            TargetKind::File { .. } => false,
+        })
+        .map(WatchTarget::source)
+        .collect()
+}
+
+fn check_daily_token(
+    check: &mut DailyCheck,
+    targets: &[WatchTarget],
+    settings: &Settings,
+    matcher: &dyn VersionMatcher,
+) {
+    let (year, month, day) = local_date();
+    let today = days_from_civil(year, month, day);
+    let token = day_ganzhi(today);
+
+    if check.day != Some(today) {
+        check.day = Some(today);
+        check.is_warned = false;
+        print_info(&format!(
+            "Today is {:04}-{:02}-{:02}, expecting files with the token {}",
+            year, month, day, token
+        ));
+    }
+
+    let (_, minute_of_day) = local_time();
+
+    if check.is_warned || u64::from(minute_of_day) * 60 < settings.daily_reset_grace_secs {
+        return;
+    }
+
+    check.is_warned = true;
+
+    for source in targets_missing_token(targets, matcher, &token) {
+        let message = format!(
+            "{}: No file with today's token {} yet, check that today's sheet was dropped and that the system timezone is right",
+            source, token
+        );
+
+        print_error(&message);
+        show_notification(&message);
+    }
+}
+
+fn join_running(targets: &mut [WatchTarget]) {
+    for target in targets {
+        if let Some(run) = target.state.running.take() {
+            print_info("Waiting for the running script to finish");
+
+            let _ = run.handle.join();
+        }
+    }
+}
+
+fn rearm_watched_paths(
+    watcher: &mut dyn Watcher,
+    watched_paths: &[(PathBuf, RecursiveMode)],
+    lost_paths: &mut Vec<PathBuf>,
+) -> bool {
+    let mut is_regained = false;
+
+    for (path, recursive_mode) in watched_paths {
+        let is_lost = lost_paths.contains(path);
+
+        if !is_lost && !path.exists() {
+            let _ = watcher.unwatch(path);
+            lost_paths.push(path.clone());
+
+            let message = format!(
+                "Watched directory {} disappeared, waiting for it to reappear",
+                path.display()
+            );
+
+            print_error(&message);
+            show_notification(&message);
+        } else if is_lost && path.exists() {
+            match watcher.watch(path, *recursive_mode) {
+                Ok(()) => {
+                    lost_paths.retain(|lost_path| lost_path != path);
+                    is_regained = true;
+
+                    let message = format!(
+                        "Watched directory {} reappeared, watching again",
+                        path.display()
+                    );
+
+                    print_info(&message);
+                    show_notification(&message);
+                }
+                Err(e) => {
+                    print_error(&format!("Failed to re-watch {}: {:?}", path.display(), e));
+                }
+            }
+        }
+    }
+
+    is_regained
+}
+
+fn is_subdirectory_change(settings: &Settings, event: &Event) -> bool {
+    let directories = settings.listened_directory.values();
+
+    event.paths.iter().any(|path| match event.kind {
+        EventKind::Create(_) => {
+            path.is_dir()
+                && path
+                    .parent()
+                    .is_some_and(|parent| directories.iter().any(|dir| Path::new(dir) == parent))
+        }
+        _ => directories.iter().any(|dir| Path::new(dir) == path),
+    })
+}
+
+fn check_directories(path_config: &PathConfig) -> Result<(), String> {
+    let settings = &path_config.settings;
+    let mut directories = vec![settings.script_directory.as_str()];
+
+    if path_config.file.is_empty() {
+        directories.extend(settings.listened_directory.values());
+    }
+
+    match directories
+        .into_iter()
+        .find(|directory| !Path::new(directory).is_dir())
+    {
+        Some(directory) => Err(format!("Directory {} does not exist", directory)),
+        None => Ok(()),
+    }
+}
+
+fn reload_config(config_file: &Path) -> Result<Arc<PathConfig>, SheetWizardError> {
+    let new_config = load_config(&config_file.to_string_lossy())?;
+
+    check_directories(&new_config).map_err(SheetWizardError::Config)?;
+
+    Ok(Arc::new(new_config))
+}
+
+fn watch_config(
+    path_config: Arc<PathConfig>,
+    config_file: &Path,
+    watcher: &mut dyn Watcher,
+    rx: &mpsc::Receiver<NotifyResult<Event>>,
+    options: &WatcherOptions,
+    progress: &mut WatchProgress,
+) -> Result<WatchOutcome, SheetWizardError> {
+    let settings = &path_config.settings;
+    let runner: Arc<dyn ScriptRunner> = Arc::new(ProcessRunner);
+
+    init_file_log(settings);
+    init_notifications(settings);
+    init_python_executables(&path_config);
+
+    if settings.verify_write_access {
+        for directory in settings.writable_directories() {
+            if let Err(message) = check_write_access(directory) {
+                show_notification(&message);
+
+                return Err(SheetWizardError::Io(io::Error::other(message)));
+            }
+        }
+    }
+
+    let matcher = build_version_matcher(settings).map_err(SheetWizardError::Config)?;
+    let mut targets = build_watch_targets(&path_config);
+
+    if let Some(mut carried) = progress.carried.take() {
+        for target in &mut targets {
+            target.active_runs = Arc::clone(&carried.active_runs);
+
+            if let Some(index) = carried
+                .states
+                .iter()
+                .position(|(source, _)| *source == target.source())
+            {
+                target.state = carried.states.swap_remove(index).1;
+            }
+        }
+
+        for (_, state) in carried.states {
+            if let Some(run) = state.running {
+                let _ = run.handle.join();
+            }
+        }
+    }
+
+    let grace_until = progress.started_at + Duration::from_secs(settings.startup_grace_secs);
+
+    if Instant::now() < grace_until {
+        print_info(&format!(
+            "Startup grace period, detection resumes in {:?}",
+            grace_until - Instant::now()
+        ));
+
+        for target in &mut targets {
+            start_grace_period(target, settings, matcher.as_ref(), grace_until);
+        }
+    }
+
+    for target in &targets {
+        if let TargetKind::Directory(query) = &target.kind {
+            let existing_paths: Vec<PathBuf> = read_dir(query.folder_path)
+                .map(|entries| {
+                    entries
+                        .filter_map(|entry| entry.ok())
+                        .map(|entry| entry.path())
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            warn_unrecognized_versions(&existing_paths, query, matcher.as_ref());
+        }
+    }
+
+    let mut watched_paths: Vec<(PathBuf, RecursiveMode)> = Vec::new();
+    let config_watch_path = (
+        config_file.parent().unwrap_or(Path::new(".")).to_path_buf(),
+        RecursiveMode::NonRecursive,
+    );
+
+    for watch_path in targets
+        .iter()
+        .map(|target| target.watch_path(settings))
+        .chain([config_watch_path])
+    {
+        if !watched_paths.iter().any(|(path, _)| *path == watch_path.0) {
+            watched_paths.push(watch_path);
+        }
+    }
+
+    for (path, recursive_mode) in &watched_paths {
+        if let Err(e) = watch_with_retry(
+            watcher,
+            path,
+            *recursive_mode,
+            Duration::from_secs(settings.watch_retry_secs),
+            settings.watch_retry_attempts,
+        ) {
+            let message = format!("Failed to watch {}, giving up: {}", path.display(), e);
+
+            print_error(&message);
+            show_notification(&message);
+
+            return Err(e.into());
+        }
+    }
+
+    if settings.startup_notification && !progress.is_startup_notified {
+        let watching: Vec<String> = targets
+            .iter()
+            .map(|target| {
+                let filenames: Vec<&str> = target
+                    .steps
+                    .iter()
+                    .map(|step| step.filename.as_str())
+                    .collect();
+
+                format!("{} ({})", target.source(), filenames.join(", "))
+            })
+            .collect();
+
+        progress.is_startup_notified = true;
+        show_notification(
+            &settings
+                .language
+                .messages()
+                .watching
+                .replace("{targets}", &watching.join("\n")),
+        );
+    }
+
+    let mut outcome = WatchOutcome::Stop;
+    let mut was_paused = false;
+    let mut lost_paths: Vec<PathBuf> = Vec::new();
+    let mut last_existence_check = Instant::now();
+    let mut last_sweep: Option<Instant> = None;
+    let mut last_metrics_log = Instant::now();
+    let mut fatal_error = None;
+
+    loop {
+        let is_paused = IS_PAUSED.load(Ordering::SeqCst);
+
+        if last_existence_check.elapsed() >= TICK_INTERVAL {
+            last_existence_check = Instant::now();
+
+            if rearm_watched_paths(watcher, &watched_paths, &mut lost_paths) {
+                for target in &mut targets {
+                    target.state.reset_detection();
+                }
+            }
+        }
+
+        if was_paused && !is_paused {
+            print_info("Resumed, starting with fresh state");
+
+            for target in &mut targets {
+                target.state.reset_detection();
+            }
+        }
+
+        was_paused = is_paused;
+
+        if options
+            .max_runtime
+            .is_some_and(|max_runtime| progress.started_at.elapsed() >= max_runtime)
+        {
+            print_info("Maximum runtime reached, exiting");
+            break;
+        }
+
+        let sweep_interval = Duration::from_secs(settings.sweep_interval_secs);
+
+        if !is_paused
+            && !sweep_interval.is_zero()
+            && last_sweep.is_none_or(|swept_at| swept_at.elapsed() >= sweep_interval)
+        {
+            last_sweep = Some(Instant::now());
+
+            for target in &mut targets {
+                sweep_target(target, &path_config, matcher.as_ref(), &runner);
+            }
+        }
+
+        let metrics_interval = Duration::from_secs(settings.metrics_interval_secs);
+
+        if settings.daily_reset {
+            check_daily_token(
+                &mut progress.daily_check,
+                &targets,
+                settings,
+                matcher.as_ref(),
+            );
+        }
+
+        if !metrics_interval.is_zero() && last_metrics_log.elapsed() >= metrics_interval {
+            last_metrics_log = Instant::now();
+            progress.status.metrics.log();
+        }
+
+        for target in &mut targets {
+            if !is_paused && settings.close_detection == CloseDetection::Handle {
+                check_file_handle(target, &path_config, matcher.as_ref(), &runner);
+            }
+
+            target
+                .state
+                .settle_modifications(Duration::from_millis(settings.debounce_ms));
+
+            if !is_paused {
+                flush_batch(target, &path_config, matcher.as_ref(), &runner);
+                flush_pending_close(target, &path_config, matcher.as_ref(), &runner);
+            }
+
+            if let Some(result) = poll_run(target, &path_config, matcher.as_ref()) {
+                let success = result.success;
+
+                progress.status.metrics.record(&result);
+                progress.first_result.get_or_insert(success);
+                progress.status.last_result = Some((success, SystemTime::now()));
+                progress.status.is_dirty = true;
+            }
+
+            start_queued_run(target, &path_config, matcher.as_ref(), &runner);
+        }
+
+        update_status_file(
+            settings,
+            &targets,
+            matcher.as_ref(),
+            &mut progress.status,
+            true,
+        );
+
+        if options.exit_after_first && progress.first_result.is_some() {
+            print_info("First file processed, exiting");
+            break;
+        }
+
+        let timeout = if targets
+            .iter()
+            .any(|target| target.state.pending_close.is_some())
+        {
+            CLOSE_CHECK_INTERVAL
+        } else {
+            TICK_INTERVAL
+        };
+        let res = match rx.recv_timeout(timeout) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => {
+                if !is_paused
+                    && targets
+                        .iter()
+                        .any(|target| target.state.is_processing_deferred)
+                    && hold_back_reason(settings).is_none()
+                {
+                    print_info("Running deferred processing");
+
+                    for target in targets
+                        .iter_mut()
+                        .filter(|target| target.state.is_processing_deferred)
+                    {
+                        run_target(target, &path_config, matcher.as_ref(), None, &runner);
+                    }
+                }
+
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        if let Ok(event) = &res {
+            if !event.paths.is_empty() && event.paths.iter().all(|path| settings.is_excluded(path))
+            {
+                print_debug(&format!(
+                    "{} is in an excluded subdirectory, event ignored",
+                    event.paths[0].display()
+                ));
+                continue;
+            }
+
+            progress.status.metrics.events_observed += 1;
+        }
+
+        match res {
+            Ok(event) => match event.kind {
+                EventKind::Create(_) | EventKind::Modify(_)
+                    if event.paths.iter().any(|path| path == config_file) =>
+                {
+                    match reload_config(config_file) {
+                        Ok(new_config) => {
+                            print_info("Configuration changed, reloading");
+                            outcome = WatchOutcome::Reload(new_config);
+                            break;
+                        }
+                        Err(e) => {
+                            let message = format!(
+                                "Failed to reload path.toml, keeping the previous config: {}",
+                                e
+                            );
+
+                            show_notification(&message);
+                        }
+                    }
+                }
+                EventKind::Create(_) | EventKind::Remove(_)
+                    if settings.max_depth.is_some() && is_subdirectory_change(settings, &event) =>
+                {
+                    match reload_config(config_file) {
+                        Ok(new_config)
+                            if new_config.settings.listened_directory.values()
+                                != settings.listened_directory.values() =>
+                        {
+                            print_info(&format!(
+                                "Subfolder {} changed, rearming the watches",
+                                event.paths[0].display()
+                            ));
+                            outcome = WatchOutcome::Rearm(new_config);
+                            break;
+                        }
+                        Ok(_) => {}
+                        Err(e) => print_error(&format!(
+                            "Failed to rescan the subfolders, keeping the previous ones: {}",
+                            e
+                        )),
+                    }
+                }
+                EventKind::Create(_)
+                | EventKind::Modify(_)
+                | EventKind::Remove(_)
+                | EventKind::Access(_)
+                    if !is_paused =>
+                {
+                    if process_events(
+                        &mut targets,
+                        [Ok(event)],
+                        &path_config,
+                        matcher.as_ref(),
+                        &runner,
+                    ) {
+                        progress.status.last_event_at = Some(SystemTime::now());
+                        progress.status.is_dirty = true;
+                    }
+                }
+                EventKind::Create(_)
+                | EventKind::Modify(_)
+                | EventKind::Remove(_)
+                | EventKind::Access(_) => {}
+                EventKind::Other if event.info() == Some(MANUAL_RUN_INFO) => {
+                    if is_paused {
+                        print_info("Paused, manual run ignored");
+                        continue;
+                    }
+
+                    print_info("Manual run requested");
+                    progress.status.last_event_at = Some(SystemTime::now());
+                    progress.status.is_dirty = true;
+
+                    for target in &mut targets {
+                        if target.expected_path(matcher.as_ref(), false).is_some() {
+                            run_target(target, &path_config, matcher.as_ref(), None, &runner);
+                        }
+                    }
+                }
+                _ => {
+                    break;
+                }
+            },
+            Err(e) if is_fatal_watcher_error(&e) => {
+                print_error(&format!("Watcher failed, stopping it: {:?}", e));
+                fatal_error = Some(e);
+                break;
+            }
+            Err(e) => {
+                print_error(&format!("Error occurred in watcher: {:?}", e));
+            }
+        }
+    }
+
+    let mut kept_paths = Vec::new();
+
+    if let WatchOutcome::Rearm(new_config) = &outcome {
+        // Folders that are still listened keep their watch and their detection state, running
+        // scripts included.
+        kept_paths = build_watch_targets(new_config)
+            .iter()
+            .map(|target| target.watch_path(&new_config.settings).0)
+            .collect();
+        progress.carried = Some(CarriedTargets {
+            active_runs: targets
+                .first()
+                .map(|target| Arc::clone(&target.active_runs))
+                .unwrap_or_default(),
+            states: targets
+                .iter_mut()
+                .map(|target| (target.source(), std::mem::take(&mut target.state)))
+                .collect(),
+        });
+    } else {
+        join_running(&mut targets);
+    }
+
+    update_status_file(
+        settings,
+        &targets,
+        matcher.as_ref(),
+        &mut progress.status,
+        matches!(outcome, WatchOutcome::Reload(_) | WatchOutcome::Rearm(_)),
+    );
+
+    for (path, _) in watched_paths
+        .iter()
+        .filter(|(path, _)| !lost_paths.contains(path) && !kept_paths.contains(path))
+    {
+        let _ = watcher.unwatch(path);
+    }
+
+    match fatal_error {
+        Some(e) => Err(SheetWizardError::Watcher(e)),
+        None => Ok(outcome),
+    }
+}
+
+#[derive(Serialize)]
+struct WatchedFileStatus {
+    source: String,
+    latest_file: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct LastResultStatus {
+    success: bool,
+    finished_at: String,
+}
+
+#[derive(Serialize)]
+struct StatusReport {
+    is_watching: bool,
+    is_paused: bool,
+    updated_at: String,
+    files: Vec<WatchedFileStatus>,
+    last_event_at: Option<String>,
+    last_result: Option<LastResultStatus>,
+    metrics: MetricsSummary,
+}
+
+#[derive(Serialize)]
+struct MetricsSummary {
+    events_observed: u64,
+    files_processed: u64,
+    successes: u64,
+    failures: u64,
+    timeouts: u64,
+    average_duration_ms: u64,
+}
+
+#[derive(Default)]
+struct Metrics {
+    events_observed: u64,
+    successes: u64,
+    failures: u64,
+    timeouts: u64,
+    total_duration: Duration,
+}
+
+impl Metrics {
+    fn record(&mut self, result: &RunResult) {
+        if !result.has_run {
+            return;
+        }
+
+        if result.success {
+            self.successes += 1;
+        } else {
+            self.failures += 1;
+        }
+
+        if result.timed_out {
+            self.timeouts += 1;
+        }
+
+        self.total_duration += result.duration;
+    }
+
+    fn files_processed(&self) -> u64 {
+        self.successes + self.failures
+    }
+
+    fn average_duration_ms(&self) -> u64 {
+        match self.files_processed() {
+            0 => 0,
+            count => self.total_duration.as_millis() as u64 / count,
+        }
+    }
+
+    fn summary(&self) -> MetricsSummary {
+        MetricsSummary {
+            events_observed: self.events_observed,
+            files_processed: self.files_processed(),
+            successes: self.successes,
+            failures: self.failures,
+            timeouts: self.timeouts,
+            average_duration_ms: self.average_duration_ms(),
+        }
+    }
+
+    fn log(&self) {
+        log_event(
+            LogLevel::Info,
+            "metrics",
+            &format!(
+                "Metrics: {} events observed, {} files processed, {} succeeded, {} failed, {} timed out, {}ms on average",
+                self.events_observed,
+                self.files_processed(),
+                self.successes,
+                self.failures,
+                self.timeouts,
+                self.average_duration_ms()
+            ),
+            json!(self.summary()),
+        );
+    }
+}
+
+#[derive(Default)]
+struct StatusTracker {
+    last_event_at: Option<SystemTime>,
+    last_result: Option<(bool, SystemTime)>,
+    last_written_at: Option<Instant>,
+    is_dirty: bool,
+    metrics: Metrics,
+}
+
+fn write_status_file(
+    status_path: &str,
+    targets: &[WatchTarget],
+    matcher: &dyn VersionMatcher,
+    tracker: &StatusTracker,
+    is_watching: bool,
+) -> Result<(), Box<dyn Error>> {
+    let report = StatusReport {
+        is_watching,
+        is_paused: IS_PAUSED.load(Ordering::SeqCst),
+        updated_at: format_timestamp(SystemTime::now()),
+        files: targets
+            .iter()
+            .map(|target| WatchedFileStatus {
+                source: target.source(),
+                latest_file: target.expected_path(matcher, false),
+            })
+            .collect(),
+        last_event_at: tracker.last_event_at.map(format_timestamp),
+        last_result: tracker
+            .last_result
+            .map(|(success, finished_at)| LastResultStatus {
+                success,
+                finished_at: format_timestamp(finished_at),
+            }),
+        metrics: tracker.metrics.summary(),
+    };
+    let mut temp_path = PathBuf::from(status_path).into_os_string();
+
+    temp_path.push(".tmp");
+    std::fs::write(&temp_path, serde_json::to_string_pretty(&report)?)?;
+    rename(&temp_path, status_path)?;
+
+    Ok(())
+}
+
+fn update_status_file(
+    settings: &Settings,
+    targets: &[WatchTarget],
+    matcher: &dyn VersionMatcher,
+    tracker: &mut StatusTracker,
+    is_watching: bool,
+) {
+    let Some(status_path) = &settings.status_path else {
+        return;
+    };
+
+    if is_watching
+        && !tracker.is_dirty
+        && tracker
+            .last_written_at
+            .is_some_and(|written_at| written_at.elapsed() < STATUS_INTERVAL)
+    {
+        return;
+    }
+
+    if let Err(e) = write_status_file(status_path, targets, matcher, tracker, is_watching) {
+        print_error(&format!("Failed to write status to {}: {}", status_path, e));
+    }
+
+    tracker.last_written_at = Some(Instant::now());
+    tracker.is_dirty = false;
+}
+
+fn watcher_mode_of(settings: &Settings) -> (WatchMode, u64) {
+    (settings.watch_mode, settings.poll_interval_ms)
+}
+
+fn create_watcher(
+    settings: &Settings,
+    tx: mpsc::Sender<NotifyResult<Event>>,
+) -> NotifyResult<Box<dyn Watcher>> {
+    match settings.watch_mode {
+        WatchMode::Recommended => Ok(Box::new(recommended_watcher(tx)?)),
+        WatchMode::Poll => {
+            print_info(&format!(
+                "Polling for changes every {}ms",
+                settings.poll_interval_ms
+            ));
+
+            Ok(Box::new(PollWatcher::new(
+                tx,
+                NotifyConfig::default()
+                    .with_poll_interval(Duration::from_millis(settings.poll_interval_ms)),
+            )?))
+        }
+    }
+}
+
+fn run_watcher(
+    config_path: &str,
+    tx: mpsc::Sender<NotifyResult<Event>>,
+    rx: &mpsc::Receiver<NotifyResult<Event>>,
+    options: &WatcherOptions,
+) -> Result<(), SheetWizardError> {
+    let mut path_config = load_initial_config(config_path)?;
+
+    check_conda_environments(&path_config);
+
+    let config_file = canonicalize(config_path)?;
+
+    if let Some(record) = path_config
+        .settings
+        .history_path
+        .as_deref()
+        .and_then(read_last_history_record)
+    {
+        print_info(&format!(
+            "Last processed {} at {} ({})",
+            record
+                .file
+                .as_deref()
+                .map_or(record.source.clone(), |file| file.display().to_string()),
+            record.timestamp,
+            if record.success {
+                "succeeded"
+            } else {
+                "failed"
+            }
+        ));
+    }
+
+    let mut simulate_listener = path_config
+        .settings
+        .simulate_port
+        .map(|port| start_simulate_listener(port, tx.clone()))
+        .transpose()?;
+    let mut watcher_mode = watcher_mode_of(&path_config.settings);
+    let mut watcher = create_watcher(&path_config.settings, tx.clone())?;
+    let mut progress = WatchProgress {
+        started_at: Instant::now(),
+        first_result: None,
+        is_startup_notified: false,
+        status: StatusTracker::default(),
+        daily_check: DailyCheck::default(),
+        carried: None,
+    };
+
+    while let WatchOutcome::Reload(new_config) | WatchOutcome::Rearm(new_config) = watch_config(
+        path_config,
+        &config_file,
+        watcher.as_mut(),
+        rx,
+        options,
+        &mut progress,
+    )? {
+        path_config = new_config;
+
+        update_simulate_listener(&mut simulate_listener, &path_config.settings, &tx);
+
+        if watcher_mode != watcher_mode_of(&path_config.settings) {
+            print_info("Watch mode changed, recreating the watcher");
+            watcher_mode = watcher_mode_of(&path_config.settings);
+            watcher = create_watcher(&path_config.settings, tx.clone())?;
+        }
+    }
+
+    if options.exit_after_first && progress.first_result == Some(false) {
+        return Err(SheetWizardError::Script("Processing failed".to_string()));
+    }
+
+    Ok(())
+}
+
+fn describe_panic(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+fn is_fatal_watcher_error(e: &notify::Error) -> bool {
+    matches!(
+        e.kind,
+        NotifyErrorKind::Generic(_)
+            | NotifyErrorKind::MaxFilesWatch
+            | NotifyErrorKind::InvalidConfig(_)
+    )
+}
+
+fn is_config_load_retried(config_path: &str) -> bool {
+    read_to_string(config_path)
+        .ok()
+        .and_then(|content| {
+            let content = content.strip_prefix('\u{feff}').unwrap_or(&content);
+
+            from_str::<toml::Table>(content)
+                .ok()?
+                .get("settings")?
+                .get("retry_config_load")?
+                .as_bool()
+        })
+        .unwrap_or(false)
+}
+
+fn load_initial_config(config_path: &str) -> Result<Arc<PathConfig>, SheetWizardError> {
+    let mut delay = Duration::from_secs(1);
+
+    loop {
+        let error = match load_config(config_path) {
+            Ok(config) => match check_directories(&config) {
+                Ok(()) => return Ok(Arc::new(config)),
+                Err(message) => SheetWizardError::Config(message),
+            },
+            Err(e) => e,
+        };
+
+        if !is_config_load_retried(config_path) || IS_SHUTTING_DOWN.load(Ordering::SeqCst) {
+            match &error {
+                SheetWizardError::MissingConfig(_) => report_missing_config(&error),
+                SheetWizardError::Config(message) => {
+                    show_notification(message);
+                }
+                _ => {}
+            }
+
+            return Err(error);
+        }
+
+        let message = format!("{}, retrying in {:?}", error, delay);
+
+        print_error(&message);
+        show_notification(&message);
+
+        let failed_at = Instant::now();
+
+        while failed_at.elapsed() < delay && !IS_SHUTTING_DOWN.load(Ordering::SeqCst) {
+            sleep(TICK_INTERVAL);
+        }
+
+        delay = (delay * 2).min(WATCH_RETRY_MAX_DELAY);
+    }
+}
+
+fn report_missing_config(error: &SheetWizardError) {
+    #[cfg(all(windows, not(debug_assertions)))]
+    let message = format!(
+        "{}, set {} to the directory containing path.toml",
+        error,
+        config_env_var()
+    );
+    #[cfg(any(not(windows), debug_assertions))]
+    let message = error.to_string();
+
+    init_fallback_log();
+    print_error(&message);
+    show_notification(&message);
+}
+
+fn supervise_watcher(
+    config_path: &str,
+    tx: mpsc::Sender<NotifyResult<Event>>,
+    rx: &mpsc::Receiver<NotifyResult<Event>>,
+    options: &WatcherOptions,
+) -> Result<(), SheetWizardError> {
+    let mut restarts = 0;
+
+    loop {
+        let error = match catch_unwind(AssertUnwindSafe(|| {
+            run_watcher(config_path, tx.clone(), rx, options)
+        })) {
+            Ok(Err(SheetWizardError::Watcher(e))) => SheetWizardError::Watcher(e),
+            Ok(result) => return result,
+            Err(payload) => SheetWizardError::Panic(describe_panic(payload.as_ref())),
+        };
+
+        if restarts >= MAX_WATCHER_RESTARTS || IS_SHUTTING_DOWN.load(Ordering::SeqCst) {
+            show_notification(&format!("{}, giving up", error));
+
+            return Err(error);
+        }
+
+        restarts += 1;
+
+        let message = format!(
+            "{}, restarting in {:?} ({}/{})",
+            error, WATCHER_RESTART_DELAY, restarts, MAX_WATCHER_RESTARTS
+        );
+
+        print_error(&message);
+        show_notification(&message);
+        sleep(WATCHER_RESTART_DELAY);
+    }
+}
+
+#[cfg(any(not(windows), debug_assertions))]
+fn get_config_path() -> String {
+    "./path.toml".to_string()
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn get_config_path() -> String {
+    let path_config_directory = var(config_env_var()).unwrap_or("./".to_string());
+
+    format!("{}\\path.toml", path_config_directory)
+}
+
+#[derive(Serialize)]
+struct VersionedFile {
+    path: String,
+    version_token: String,
+    version_index: usize,
+}
+
+fn list_versioned_files(query: &FileQuery, matcher: &dyn VersionMatcher) -> Vec<VersionedFile> {
+    let mut files: Vec<VersionedFile> = get_versioned_files(query, matcher)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(version_index, path)| VersionedFile {
+            version_token: path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .and_then(|stem| {
+                    stem.strip_prefix(query.filename_prefix)
+                        .map(|token| token.to_string())
+                })
+                .unwrap_or_default(),
+            path: path.to_string_lossy().to_string(),
+            version_index,
+        })
+        .collect();
+
+    files.sort_by_key(|file| file.version_index);
+    files
+}
+
+#[derive(Serialize)]
+struct ScanResult {
+    directory: String,
+    filename_prefix: String,
+    latest: Option<String>,
+    hidden: Option<String>,
+    candidates: Vec<VersionedFile>,
+}
+
+fn scan_query(query: &FileQuery, matcher: &dyn VersionMatcher) -> ScanResult {
+    let resolve = |is_hidden_file| {
+        get_filename_with_largest_tiangan(query, matcher, is_hidden_file)
+            .map(|path| path.to_string_lossy().to_string())
+    };
+
+    ScanResult {
+        directory: query.folder_path.to_string(),
+        filename_prefix: query.filename_prefix.to_string(),
+        latest: resolve(false),
+        hidden: resolve(true),
+        candidates: list_versioned_files(query, matcher),
+    }
+}
+
+#[derive(Serialize)]
+struct DoctorCheck {
+    check: String,
+    target: String,
+    ok: bool,
+    message: Option<String>,
+}
+
+fn validate_settings(settings: &Settings) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for directory in settings.listened_directory.values() {
+        if !Path::new(directory).is_dir() {
+            problems.push(format!("listened_directory {} does not exist", directory));
+        }
+    }
+
+    if !Path::new(&settings.script_directory).is_dir() {
+        problems.push(format!(
+            "script_directory {} does not exist",
+            settings.script_directory
+        ));
+    } else {
+        let steps = settings.script_steps();
+        let filenames = steps
+            .iter()
+            .map(|step| &step.filename)
+            .chain(settings.ext_scripts.values());
+
+        for filename in filenames {
+            if !Path::new(&settings.script_directory)
+                .join(filename)
+                .is_file()
+            {
+                problems.push(format!(
+                    "{} is not present under script_directory",
+                    filename
+                ));
+            }
+        }
+    }
+
+    if settings
+        .ext_name
+        .values()
+        .iter()
+        .all(|ext_name| ext_name.is_empty())
+    {
+        problems.push("ext_name is empty".to_string());
+    }
+
+    if let Some(run_as) = settings
+        .run_as
+        .as_ref()
+        .filter(|run_as| cfg!(windows) && run_as.credential.is_none())
+    {
+        problems.push(format!(
+            "run_as.credential is not set, it is needed to log on as {}",
+            run_as.user
+        ));
+    }
+
+    if settings
+        .filename_prefix
+        .values()
+        .iter()
+        .any(|prefix| prefix.is_empty())
+    {
+        problems.push("filename_prefix is empty".to_string());
+    }
+
+    if settings
+        .hidden_filename_prefix
+        .values()
+        .iter()
+        .any(|prefix| prefix.is_empty())
+    {
+        problems.push("hidden_filename_prefix is empty".to_string());
+    }
+
+    if settings.hidden_filename_prefix.values().len() > settings.filename_prefix.values().len() {
+        problems.push("hidden_filename_prefix has more entries than filename_prefix".to_string());
+    }
+
+    problems
+}
+
+fn run_cli(arguments: &[String]) -> Result<(), Box<dyn Error>> {
+    let command = arguments[0].as_str();
+    let is_json = arguments.iter().any(|argument| argument == "--json");
+    let path_config = load_config(&get_config_path())?;
+    let matcher = build_version_matcher(&path_config.settings)?;
+    let mut file_queries = path_config.settings.file_queries();
+
+    if let Some(index) = arguments.iter().position(|argument| argument == "--dir") {
+        let directory = arguments
+            .get(index + 1)
+            .ok_or("--dir requires a directory")?;
+
+        file_queries.retain(|query| query.folder_path == directory);
+
+        if file_queries.is_empty() {
+            return Err(format!("{} is not a listened directory", directory).into());
+        }
+    }
+
+    if let Some(index) = arguments.iter().position(|argument| argument == "--prefix") {
+        let prefix = arguments
+            .get(index + 1)
+            .ok_or("--prefix requires a filename prefix")?;
+
+        file_queries.retain(|query| query.filename_prefix == prefix);
+
+        if file_queries.is_empty() {
+            return Err(format!("{} is not a configured filename_prefix", prefix).into());
+        }
+    }
+
+    let file_query = file_queries
+        .first()
+        .ok_or("No listened directory configured")?;
+
+    match command {
+        "list" => {
+            let files: Vec<VersionedFile> = file_queries
+                .iter()
+                .flat_map(|query| list_versioned_files(query, matcher.as_ref()))
+                .collect();
+
+            if is_json {
+                println!("{}", serde_json::to_string_pretty(&files)?);
+            } else {
+                for file in files {
+                    println!(
+                        "{}\t{}\t{}",
+                        file.version_index, file.version_token, file.path
+                    );
+                }
+            }
+        }
+        "which" => {
+            let latest = get_filename_with_largest_tiangan(file_query, matcher.as_ref(), false)
+                .map(|path| path.to_string_lossy().to_string());
+
+            if is_json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&json!({ "path": latest }))?
+                );
+            } else {
+                println!("{}", latest.ok_or("No matching file found")?);
+            }
+        }
+        "scan" => {
+            let results: Vec<ScanResult> = file_queries
+                .iter()
+                .map(|query| scan_query(query, matcher.as_ref()))
+                .collect();
+
+            if is_json {
+                println!("{}", serde_json::to_string_pretty(&results)?);
+            } else {
+                for result in &results {
+                    println!("{} ({})", result.directory, result.filename_prefix);
+                    println!("  latest: {}", result.latest.as_deref().unwrap_or("none"));
+                    println!("  hidden: {}", result.hidden.as_deref().unwrap_or("none"));
+
+                    for file in &result.candidates {
+                        println!(
+                            "  {}\t{}\t{}",
+                            file.version_index, file.version_token, file.path
+                        );
+                    }
+                }
+            }
+        }
+        "current-version" => {
+            let latest = get_filename_with_largest_tiangan(file_query, matcher.as_ref(), false)
+                .map(|path| path.to_string_lossy().to_string());
+            let current = list_versioned_files(file_query, matcher.as_ref())
+                .into_iter()
+                .find(|file| Some(&file.path) == latest.as_ref());
+
+            if is_json {
+                println!("{}", serde_json::to_string_pretty(&current)?);
+            } else {
+                println!("{}", current.ok_or("No matching file found")?.version_token);
+            }
+        }
+        "doctor" => {
+            let checks: Vec<DoctorCheck> = path_config
+                .settings
+                .writable_directories()
+                .into_iter()
+                .map(|directory| {
+                    let result = check_write_access(directory);
+
+                    DoctorCheck {
+                        check: "write_access".to_string(),
+                        target: directory.to_string(),
+                        ok: result.is_ok(),
+                        message: result.err(),
+                    }
+                })
+                .collect();
+            let is_healthy = checks.iter().all(|check| check.ok);
+
+            if is_json {
+                println!("{}", serde_json::to_string_pretty(&checks)?);
+            } else {
+                for check in &checks {
+                    match &check.message {
+                        Some(message) => println!("[FAILED] {}: {}", check.check, message),
+                        None => println!("[OK] {}: {}", check.check, check.target),
+                    }
+                }
+            }
+
+            if !is_healthy {
+                return Err("Some checks failed".into());
+            }
+        }
+        "validate" => {
+            let problems = validate_settings(&path_config.settings);
+
+            if is_json {
+                println!("{}", serde_json::to_string_pretty(&problems)?);
+            } else if problems.is_empty() {
+                println!("Configuration is valid");
+            } else {
+                for problem in &problems {
+                    println!("[INVALID] {}", problem);
+                }
+            }
+
+            if !problems.is_empty() {
+                return Err("Configuration is invalid".into());
+            }
+        }
+        "history" => {
+            let history_path = path_config
+                .settings
+                .history_path
+                .as_deref()
+                .ok_or("history_path is not set in path.toml")?;
+            let count = match arguments
+                .get(1)
+                .filter(|argument| !argument.starts_with("--"))
+            {
+                Some(count) => count
+                    .parse::<usize>()
+                    .map_err(|_| format!("{} is not a record count", count))?,
+                None => DEFAULT_HISTORY_COUNT,
+            };
+            let records = read_history_records(history_path, count);
+
+            if is_json {
+                println!("{}", serde_json::to_string_pretty(&records)?);
+            } else if records.is_empty() {
+                println!("No runs recorded in {} yet", history_path);
+            } else {
+                println!("{}", format_history_table(&records));
+            }
+        }
+        "simulate" => send_simulate_request(&path_config.settings, &arguments[1..])?,
+        _ => return Err(format!("Unknown command: {}", command).into()),
+    }
+
+    Ok(())
+}
+
+fn run_once() -> Result<(), SheetWizardError> {
+    let path_config = load_config(&get_config_path())?;
+    let settings = &path_config.settings;
+    let matcher = build_version_matcher(settings).map_err(SheetWizardError::Config)?;
+    let mut all_succeeded = true;
+    let mut has_processed = false;
+
+    init_file_log(settings);
+    init_notifications(settings);
+    init_python_executables(&path_config);
+
+    for target in build_watch_targets(&path_config) {
+        let Some(path) = target.expected_path(matcher.as_ref(), false) else {
+            print_info(&format!("No matching file in {}", target.source()));
+            continue;
+        };
+        let Some(steps) = target.steps_for(settings, Some(&path)) else {
+            print_info(&format!("No script is mapped to {}", path.display()));
+            continue;
+        };
+
+        print_info(&format!("Processing {}", path.display()));
+
+        let context =
+            target.run_context(settings, matcher.as_ref(), Arc::new(AtomicBool::new(false)));
+
+        set_correlation_id(context.correlation_id.clone());
+
+        let result = process_sheet(settings, &steps, &context);
+
+        observe_result(target.observer.as_ref(), &target.source(), &result);
+        all_succeeded &= result.success;
+        has_processed = true;
+    }
+
+    if !has_processed {
+        return Err(SheetWizardError::NoMatch);
+    }
+
+    if !all_succeeded {
+        return Err(SheetWizardError::Script("Processing failed".to_string()));
+    }
+
+    Ok(())
+}
+
+fn parse_reprocess_count(arguments: &[String]) -> Result<Option<usize>, Box<dyn Error>> {
+    let Some(index) = arguments
+        .iter()
+        .position(|argument| argument == "--reprocess-last")
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(
+        arguments
+            .get(index + 1)
+            .ok_or("--reprocess-last requires a count")?
+            .parse()?,
+    ))
+}
+
+fn run_reprocess(count: usize) -> Result<(), SheetWizardError> {
+    let path_config = load_config(&get_config_path())?;
+    let settings = &path_config.settings;
+    let matcher = build_version_matcher(settings).map_err(SheetWizardError::Config)?;
+    let mut all_succeeded = true;
+
+    init_file_log(settings);
+    init_notifications(settings);
+    init_python_executables(&path_config);
+
+    if settings.pass_file.is_none() {
+        return Err(SheetWizardError::Config(
+            "--reprocess-last requires [settings.pass_file] to hand each file to the scripts"
+                .to_string(),
+        ));
+    }
+
+    for target in build_watch_targets(&path_config) {
+        let TargetKind::Directory(query) = &target.kind else {
+            print_info(&format!(
+                "{} is a single file, skipped by --reprocess-last",
+                target.source()
+            ));
+            continue;
+        };
+
+        let paths = get_top_n_files(query, matcher.as_ref(), count);
+
+        if paths.is_empty() {
+            print_info(&format!("No matching file in {}", target.source()));
+        }
+
+        for path in paths {
+            let Some(steps) = target.steps_for(settings, Some(&path)) else {
+                print_info(&format!("No script is mapped to {}", path.display()));
+                continue;
+            };
+
+            print_info(&format!("Reprocessing {}", path.display()));
+
+            let mut context =
+                target.run_context(settings, matcher.as_ref(), Arc::new(AtomicBool::new(false)));
+
+            context.file = Some(target.resolve_file(matcher.as_ref(), path));
+            set_correlation_id(context.correlation_id.clone());
+
+            let result = process_sheet(settings, &steps, &context);
+
+            observe_result(target.observer.as_ref(), &target.source(), &result);
+            all_succeeded &= result.success;
+        }
+    }
+
+    if !all_succeeded {
+        return Err(SheetWizardError::Script("Processing failed".to_string()));
+    }
+
+    Ok(())
+}
+
+fn run_foreground(options: &WatcherOptions) -> Result<(), SheetWizardError> {
+    let (tx, rx) = mpsc::channel::<NotifyResult<Event>>();
+
+    supervise_watcher(&get_config_path(), tx, &rx, options)
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn set_service_state(
+    status_handle: &ServiceStatusHandle,
+    current_state: ServiceState,
+    checkpoint: u32,
+) -> windows_service::Result<()> {
+    let (controls_accepted, wait_hint) = match current_state {
+        ServiceState::Running | ServiceState::Paused => (
+            ServiceControlAccept::STOP | ServiceControlAccept::PAUSE_CONTINUE,
+            Duration::default(),
+        ),
+        ServiceState::StopPending => (ServiceControlAccept::empty(), STOP_WAIT_HINT),
+        _ => (ServiceControlAccept::empty(), Duration::default()),
+    };
+
+    status_handle.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint,
+        wait_hint,
+        process_id: None,
+    })
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn report_stop_pending(status_handle: ServiceStatusHandle, checkpoint: Arc<Mutex<Option<u32>>>) {
+    loop {
+        {
+            let Ok(mut checkpoint) = checkpoint.lock() else {
+                return;
+            };
+            let Some(cur_checkpoint) = checkpoint.as_mut() else {
+                return;
+            };
+
+            *cur_checkpoint += 1;
+            let _ = set_service_state(&status_handle, ServiceState::StopPending, *cur_checkpoint);
+        }
+
+        sleep(STOP_WAIT_HINT / 2);
+    }
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+const DEFAULT_SERVICE_NAME: &str = "SheetWizard";
+#[cfg(all(windows, not(debug_assertions)))]
+const DEFAULT_CONFIG_ENV_VAR: &str = "SW_TOML_PATH";
+
+#[cfg(all(windows, not(debug_assertions)))]
+static SERVICE_NAME: OnceLock<String> = OnceLock::new();
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn service_name() -> &'static str {
+    SERVICE_NAME
+        .get()
+        .map_or(DEFAULT_SERVICE_NAME, String::as_str)
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn config_env_var() -> String {
+    if service_name() == DEFAULT_SERVICE_NAME {
+        return DEFAULT_CONFIG_ENV_VAR.to_string();
+    }
+
+    let prefix: String = service_name()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    format!("{}_TOML_PATH", prefix)
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn pipe_name() -> String {
+    format!(r"\\.\pipe\{}", service_name())
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn accept_pipe_client() -> io::Result<File> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::FromRawHandle;
+    use windows_sys::Win32::Foundation::{ERROR_PIPE_CONNECTED, INVALID_HANDLE_VALUE};
+    use windows_sys::Win32::Storage::FileSystem::PIPE_ACCESS_INBOUND;
+    use windows_sys::Win32::System::Pipes::{
+        ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_TYPE_BYTE,
+        PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+    };
+
+    let name: Vec<u16> = OsStr::new(&pipe_name()).encode_wide().chain([0]).collect();
+    let handle = unsafe {
+        CreateNamedPipeW(
+            name.as_ptr(),
+            PIPE_ACCESS_INBOUND,
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT,
+            PIPE_UNLIMITED_INSTANCES,
+            0,
+            512,
+            0,
+            std::ptr::null(),
+        )
+    };
+
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+
+    // The File owns the handle from here on, so it is closed on every path.
+    let pipe = unsafe { File::from_raw_handle(handle) };
+
+    if unsafe { ConnectNamedPipe(handle, std::ptr::null_mut()) } == 0 {
+        let e = io::Error::last_os_error();
+
+        if e.raw_os_error() != Some(ERROR_PIPE_CONNECTED as i32) {
+            return Err(e);
+        }
+    }
+
+    Ok(pipe)
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn start_pipe_listener(tx: mpsc::Sender<NotifyResult<Event>>) {
+    spawn(move || loop {
+        let pipe = match accept_pipe_client() {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                print_error(&format!("Failed to listen on {}: {}", pipe_name(), e));
+                sleep(WATCHER_RESTART_DELAY);
+                continue;
+            }
+        };
+        let mut command = String::new();
+
+        if BufReader::new(pipe).read_line(&mut command).is_err() {
+            continue;
+        }
+
+        match command.trim() {
+            "run" => {
+                if tx.send(Ok(manual_run_event())).is_err() {
+                    return;
+                }
+            }
+            command => print_error(&format!("Unknown pipe command: {}", command)),
+        }
+    });
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn send_pipe_command(command: &str) -> Result<(), Box<dyn Error>> {
+    let mut pipe = OpenOptions::new()
+        .write(true)
+        .open(pipe_name())
+        .map_err(|e| {
+            format!(
+                "Failed to open {}, is the service running? {}",
+                pipe_name(),
+                e
+            )
+        })?;
+
+    writeln!(pipe, "{}", command)?;
+    println!("Sent {} to the {} service", command, service_name());
+
+    Ok(())
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn run_service() -> Result<(), Box<dyn Error>> {
+    let (tx, rx) = mpsc::channel::<NotifyResult<Event>>();
+    let tx_clone = tx.clone();
+    let stop_checkpoint = Arc::new(Mutex::new(Some(0)));
+    let handler_checkpoint = Arc::clone(&stop_checkpoint);
+    let status_slot: Arc<OnceLock<ServiceStatusHandle>> = Arc::new(OnceLock::new());
+    let handler_slot = Arc::clone(&status_slot);
+    let status_handle = service_control_handler::register(
+        service_name(),
+        move |control_event| -> ServiceControlHandlerResult {
+            match control_event {
+                ServiceControl::Stop => {
+                    IS_SHUTTING_DOWN.store(true, Ordering::SeqCst);
+
+                    if let Some(status_handle) = handler_slot.get().copied() {
+                        let checkpoint = Arc::clone(&handler_checkpoint);
+
+                        spawn(move || report_stop_pending(status_handle, checkpoint));
+                    }
+
+                    let _ = tx_clone.send(Ok(Event::new(EventKind::Other)));
+
+                    ServiceControlHandlerResult::NoError
+                }
+                ServiceControl::Pause | ServiceControl::Continue => {
+                    let is_pausing = control_event == ServiceControl::Pause;
+
+                    IS_PAUSED.store(is_pausing, Ordering::SeqCst);
+
+                    if let Some(status_handle) = handler_slot.get() {
+                        let current_state = if is_pausing {
+                            ServiceState::Paused
+                        } else {
+                            ServiceState::Running
+                        };
+
+                        let _ = set_service_state(status_handle, current_state, 0);
+                    }
+
+                    ServiceControlHandlerResult::NoError
+                }
+                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+                _ => ServiceControlHandlerResult::NotImplemented,
+            }
+        },
+    )?;
+
+    let _ = status_slot.set(status_handle);
+
+    set_service_state(&status_handle, ServiceState::Running, 0)?;
+    start_pipe_listener(tx.clone());
+
+    let result = supervise_watcher(&get_config_path(), tx, &rx, &WatcherOptions::default());
+
+    if let Ok(mut checkpoint) = stop_checkpoint.lock() {
+        *checkpoint = None;
+        set_service_state(&status_handle, ServiceState::Stopped, 0)?;
+    }
+
+    Ok(result?)
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn set_service_environment(variables: &[(&str, &str)]) -> Result<(), String> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::System::Registry::{RegSetKeyValueW, HKEY_LOCAL_MACHINE, REG_MULTI_SZ};
+
+    let to_wide =
+        |value: &str| -> Vec<u16> { OsStr::new(value).encode_wide().chain([0]).collect() };
+    let subkey = to_wide(&format!(
+        "SYSTEM\\CurrentControlSet\\Services\\{}",
+        service_name()
+    ));
+    let value_name = to_wide("Environment");
+    let mut data: Vec<u16> = variables
+        .iter()
+        .flat_map(|(name, value)| to_wide(&format!("{}={}", name, value)))
+        .collect();
+
+    data.push(0);
+
+    let status = unsafe {
+        RegSetKeyValueW(
+            HKEY_LOCAL_MACHINE,
+            subkey.as_ptr(),
+            value_name.as_ptr(),
+            REG_MULTI_SZ,
+            data.as_ptr().cast(),
+            (data.len() * size_of::<u16>()) as u32,
+        )
+    };
+
+    if status != 0 {
+        return Err(format!(
+            "Failed to set the service environment (error {})",
+            status
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn install_service(arguments: &[String]) -> Result<(), Box<dyn Error>> {
+    let executable_path = std::env::current_exe()?;
+    let config_directory = match arguments
+        .iter()
+        .position(|argument| argument == "--config-dir")
+    {
+        Some(index) => absolute(
+            arguments
+                .get(index + 1)
+                .ok_or("--config-dir requires a directory")?,
+        )?,
+        None => executable_path
+            .parent()
+            .ok_or("Failed to resolve the executable directory")?
+            .to_path_buf(),
+    };
+    let service_manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(|e| format!("Failed to open the service manager: {}", e))?;
+    let is_default_name = service_name() == DEFAULT_SERVICE_NAME;
+    let service_info = ServiceInfo {
+        name: OsString::from(service_name()),
+        display_name: OsString::from(if is_default_name {
+            "Sheet Wizard".to_string()
+        } else {
+            format!("Sheet Wizard ({})", service_name())
+        }),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path,
+        launch_arguments: if is_default_name {
+            Vec::new()
+        } else {
+            vec![
+                OsString::from("--service-name"),
+                OsString::from(service_name()),
+            ]
+        },
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = service_manager
+        .create_service(
+            &service_info,
+            ServiceAccess::CHANGE_CONFIG | ServiceAccess::DELETE,
+        )
+        .map_err(|e| format!("Failed to install the {} service: {}", service_name(), e))?;
+
+    let _ = service.set_description("A simple listener for spreadsheet files.");
+
+    if let Err(message) =
+        set_service_environment(&[(&config_env_var(), &config_directory.to_string_lossy())])
+    {
+        let _ = service.delete();
+
+        return Err(message.into());
+    }
+
+    println!(
+        "Installed the {} service, reading {}",
+        service_name(),
+        config_directory.join("path.toml").display()
+    );
+
+    Ok(())
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn uninstall_service() -> Result<(), Box<dyn Error>> {
+    let service_manager =
+        ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+            .map_err(|e| format!("Failed to open the service manager: {}", e))?;
+    let service = service_manager
+        .open_service(
+            service_name(),
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .map_err(|e| format!("Failed to open the {} service: {}", service_name(), e))?;
+
+    if service
+        .query_status()
+        .is_ok_and(|status| status.current_state != ServiceState::Stopped)
+    {
+        service
+            .stop()
+            .map_err(|e| format!("Failed to stop the {} service: {}", service_name(), e))?;
+    }
+
+    service
+        .delete()
+        .map_err(|e| format!("Failed to uninstall the {} service: {}", service_name(), e))?;
+
+    println!("Uninstalled the {} service", service_name());
+
+    Ok(())
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn run_service_entry(_: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        print_error(&format!("Service failed: {}", e));
+    }
+}
+#[cfg(all(windows, not(debug_assertions)))]
+define_windows_service!(ffi_service_main, run_service_entry);
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut arguments: Vec<String> = args().skip(1).collect();
+
+    if let Some(index) = arguments
+        .iter()
+        .position(|argument| argument == "--service-name")
+    {
+        let name = arguments
+            .get(index + 1)
+            .ok_or("--service-name requires a name")?
+            .clone();
+
+        arguments.drain(index..=index + 1);
+        let _ = SERVICE_NAME.set(name);
+    }
+
+    match arguments.first().map(String::as_str) {
+        Some("install") => return install_service(&arguments),
+        Some("uninstall") => return uninstall_service(),
+        Some("trigger") => return send_pipe_command("run"),
+        _ => {}
+    }
+
+    if arguments
+        .first()
+        .is_some_and(|argument| !argument.starts_with("--"))
+    {
+        return run_cli(&arguments);
+    }
+
+    if arguments.iter().any(|argument| argument == "--once") {
+        return Ok(run_once()?);
+    }
+
+    if let Some(count) = parse_reprocess_count(&arguments)? {
+        return Ok(run_reprocess(count)?);
+    }
+
+    if !arguments.is_empty() {
+        return Ok(run_foreground(&parse_watcher_options(&arguments)?)?);
+    }
+
+    service_dispatcher::start(service_name(), ffi_service_main)?;
+
+    Ok(())
+}
+
+#[cfg(any(not(windows), debug_assertions))]
+fn main() -> Result<(), Box<dyn Error>> {
+    let arguments: Vec<String> = args().skip(1).collect();
+
+    if arguments
+        .first()
+        .is_some_and(|argument| !argument.starts_with("--"))
+    {
+        return run_cli(&arguments);
+    }
+
+    if arguments.iter().any(|argument| argument == "--once") {
+        return Ok(run_once()?);
+    }
+
+    if let Some(count) = parse_reprocess_count(&arguments)? {
+        return Ok(run_reprocess(count)?);
+    }
+
+    run_foreground(&parse_watcher_options(&arguments)?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static GLOBAL_LOG: Mutex<()> = Mutex::new(());
+
+    // Tests that set up the process-wide log take this so they don't swap it under each other.
+    fn lock_global_log() -> std::sync::MutexGuard<'static, ()> {
+        GLOBAL_LOG.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn process_runner() -> Arc<dyn ScriptRunner> {
+        Arc::new(ProcessRunner)
+    }
+
+    #[derive(Default)]
+    struct RecordingRunner {
+        files: Mutex<Vec<Option<PathBuf>>>,
+    }
+
+    impl ScriptRunner for RecordingRunner {
+        fn run(&self, _: &Settings, _: &[ScriptStep], context: &RunContext) -> RunResult {
+            self.files
+                .lock()
+                .unwrap()
+                .push(context.file.as_ref().map(|(path, _)| path.clone()));
+
+            RunResult {
+                success: true,
+                ..RunResult::default()
+            }
+        }
+    }
+
+    fn sheet_config(settings: &str) -> Arc<PathConfig> {
+        Arc::new(
+            from_str::<PathConfig>(&format!(
+                r#"
+                [settings]
+                listened_directory = "D:/sheets"
+                script_directory = "D:/scripts"
+                {}
+
+                [[file]]
+                path = "D:/sheets/sheet甲.xlsx"
+                "#,
+                settings
+            ))
+            .unwrap(),
+        )
+    }
+
+    #[cfg(unix)]
+    fn script_settings(directory: &Path, extra: &str) -> Settings {
+        from_str::<PathConfig>(&format!(
+            r#"
+            [settings]
+            listened_directory = '{0}'
+            script_directory = '{0}'
+            command_template = "sh {{script_path}}"
+            {1}
+            "#,
+            directory.display(),
+            extra
+        ))
+        .unwrap()
+        .settings
+    }
+
+    #[cfg(unix)]
+    fn test_step(filename: &str) -> ScriptStep {
+        ScriptStep {
+            filename: filename.to_string(),
+            env_name: None,
+            args: Vec::new(),
+            env: HashMap::new(),
+        }
+    }
+
+    #[cfg(unix)]
+    fn test_context() -> RunContext {
+        RunContext {
+            file: None,
+            cancel: Arc::new(AtomicBool::new(false)),
+            correlation_id: None,
+            source: "test".to_string(),
+            filename_prefix: None,
+        }
+    }
+
+    fn sheet_edit_events() -> (PathBuf, [Event; 3]) {
+        let sheet_path = PathBuf::from("D:/sheets/sheet甲.xlsx");
+        let hidden_path = PathBuf::from("D:/sheets/~$sheet甲.xlsx");
+
+        (
+            sheet_path.clone(),
+            [
+                Event::new(EventKind::Create(CreateKind::File)).add_path(hidden_path.clone()),
+                Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                    .add_path(sheet_path),
+                Event::new(EventKind::Remove(RemoveKind::File)).add_path(hidden_path),
+            ],
+        )
+    }
+
+    #[test]
+    fn open_modify_close_runs_the_script_once_for_the_closed_file() {
+        let path_config = sheet_config("debounce_ms = 0");
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+        let recorder = Arc::new(RecordingRunner::default());
+        let runner: Arc<dyn ScriptRunner> = recorder.clone();
+        let sheet_path = PathBuf::from("D:/sheets/sheet甲.xlsx");
+        let hidden_path = PathBuf::from("D:/sheets/~$sheet甲.xlsx");
+        let events = [
+            Event::new(EventKind::Create(CreateKind::File)).add_path(hidden_path.clone()),
+            Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                .add_path(sheet_path.clone()),
+            Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                .add_path(sheet_path.clone()),
+            Event::new(EventKind::Remove(RemoveKind::File)).add_path(hidden_path.clone()),
+            Event::new(EventKind::Remove(RemoveKind::File)).add_path(hidden_path),
+        ];
+
+        assert!(process_events(
+            &mut targets,
+            events.into_iter().map(Ok),
+            &path_config,
+            matcher.as_ref(),
+            &runner,
+        ));
+
+        join_running(&mut targets);
+
+        assert_eq!(*recorder.files.lock().unwrap(), vec![Some(sheet_path)]);
+    }
+
+    #[test]
+    fn reloads_move_the_simulate_listener_to_the_new_port() {
+        let probes = [
+            TcpListener::bind(("127.0.0.1", 0)).unwrap(),
+            TcpListener::bind(("127.0.0.1", 0)).unwrap(),
+        ];
+        let [old_port, new_port] = probes.map(|probe| probe.local_addr().unwrap().port());
+        let (tx, rx) = mpsc::channel();
+        let mut simulate_listener = Some(start_simulate_listener(old_port, tx.clone()).unwrap());
+        let path_config = sheet_config(&format!("simulate_port = {}", new_port));
+
+        update_simulate_listener(&mut simulate_listener, &path_config.settings, &tx);
+
+        assert!(TcpStream::connect(("127.0.0.1", old_port)).is_err());
+
+        send_simulate_request(&path_config.settings, &["--run".to_string()]).unwrap();
+
+        assert_eq!(
+            rx.try_recv().unwrap().unwrap().info(),
+            Some(MANUAL_RUN_INFO)
+        );
+
+        update_simulate_listener(&mut simulate_listener, &sheet_config("").settings, &tx);
+
+        assert!(simulate_listener.is_none());
+        assert!(TcpStream::connect(("127.0.0.1", new_port)).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn access_events_go_through_the_watch_loop() {
+        let folder = tempfile::tempdir().unwrap();
+        let config_path = folder.path().join("path.toml");
+        let sheet_path = folder.path().join("sheet甲.xlsx");
+        let hidden_path = folder.path().join("~$sheet甲.xlsx");
+
+        std::fs::write(&sheet_path, "sheet").unwrap();
+        std::fs::write(folder.path().join("mark.sh"), "echo ran > ran.txt\n").unwrap();
+
+        let path_config = Arc::new(
+            from_str::<PathConfig>(&format!(
+                r#"
+                [settings]
+                listened_directory = '{0}'
+                script_directory = '{0}'
+                script_filename = "mark.sh"
+                command_template = "sh {{script_path}}"
+                debounce_ms = 0
+                check_conda_env = false
+
+                [settings.event_phases]
+                open = ["access"]
+                close = ["access_close"]
+
+                [[file]]
+                path = '{1}'
+                "#,
+                folder.path().display(),
+                sheet_path.display()
+            ))
+            .unwrap(),
+        );
+        let (tx, rx) = mpsc::channel();
+
+        for event in [
+            Event::new(EventKind::Access(AccessKind::Close(AccessMode::Any)))
+                .add_path(hidden_path.clone()),
+            Event::new(EventKind::Access(AccessKind::Open(AccessMode::Any)))
+                .add_path(hidden_path.clone()),
+            Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                .add_path(sheet_path.clone()),
+            Event::new(EventKind::Access(AccessKind::Close(AccessMode::Write)))
+                .add_path(hidden_path.clone()),
+        ] {
+            tx.send(Ok(event)).unwrap();
+        }
+
+        drop(tx);
+
+        let mut progress = WatchProgress {
+            started_at: Instant::now(),
+            first_result: None,
+            is_startup_notified: false,
+            status: StatusTracker::default(),
+            daily_check: DailyCheck::default(),
+            carried: None,
+        };
+        let _log = lock_global_log();
+
+        watch_config(
+            path_config,
+            &config_path,
+            &mut notify::NullWatcher,
+            &rx,
+            &WatcherOptions::default(),
+            &mut progress,
+        )
+        .unwrap();
+
+        assert_eq!(
+            read_to_string(folder.path().join("ran.txt")).unwrap(),
+            "ran\n"
+        );
+    }
+
+    #[test]
+    fn new_subfolders_are_rearmed_without_losing_detection_state() {
+        let folder = tempfile::tempdir().unwrap();
+        let config_path = folder.path().join("path.toml");
+        let root = folder.path().join("sheets");
+
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(folder.path().join("scripts")).unwrap();
+        std::fs::write(root.join("sheet甲.xlsx"), "").unwrap();
+        std::fs::write(
+            &config_path,
+            r#"
+            [settings]
+            listened_directory = "sheets"
+            script_directory = "scripts"
+            max_depth = 1
+            check_conda_env = false
+            "#,
+        )
+        .unwrap();
+
+        let path_config = Arc::new(load_config(&config_path.to_string_lossy()).unwrap());
+        let (tx, rx) = mpsc::channel();
+
+        std::fs::create_dir(root.join("c")).unwrap();
+        tx.send(Ok(
+            Event::new(EventKind::Create(CreateKind::File)).add_path(root.join("~$sheet甲.xlsx"))
+        ))
+        .unwrap();
+        tx.send(Ok(
+            Event::new(EventKind::Create(CreateKind::Folder)).add_path(root.join("c"))
+        ))
+        .unwrap();
+        drop(tx);
+
+        let mut progress = WatchProgress {
+            started_at: Instant::now(),
+            first_result: None,
+            is_startup_notified: false,
+            status: StatusTracker::default(),
+            daily_check: DailyCheck::default(),
+            carried: None,
+        };
+        let _log = lock_global_log();
+        let outcome = watch_config(
+            path_config,
+            &config_path,
+            &mut notify::NullWatcher,
+            &rx,
+            &WatcherOptions::default(),
+            &mut progress,
+        )
+        .unwrap();
+
+        let WatchOutcome::Rearm(new_config) = outcome else {
+            panic!("the subfolder should rearm the watches");
+        };
+        let carried = progress.carried.as_ref().unwrap();
+
+        assert_eq!(new_config.settings.listened_directory.values().len(), 2);
+        assert!(carried.states.iter().any(|(source, state)| {
+            *source == root.to_string_lossy() && state.is_expected_hidden_file_opened
+        }));
+    }
+
+    #[test]
+    fn detection_transitions_are_reported_to_the_observer() {
+        #[derive(Default)]
+        struct RecordingObserver {
+            transitions: Mutex<Vec<String>>,
+        }
+
+        impl DetectionObserver for RecordingObserver {
+            fn observe(&self, source: &str, transition: &Transition) {
+                let name = match transition {
+                    Transition::Opened { file } => format!("opened {}", file),
+                    Transition::Modified { file, .. } => format!("modified {}", file),
+                    Transition::Closed { file } => format!("closed {}", file),
+                    Transition::Triggered { .. } => "triggered".to_string(),
+                    Transition::Succeeded { message, .. } => format!("succeeded {}", message),
+                    Transition::Failed { message, .. } => format!("failed {}", message),
+                };
+
+                self.transitions
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}: {}", source, name));
+            }
+        }
+
+        struct FailingRunner;
+
+        impl ScriptRunner for FailingRunner {
+            fn run(&self, _: &Settings, _: &[ScriptStep], _: &RunContext) -> RunResult {
+                RunResult {
+                    message: Some("exit code 1".to_string()),
+                    ..RunResult::default()
+                }
+            }
+        }
+
+        let path_config = sheet_config("debounce_ms = 0");
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+        let observer = Arc::new(RecordingObserver::default());
+        let runner: Arc<dyn ScriptRunner> = Arc::new(FailingRunner);
+        let (_, events) = sheet_edit_events();
+
+        targets[0].observer = observer.clone();
+        process_events(
+            &mut targets,
+            events.into_iter().map(Ok),
+            &path_config,
+            matcher.as_ref(),
+            &runner,
+        );
+        join_running(&mut targets);
+
+        assert_eq!(
+            *observer.transitions.lock().unwrap(),
+            [
+                "D:/sheets/sheet甲.xlsx: opened ~$sheet甲.xlsx",
+                "D:/sheets/sheet甲.xlsx: modified sheet甲.xlsx",
+                "D:/sheets/sheet甲.xlsx: closed ~$sheet甲.xlsx",
+                "D:/sheets/sheet甲.xlsx: triggered",
+                "D:/sheets/sheet甲.xlsx: failed exit code 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn post_close_delay_is_waited_before_the_script_runs() {
+        let path_config = sheet_config("debounce_ms = 0\npost_close_delay_ms = 200");
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+        let recorder = Arc::new(RecordingRunner::default());
+        let runner: Arc<dyn ScriptRunner> = recorder.clone();
+        let (sheet_path, events) = sheet_edit_events();
+
+        process_events(
+            &mut targets,
+            events.into_iter().map(Ok),
+            &path_config,
+            matcher.as_ref(),
+            &runner,
+        );
+        flush_pending_close(&mut targets[0], &path_config, matcher.as_ref(), &runner);
+
+        assert!(targets[0].state.pending_close.is_some());
+        assert!(targets[0].state.running.is_none());
+
+        targets[0].state.pending_close.as_mut().unwrap().stage = CloseStage::Delay(Instant::now());
+        flush_pending_close(&mut targets[0], &path_config, matcher.as_ref(), &runner);
+
+        assert!(targets[0].state.pending_close.is_none());
+
+        join_running(&mut targets);
+
+        assert_eq!(*recorder.files.lock().unwrap(), vec![Some(sheet_path)]);
+    }
+
+    #[test]
+    fn close_checks_wait_for_deadlines_without_blocking() {
+        let path_config = sheet_config("debounce_ms = 200\nstability_ms = 200");
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+        let recorder = Arc::new(RecordingRunner::default());
+        let runner: Arc<dyn ScriptRunner> = recorder.clone();
+        let (sheet_path, events) = sheet_edit_events();
+        let started_at = Instant::now();
+
+        process_events(
+            &mut targets,
+            events.into_iter().map(Ok),
+            &path_config,
+            matcher.as_ref(),
+            &runner,
+        );
+
+        assert!(started_at.elapsed() < Duration::from_millis(200));
+        assert!(matches!(
+            targets[0].state.pending_close.as_ref().unwrap().stage,
+            CloseStage::Debounce(_)
+        ));
+
+        let settled_at = Instant::now() - Duration::from_secs(1);
+
+        targets[0].state.last_modified_at = Some(settled_at);
+        targets[0].state.pending_close.as_mut().unwrap().stage = CloseStage::Debounce(settled_at);
+        flush_pending_close(&mut targets[0], &path_config, matcher.as_ref(), &runner);
+
+        let Some(CloseStage::Stability { stable_since, .. }) = targets[0]
+            .state
+            .pending_close
+            .as_mut()
+            .map(|pending| &mut pending.stage)
+        else {
+            panic!("the stability check should be pending");
+        };
+
+        *stable_since = settled_at;
+        flush_pending_close(&mut targets[0], &path_config, matcher.as_ref(), &runner);
+
+        assert!(targets[0].state.pending_close.is_none());
+
+        join_running(&mut targets);
+
+        assert_eq!(*recorder.files.lock().unwrap(), vec![Some(sheet_path)]);
+    }
+
+    #[test]
+    fn sweeps_ignore_changes_written_by_the_script() {
+        struct WritingRunner(AtomicUsize);
+
+        impl ScriptRunner for WritingRunner {
+            fn run(&self, _: &Settings, _: &[ScriptStep], context: &RunContext) -> RunResult {
+                let run_count = self.0.fetch_add(1, Ordering::SeqCst);
+
+                if let Some((path, _)) = &context.file {
+                    std::fs::write(path, format!("written by run {}", run_count)).unwrap();
+                }
+
+                RunResult {
+                    success: true,
+                    ..RunResult::default()
+                }
+            }
+        }
+
+        let folder = tempfile::tempdir().unwrap();
+        let sheet_path = folder.path().join("sheet甲.xlsx");
+        let path_config = Arc::new(
+            from_str::<PathConfig>(&format!(
+                r#"
+                [settings]
+                listened_directory = '{0}'
+                script_directory = '{0}'
+                sweep_interval_secs = 60
+                "#,
+                folder.path().display()
+            ))
+            .unwrap(),
+        );
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+        let writer = Arc::new(WritingRunner(AtomicUsize::new(0)));
+        let runner: Arc<dyn ScriptRunner> = writer.clone();
+
+        std::fs::write(&sheet_path, "edited").unwrap();
+        sweep_target(&mut targets[0], &path_config, matcher.as_ref(), &runner);
+        std::fs::write(&sheet_path, "edited again").unwrap();
+        sweep_target(&mut targets[0], &path_config, matcher.as_ref(), &runner);
+
+        while poll_run(&mut targets[0], &path_config, matcher.as_ref()).is_none() {
+            sleep(Duration::from_millis(10));
+        }
+
+        sweep_target(&mut targets[0], &path_config, matcher.as_ref(), &runner);
+
+        assert!(targets[0].state.running.is_none());
+        assert_eq!(writer.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn repeated_failures_pause_processing_for_the_cooldown() {
+        struct FailingRunner(AtomicUsize);
+
+        impl ScriptRunner for FailingRunner {
+            fn run(&self, _: &Settings, _: &[ScriptStep], _: &RunContext) -> RunResult {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                RunResult {
+                    has_run: true,
+                    ..RunResult::default()
+                }
+            }
+        }
+
+        let path_config = sheet_config("failure_threshold = 2");
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+        let failing = Arc::new(FailingRunner(AtomicUsize::new(0)));
+        let runner: Arc<dyn ScriptRunner> = failing.clone();
+        let target = &mut targets[0];
+
+        for _ in 0..3 {
+            run_target(target, &path_config, matcher.as_ref(), None, &runner);
+
+            while target.state.running.is_some() {
+                poll_run(target, &path_config, matcher.as_ref());
+                sleep(Duration::from_millis(10));
+            }
+        }
+
+        assert_eq!(failing.0.load(Ordering::SeqCst), 2);
+        assert!(target.state.cooldown_until.is_some());
+
+        target.state.is_run_queued = true;
+        start_queued_run(target, &path_config, matcher.as_ref(), &runner);
+
+        assert!(target.state.running.is_none());
+
+        target.state.cooldown_until = Some(Instant::now());
+        run_target(target, &path_config, matcher.as_ref(), None, &runner);
+        join_running(&mut targets);
+
+        assert_eq!(failing.0.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn without_hidden_files_the_visible_file_is_tracked() {
+        let folder = tempfile::tempdir().unwrap();
+
+        for filename in ["sheet甲.xlsx", "sheet乙.xlsx"] {
+            std::fs::write(folder.path().join(filename), "").unwrap();
+        }
+
+        let path_config = Arc::new(
+            from_str::<PathConfig>(&format!(
+                r#"
+                [settings]
+                listened_directory = '{0}'
+                script_directory = '{0}'
+                debounce_ms = 0
+                skip_unchanged = true
+                use_hidden_file = false
+
+                [settings.event_phases]
+                open = ["access"]
+                close = ["access_close_write"]
+                "#,
+                folder.path().display()
+            ))
+            .unwrap(),
+        );
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+        let recorder = Arc::new(RecordingRunner::default());
+        let runner: Arc<dyn ScriptRunner> = recorder.clone();
+        let older_path = folder.path().join("sheet甲.xlsx");
+        let sheet_path = folder.path().join("sheet乙.xlsx");
+        let opened = [
+            Event::new(EventKind::Access(AccessKind::Open(AccessMode::Any)))
+                .add_path(older_path.clone()),
+            Event::new(EventKind::Access(AccessKind::Close(AccessMode::Write)))
+                .add_path(older_path),
+            Event::new(EventKind::Access(AccessKind::Open(AccessMode::Any)))
+                .add_path(sheet_path.clone()),
+            Event::new(EventKind::Access(AccessKind::Close(AccessMode::Read)))
+                .add_path(sheet_path.clone()),
+        ];
+        let saved = [
+            Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                .add_path(sheet_path.clone()),
+            Event::new(EventKind::Access(AccessKind::Close(AccessMode::Write)))
+                .add_path(sheet_path.clone()),
+        ];
+
+        assert_eq!(
+            targets[0].expected_path(matcher.as_ref(), true),
+            Some(sheet_path.clone())
+        );
+        assert_eq!(targets[0].existing_lock_file(matcher.as_ref()), None);
+
+        process_events(
+            &mut targets,
+            opened.into_iter().map(Ok),
+            &path_config,
+            matcher.as_ref(),
+            &runner,
+        );
+        std::fs::write(&sheet_path, "saved").unwrap();
+        process_events(
+            &mut targets,
+            saved.into_iter().map(Ok),
+            &path_config,
+            matcher.as_ref(),
+            &runner,
+        );
+        join_running(&mut targets);
+
+        assert_eq!(*recorder.files.lock().unwrap(), vec![Some(sheet_path)]);
+    }
+
+    #[test]
+    fn trigger_file_mode_runs_once_per_matching_flag_file() {
+        let path_config = Arc::new(
+            from_str::<PathConfig>(
+                r#"
+                [settings]
+                listened_directory = "D:/sheets"
+                filename_prefix = ["sheet", "report"]
+                script_directory = "D:/scripts"
+                trigger_mode = "file"
+                trigger_file = "*.flag"
+                "#,
+            )
+            .unwrap(),
+        );
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+        let recorder = Arc::new(RecordingRunner::default());
+        let runner: Arc<dyn ScriptRunner> = recorder.clone();
+        let flag_path = PathBuf::from("D:/sheets/DONE.flag");
+        let events = [
+            Event::new(EventKind::Create(CreateKind::File))
+                .add_path("D:/sheets/sheet乙.xlsx".into()),
+            Event::new(EventKind::Create(CreateKind::File)).add_path("D:/other/DONE.flag".into()),
+            Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                .add_path(flag_path.clone()),
+            Event::new(EventKind::Create(CreateKind::File)).add_path(flag_path.clone()),
+        ];
+
+        assert_eq!(targets.len(), 1);
+
+        process_events(
+            &mut targets,
+            events.into_iter().map(Ok),
+            &path_config,
+            matcher.as_ref(),
+            &runner,
+        );
+        join_running(&mut targets);
+
+        assert_eq!(*recorder.files.lock().unwrap(), vec![Some(flag_path)]);
+    }
+
+    #[test]
+    fn env_vars_are_expanded_in_path_fields() {
+        let folder = tempfile::tempdir().unwrap();
+        let config_path = folder.path().join("path.toml");
+
+        std::env::set_var("SHEETWIZARD_TEST_HOME", folder.path());
+
+        std::fs::write(
+            &config_path,
+            r#"
+            [settings]
+            listened_directory = "%SHEETWIZARD_TEST_HOME%/sheets"
+            script_directory = "${SHEETWIZARD_TEST_HOME}/scripts"
+            "#,
+        )
+        .unwrap();
+
+        let path_config = load_config(&config_path.to_string_lossy()).unwrap();
+
+        assert_eq!(
+            path_config.settings.listened_directory.values(),
+            vec![format!("{}/sheets", folder.path().display())]
+        );
+        assert_eq!(
+            path_config.settings.script_directory,
+            format!("{}/scripts", folder.path().display())
+        );
+    }
+
+    #[test]
+    fn relative_paths_resolve_against_the_config_file() {
+        let folder = tempfile::tempdir().unwrap();
+        let config_path = folder.path().join("path.toml");
+
+        std::fs::write(
+            &config_path,
+            format!(
+                r#"
+                [settings]
+                listened_directory = "sheets"
+                script_directory = "{}"
+                "#,
+                folder.path().join("scripts").display()
+            ),
+        )
+        .unwrap();
+
+        let path_config = load_config(&config_path.to_string_lossy()).unwrap();
+
+        assert_eq!(
+            path_config.settings.listened_directory.values(),
+            vec![folder.path().join("sheets").to_string_lossy()]
+        );
+        assert_eq!(
+            path_config.settings.script_directory,
+            folder.path().join("scripts").to_string_lossy()
+        );
+    }
+
+    #[test]
+    fn max_depth_watches_each_subfolder_up_to_the_depth() {
+        let folder = tempfile::tempdir().unwrap();
+        let config_path = folder.path().join("path.toml");
+        let root = folder.path().join("sheets");
+
+        for subdirectory in ["b", "a/deep", "output"] {
+            std::fs::create_dir_all(root.join(subdirectory)).unwrap();
+        }
+
+        std::fs::write(
+            &config_path,
+            r#"
+            [settings]
+            listened_directory = "sheets"
+            script_directory = "scripts"
+            max_depth = 1
+            exclude_subdirectories = ["output"]
+            "#,
+        )
+        .unwrap();
+
+        let path_config = load_config(&config_path.to_string_lossy()).unwrap();
+        let settings = &path_config.settings;
+        let targets = build_watch_targets(&path_config);
+
+        assert_eq!(
+            settings.listened_directory.values(),
+            vec![
+                root.to_string_lossy(),
+                root.join("a").to_string_lossy(),
+                root.join("b").to_string_lossy(),
+            ]
+        );
+        assert!(targets
+            .iter()
+            .all(|target| target.watch_path(settings).1 == RecursiveMode::NonRecursive));
+        assert!(settings.is_excluded(&root.join("output").join("sheet甲.xlsx")));
+
+        std::fs::create_dir(root.join("c")).unwrap();
+        std::fs::write(root.join("sheet甲.xlsx"), "").unwrap();
+
+        let created = |path: PathBuf| Event::new(EventKind::Create(CreateKind::Any)).add_path(path);
+
+        assert!(is_subdirectory_change(settings, &created(root.join("c"))));
+        assert!(!is_subdirectory_change(
+            settings,
+            &created(root.join("sheet甲.xlsx"))
+        ));
+        assert!(is_subdirectory_change(
+            settings,
+            &Event::new(EventKind::Remove(RemoveKind::Folder)).add_path(root.join("b"))
+        ));
+    }
+
+    #[test]
+    fn config_with_a_bom_and_crlf_line_endings_loads() {
+        let folder = tempfile::tempdir().unwrap();
+        let config_path = folder.path().join("path.toml");
+
+        std::fs::write(
+            &config_path,
+            concat!(
+                "\u{feff}[settings]\r\n",
+                "listened_directory = \"sheets\"\r\n",
+                "script_directory = \"scripts\"\r\n",
+                "script_filename = \"report.py\"\r\n",
+            ),
+        )
+        .unwrap();
+
+        let path_config = load_config(&config_path.to_string_lossy()).unwrap();
+
+        assert_eq!(path_config.settings.script_filename(), "report.py");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn windows_interpreters_are_rejected_elsewhere() {
+        let folder = tempfile::tempdir().unwrap();
+        let config_path = folder.path().join("path.toml");
+        let load_with = |interpreter: &str| {
+            std::fs::write(
+                &config_path,
+                format!(
+                    "[settings]\nlistened_directory = \"sheets\"\nscript_directory = \"scripts\"\ninterpreter = \"{}\"\n",
+                    interpreter
+                ),
+            )
+            .unwrap();
+            load_config(&config_path.to_string_lossy())
+        };
+
+        assert!(load_with("cmd").is_err());
+        assert!(load_with("powershell").is_err());
+        assert_eq!(
+            load_with("python").unwrap().settings.command_template(),
+            "conda run -n {env} python \"{script_path}\" -m SheetWizard"
+        );
+    }
+
+    #[test]
+    fn script_path_is_split_into_directory_and_filename() {
+        let folder = tempfile::tempdir().unwrap();
+        let config_path = folder.path().join("path.toml");
+        let load_with = |script_settings: &str| {
+            std::fs::write(
+                &config_path,
+                format!(
+                    "[settings]\nlistened_directory = \"sheets\"\n{}\n",
+                    script_settings
+                ),
+            )
+            .unwrap();
+            load_config(&config_path.to_string_lossy())
+        };
+
+        let settings = load_with("script_path = \"scripts/report.py\"")
+            .unwrap()
+            .settings;
+
+        assert_eq!(
+            Path::new(&settings.script_directory),
+            folder.path().join("scripts")
+        );
+        assert_eq!(settings.script_filename(), "report.py");
+
+        for script_settings in [
+            "",
+            "script_path = \"scripts/report.py\"\nscript_directory = \"scripts\"",
+            "script_path = \"scripts/report.py\"\nscript_filename = \"main.py\"",
+        ] {
+            assert!(matches!(
+                load_with(script_settings),
+                Err(SheetWizardError::Config(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn events_in_excluded_subdirectories_are_recognized() {
+        let settings = from_str::<PathConfig>(
+            r#"
+            [settings]
+            listened_directory = ["D:/sheets", "D:/other"]
+            script_directory = "D:/scripts"
+            exclude_subdirectories = ["output", "archive/2024"]
+            "#,
+        )
+        .unwrap()
+        .settings;
+
+        assert!(settings.is_excluded(Path::new("D:/sheets/output/result.xlsx")));
+        assert!(settings.is_excluded(Path::new("D:/other/archive/2024/sheet甲.xlsx")));
+        assert!(!settings.is_excluded(Path::new("D:/sheets/sheet甲.xlsx")));
+        assert!(!settings.is_excluded(Path::new("D:/sheets/output2/sheet甲.xlsx")));
+        assert!(!settings.is_excluded(Path::new("D:/sheets/archive/sheet甲.xlsx")));
+    }
+
+    #[test]
+    fn an_invalid_reload_keeps_the_whole_previous_config() {
+        let folder = tempfile::tempdir().unwrap();
+        let config_path = folder.path().join("path.toml");
+
+        for directory in ["sheets", "other", "scripts"] {
+            create_dir_all(folder.path().join(directory)).unwrap();
+        }
+
+        let write_config = |directory: &str, extra: &str| {
+            std::fs::write(
+                &config_path,
+                format!(
+                    "[settings]\nlistened_directory = \"{}\"\nscript_directory = \"scripts\"\n{}\n",
+                    directory, extra
+                ),
+            )
+            .unwrap();
+        };
+
+        write_config("sheets", "filename_prefix = \"report\"");
+        let mut path_config = reload_config(&config_path).unwrap();
+
+        write_config(
+            "other",
+            "filename_prefix = \"sheet\"\nhidden_filename_prefix = \"sheet~\"",
+        );
+
+        if let Ok(new_config) = reload_config(&config_path) {
+            path_config = new_config;
+        }
+
+        assert_eq!(
+            path_config.settings.listened_directory.values(),
+            vec![folder.path().join("sheets").to_string_lossy()]
+        );
+        assert_eq!(
+            path_config.settings.filename_prefix.values(),
+            vec!["report"]
+        );
+
+        write_config("other", "filename_prefix = \"sheet\"");
+        path_config = reload_config(&config_path).unwrap();
+
+        assert_eq!(
+            path_config.settings.listened_directory.values(),
+            vec![folder.path().join("other").to_string_lossy()]
+        );
+        assert_eq!(path_config.settings.filename_prefix.values(), vec!["sheet"]);
+    }
+
+    #[test]
+    fn identical_or_overlapping_prefixes_are_rejected() {
+        let folder = tempfile::tempdir().unwrap();
+        let config_path = folder.path().join("path.toml");
+
+        for (hidden_prefix, expected) in [("sheet", "is the same as"), ("sheet~", "overlap")] {
+            std::fs::write(
+                &config_path,
+                format!(
+                    r#"
+                    [settings]
+                    listened_directory = "sheets"
+                    script_directory = "scripts"
+                    filename_prefix = "sheet"
+                    hidden_filename_prefix = "{}"
+                    "#,
+                    hidden_prefix
+                ),
+            )
+            .unwrap();
+
+            match load_config(&config_path.to_string_lossy()) {
+                Err(SheetWizardError::Config(message)) => assert!(message.contains(expected)),
+                _ => panic!("{} should have been rejected", hidden_prefix),
+            }
+        }
+    }
+
+    #[test]
+    fn whitespace_around_config_strings_is_trimmed() {
+        let folder = tempfile::tempdir().unwrap();
+        let config_path = folder.path().join("path.toml");
+
+        std::fs::write(folder.path().join("sheet甲.xlsx"), "").unwrap();
+        std::fs::write(
+            &config_path,
+            format!(
+                r#"
+                [settings]
+                listened_directory = "{} "
+                script_directory = " scripts"
+                filename_prefix = " sheet"
+                ext_name = "xlsx "
+                "#,
+                folder.path().display()
+            ),
+        )
+        .unwrap();
+
+        let path_config = load_config(&config_path.to_string_lossy()).unwrap();
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let queries = path_config.settings.file_queries();
+
+        assert_eq!(queries[0].hidden_filename_prefix, "~$sheet");
+        assert_eq!(
+            get_filename_with_largest_tiangan(&queries[0], matcher.as_ref(), false),
+            Some(folder.path().join("sheet甲.xlsx"))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn default_template_finds_the_script_from_the_working_directory() {
+        let folder = tempfile::tempdir().unwrap();
+        let script_directory = folder.path().join("scripts");
+        let working_directory = folder.path().join("output");
+        let mut settings = from_str::<PathConfig>(&format!(
+            r#"
+            [settings]
+            listened_directory = '{0}'
+            script_directory = '{0}'
+            script_working_directory = '{1}'
+            env_name = "working-directory-test"
+            "#,
+            script_directory.display(),
+            working_directory.display()
+        ))
+        .unwrap()
+        .settings;
+
+        std::fs::create_dir(&script_directory).unwrap();
+        std::fs::create_dir(&working_directory).unwrap();
+        std::fs::write(script_directory.join("main.py"), "echo ran > ran.txt\n").unwrap();
+
+        let _log = lock_global_log();
+
+        *PYTHON_EXECUTABLES.lock().unwrap() =
+            vec![("working-directory-test".to_string(), "sh".to_string())];
+
+        let fast_result = process_sheet(&settings, &[test_step("main.py")], &test_context());
+
+        PYTHON_EXECUTABLES.lock().unwrap().clear();
+
+        assert!(fast_result.success);
+        assert!(working_directory.join("ran.txt").is_file());
+        assert!(!script_directory.join("ran.txt").exists());
+
+        remove_file(working_directory.join("ran.txt")).unwrap();
+        settings.command_template = Some(
+            Interpreter::Python
+                .command_template()
+                .replace("conda run -n {env} python", "sh"),
+        );
+
+        assert!(process_sheet(&settings, &[test_step("main.py")], &test_context()).success);
+        assert!(working_directory.join("ran.txt").is_file());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sentinel_exit_code_reruns_the_script_up_to_max_reruns() {
+        let folder = tempfile::tempdir().unwrap();
+        let settings = script_settings(folder.path(), "max_reruns = 2");
+        let step = test_step("count.sh");
+        let context = test_context();
+        let write_script = |runs_needed: usize| {
+            std::fs::write(
+                folder.path().join("count.sh"),
+                format!(
+                    "echo run >> runs.txt\n[ $(wc -l < runs.txt) -ge {} ] || exit 75\n",
+                    runs_needed
+                ),
+            )
+            .unwrap();
+            let _ = remove_file(folder.path().join("runs.txt"));
+        };
+        let run_count = || {
+            read_to_string(folder.path().join("runs.txt"))
+                .unwrap()
+                .lines()
+                .count()
+        };
+
+        write_script(3);
+        let outcome = run_script_with_retry(&settings, &step, &context);
+
+        assert!(outcome.success);
+        assert_eq!(run_count(), 3);
+
+        write_script(4);
+        let outcome = run_script_with_retry(&settings, &step, &context);
+
+        assert!(!outcome.success);
+        assert!(matches!(
+            outcome.failure,
+            Some(ScriptFailure::TooManyReruns(2))
+        ));
+        assert_eq!(run_count(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timed_out_scripts_are_killed_with_their_children() {
+        let folder = tempfile::tempdir().unwrap();
+        let settings = script_settings(folder.path(), "script_timeout_secs = 1");
+        let step = test_step("fork.sh");
+        let context = test_context();
+
+        std::fs::write(folder.path().join("fork.sh"), "sleep 30 &\nwait\n").unwrap();
+
+        let started_at = Instant::now();
+        let outcome = run_script(&settings, &step, &context);
+
+        assert!(matches!(outcome.failure, Some(ScriptFailure::TimedOut(_))));
+        assert!(started_at.elapsed() < Duration::from_secs(10));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_held_off_by_another_process_are_skipped() {
+        let folder = tempfile::tempdir().unwrap();
+        let settings = script_settings(folder.path(), "max_retries = 2");
+        let step = test_step("touch.sh");
+        let context = test_context();
+        let held = File::create(folder.path().join(".sheetwizard.lock")).unwrap();
+
+        held.try_lock().unwrap();
+        std::fs::write(
+            folder.path().join("touch.sh"),
+            format!("touch '{}'\n", folder.path().join("ran").display()),
+        )
+        .unwrap();
+
+        let result = process_sheet(&settings, std::slice::from_ref(&step), &context);
+
+        assert!(!result.has_run);
+        assert!(result.message.is_none());
+        assert!(!folder.path().join("ran").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failure_templates_can_show_the_exit_code() {
+        let folder = tempfile::tempdir().unwrap();
+        let settings = script_settings(
+            folder.path(),
+            r#"failure_template = "{source} exited with {exit_code}""#,
+        );
+        let step = test_step("fail.sh");
+        let context = test_context();
+
+        std::fs::write(folder.path().join("fail.sh"), "exit 3\n").unwrap();
+
+        let result = process_sheet(&settings, std::slice::from_ref(&step), &context);
+
+        assert_eq!(result.message.as_deref(), Some("test exited with 3"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_placeholder_is_kept_as_an_alias_of_script_path() {
+        let folder = tempfile::tempdir().unwrap();
+        let mut settings = script_settings(folder.path(), "");
+
+        settings.command_template = Some("sh {file}".to_string());
+        let step = test_step("ok.sh");
+        let context = test_context();
+
+        std::fs::write(folder.path().join("ok.sh"), "exit 0\n").unwrap();
+
+        assert!(process_sheet(&settings, std::slice::from_ref(&step), &context).success);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn result_notifications_are_deduplicated_without_the_duration() {
+        let folder = tempfile::tempdir().unwrap();
+        let settings = script_settings(folder.path(), "");
+        let step = test_step("ok.sh");
+        let context = test_context();
+
+        std::fs::write(folder.path().join("ok.sh"), "exit 0\n").unwrap();
+
+        let result = process_sheet(&settings, std::slice::from_ref(&step), &context);
+
+        assert_eq!(
+            result.dedupe_key.as_deref(),
+            Some("test: Processed successfully in {duration}.")
+        );
+        assert!(!result.message.unwrap().contains("{duration}"));
+    }
+
+    #[test]
+    fn script_output_with_invalid_sequences_is_decoded_lossily() {
+        assert_eq!(
+            decode_output("完成 ok".as_bytes(), OutputEncoding::Utf8),
+            "完成 ok"
+        );
+        assert_eq!(
+            decode_output(b"ok \xff\xfe", OutputEncoding::Utf8),
+            "ok \u{fffd}\u{fffd}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_as_users_are_looked_up_by_name() {
+        let root = lookup_user("root").unwrap();
+
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert_eq!(root.home, Path::new("/root"));
+        assert!(root.groups.contains(&0));
+        assert_eq!(
+            lookup_user("sheetwizard-no-such-user")
+                .err()
+                .map(|e| e.kind()),
+            Some(io::ErrorKind::NotFound)
+        );
+    }
+
+    #[test]
+    fn notification_strings_follow_the_language() {
+        let settings_for = |language: &str| {
+            from_str::<PathConfig>(&format!(
+                r#"
+                [settings]
+                listened_directory = "sheets"
+                script_directory = "scripts"
+                language = "{}"
+                "#,
+                language
+            ))
+            .unwrap()
+            .settings
+        };
+
+        assert_eq!(settings_for("zh").notification_title(), "表格精灵");
+        assert_eq!(settings_for("en").notification_title(), "Sheet Wizard");
+        assert_eq!(
+            settings_for("fr").success_template(),
+            "{source}: Processed successfully in {duration}."
+        );
+        assert_eq!(format_duration(Duration::from_millis(4240)), "4.2s");
+        assert_eq!(format_duration(Duration::from_secs(125)), "2m 5s");
+    }
+
+    #[test]
+    fn only_unrecoverable_watcher_errors_are_fatal() {
+        assert!(is_fatal_watcher_error(&notify::Error::generic(
+            "inotify stopped"
+        )));
+        assert!(is_fatal_watcher_error(&notify::Error::new(
+            NotifyErrorKind::MaxFilesWatch
+        )));
+        assert!(!is_fatal_watcher_error(&notify::Error::path_not_found()));
+        assert!(!is_fatal_watcher_error(&notify::Error::io(
+            std::io::ErrorKind::NotFound.into()
+        )));
+    }
+
+    #[test]
+    fn unresolved_env_vars_are_rejected() {
+        assert!(expand_env_vars("%SHEETWIZARD_TEST_MISSING%/sheets").is_err());
+        assert!(expand_env_vars("${SHEETWIZARD_TEST_MISSING}").is_err());
+        assert_eq!(expand_env_vars("100% done").unwrap(), "100% done");
+        assert_eq!(expand_env_vars("cost $5").unwrap(), "cost $5");
+    }
+
+    #[test]
+    fn close_is_logged_with_the_closed_filename() {
+        let folder = tempfile::tempdir().unwrap();
+        let sheet_path = folder.path().join("sheet甲.xlsx");
+        let hidden_path = folder.path().join("~$sheet甲.xlsx");
+        let log_path = folder.path().join("sw.log");
+
+        std::fs::write(&sheet_path, "").unwrap();
+
+        let path_config = Arc::new(
+            from_str::<PathConfig>(&format!(
+                r#"
+                [settings]
+                listened_directory = '{0}'
+                script_directory = '{0}'
+                debounce_ms = 0
+                log_path = '{1}'
+                log_level = 'debug'
+                "#,
+                folder.path().display(),
+                log_path.display()
+            ))
+            .unwrap(),
+        );
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+        let _log = lock_global_log();
+
+        init_file_log(&path_config.settings);
+
+        for event in [
+            Event::new(EventKind::Create(CreateKind::File)).add_path(hidden_path.clone()),
+            Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                .add_path(sheet_path.clone()),
+            Event::new(EventKind::Remove(RemoveKind::File)).add_path(hidden_path.clone()),
+        ] {
+            handle_event(
+                &mut targets[0],
+                &event,
+                &path_config,
+                matcher.as_ref(),
+                &process_runner(),
+            );
+        }
+
+        join_running(&mut targets);
+
+        let log = read_to_string(&log_path).unwrap();
+
+        assert!(log.contains("~$sheet甲.xlsx closed"));
+        assert!(!log.contains("  closed"));
+    }
+
+    #[test]
+    fn failed_toasts_fall_back_to_the_log() {
+        let folder = tempfile::tempdir().unwrap();
+        let log_path = folder.path().join("sw.log");
+        let settings = from_str::<PathConfig>(&format!(
+            r#"
+            [settings]
+            listened_directory = '{0}'
+            script_directory = '{0}'
+            log_path = '{1}'
+            "#,
+            folder.path().display(),
+            log_path.display()
+        ))
+        .unwrap()
+        .settings;
+        let toast = ToastOptions {
+            title: "Sheet Wizard".to_string(),
+            duration: NotificationDuration::default(),
+            app_id: None,
+            icon_path: None,
+        };
+        let _log = lock_global_log();
+
+        init_file_log(&settings);
+        report_toast_result(&toast, "shown", NotificationFallback::Log, Ok(()));
+        report_toast_result(
+            &toast,
+            "kept",
+            NotificationFallback::Log,
+            Err("no session".to_string()),
+        );
+        report_toast_result(
+            &toast,
+            "dropped",
+            NotificationFallback::None,
+            Err("no session".to_string()),
+        );
+
+        let log = read_to_string(&log_path).unwrap();
+
+        assert!(log.contains("NOTIFY"));
+        assert!(log.contains("Sheet Wizard: kept"));
+        assert!(!log.contains("Sheet Wizard: shown"));
+        assert!(!log.contains("Sheet Wizard: dropped"));
+        assert_eq!(log.matches("Failed to show toast notification").count(), 2);
+    }
+
+    #[test]
+    fn handle_close_detection_triggers_when_the_lock_is_released() {
+        let folder = tempfile::tempdir().unwrap();
+        let sheet_path = folder.path().join("sheet甲.xlsx");
+        let hidden_path = folder.path().join("~$sheet甲.xlsx");
+
+        std::fs::write(&sheet_path, "").unwrap();
+
+        let path_config = Arc::new(
+            from_str::<PathConfig>(&format!(
+                r#"
+                [settings]
+                listened_directory = '{0}'
+                script_directory = '{0}'
+                debounce_ms = 0
+                close_detection = "handle"
+                "#,
+                folder.path().display()
+            ))
+            .unwrap(),
+        );
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+
+        for event in [
+            Event::new(EventKind::Create(CreateKind::File)).add_path(hidden_path.clone()),
+            Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                .add_path(sheet_path.clone()),
+            Event::new(EventKind::Remove(RemoveKind::File)).add_path(hidden_path.clone()),
+        ] {
+            handle_event(
+                &mut targets[0],
+                &event,
+                &path_config,
+                matcher.as_ref(),
+                &process_runner(),
+            );
+        }
+
+        assert!(targets[0].state.is_expected_hidden_file_opened);
+
+        let holder = File::open(&sheet_path).unwrap();
+
+        holder.lock().unwrap();
+        check_file_handle(
+            &mut targets[0],
+            &path_config,
+            matcher.as_ref(),
+            &process_runner(),
+        );
+
+        assert!(targets[0].state.is_file_locked);
+        assert!(targets[0].state.running.is_none());
+
+        drop(holder);
+        check_file_handle(
+            &mut targets[0],
+            &path_config,
+            matcher.as_ref(),
+            &process_runner(),
+        );
+
+        assert!(targets[0].state.is_file_locked);
+
+        targets[0].state.handle_checked_at = None;
+        check_file_handle(
+            &mut targets[0],
+            &path_config,
+            matcher.as_ref(),
+            &process_runner(),
+        );
+
+        assert!(!targets[0].state.is_expected_hidden_file_opened);
+        assert!(targets[0].state.running.is_some());
+
+        join_running(&mut targets);
+    }
+
+    #[test]
+    fn json_log_lines_carry_the_event_fields() {
+        set_correlation_id(Some("run-1".to_string()));
+
+        let line = format_log_line(
+            LogFormat::Json,
+            "INFO",
+            "script_finished",
+            "Executed script successfully",
+            json!({ "script": "main.py", "exit_code": 0 }),
+        );
+
+        set_correlation_id(None);
+
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+        assert!(!line.contains('\n'));
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(record["event"], "script_finished");
+        assert_eq!(record["message"], "Executed script successfully");
+        assert_eq!(record["correlation_id"], "run-1");
+        assert_eq!(record["script"], "main.py");
+        assert_eq!(record["exit_code"], 0);
+        assert!(record["timestamp"].is_string());
+    }
+
+    #[test]
+    fn active_hours_cover_weekdays_and_overnight_windows() {
+        let business = ActiveHours {
+            start: "08:30".to_string(),
+            end: "18:00".to_string(),
+            weekdays: vec![Weekday::Mon, Weekday::Fri],
+            outside: OutsideHours::Defer,
+        };
+        let overnight = ActiveHours {
+            start: "22:00".to_string(),
+            end: "06:00".to_string(),
+            weekdays: Vec::new(),
+            outside: OutsideHours::Skip,
+        };
+
+        assert!(business.contains(1, 8 * 60 + 30));
+        assert!(!business.contains(1, 18 * 60));
+        assert!(!business.contains(3, 12 * 60));
+        assert!(overnight.contains(0, 23 * 60));
+        assert!(overnight.contains(2, 5 * 60));
+        assert!(!overnight.contains(2, 12 * 60));
+        assert_eq!(parse_clock_time("24:00"), None);
+        assert_eq!(parse_clock_time("7:05"), Some(7 * 60 + 5));
+        assert!(ActiveHours {
+            start: "09:00".to_string(),
+            end: "09:00".to_string(),
+            weekdays: Vec::new(),
+            outside: OutsideHours::Skip,
+        }
+        .window()
+        .is_err());
+    }
+
+    #[test]
+    fn metrics_count_results_and_average_their_duration() {
+        let mut metrics = Metrics::default();
+
+        for (success, timed_out, duration_ms) in [(true, false, 100), (false, true, 300)] {
+            metrics.record(&RunResult {
+                success,
+                has_run: true,
+                timed_out,
+                duration: Duration::from_millis(duration_ms),
+                message: None,
+                dedupe_key: None,
+            });
+        }
+
+        metrics.record(&RunResult::default());
+
+        assert_eq!(
+            json!(metrics.summary()),
+            json!({
+                "events_observed": 0,
+                "files_processed": 2,
+                "successes": 1,
+                "failures": 1,
+                "timeouts": 1,
+                "average_duration_ms": 200,
+            })
+        );
+    }
+
+    #[test]
+    fn history_lists_the_last_records_as_a_table() {
+        let folder = tempfile::tempdir().unwrap();
+        let history = FileLog {
+            path: folder.path().join("history.jsonl"),
+            max_bytes: 1024 * 1024,
+            max_files: 1,
+        };
+        let history_path = history.path.to_string_lossy();
+
+        assert!(read_history_records(&history_path, 10).is_empty());
+
+        for (index, success) in [true, false, true].into_iter().enumerate() {
+            append_history(
+                &history,
+                &HistoryRecord {
+                    timestamp: format!("2024-01-0{}T08:00:00Z", index + 1),
+                    source: "D:/sheets".to_string(),
+                    file: (index > 0).then(|| PathBuf::from(format!("sheet{}.xlsx", index))),
+                    version: None,
+                    success,
+                    exit_code: success.then_some(0).or(Some(2)),
+                    duration_ms: 10,
+                },
+            )
+            .unwrap();
+        }
+
+        let records = read_history_records(&history_path, 2);
+
+        assert_eq!(
+            format_history_table(&records),
+            concat!(
+                "TIME                  FILE         RESULT  EXIT CODE\n",
+                "2024-01-02T08:00:00Z  sheet1.xlsx  failed  2\n",
+                "2024-01-03T08:00:00Z  sheet2.xlsx  ok      0",
+            )
+        );
+        assert_eq!(
+            read_last_history_record(&history_path).map(|record| record.timestamp),
+            Some("2024-01-03T08:00:00Z".to_string())
+        );
+    }
+
+    #[test]
+    fn history_is_rotated_and_read_across_the_rotation() {
+        let folder = tempfile::tempdir().unwrap();
+        let history = FileLog {
+            path: folder.path().join("history.jsonl"),
+            max_bytes: 1,
+            max_files: 1,
+        };
+        let history_path = history.path.to_string_lossy();
+
+        for index in 1..=3 {
+            append_history(
+                &history,
+                &HistoryRecord {
+                    timestamp: format!("2024-01-0{}T08:00:00Z", index),
+                    source: "D:/sheets".to_string(),
+                    file: None,
+                    version: Some(index),
+                    success: true,
+                    exit_code: Some(0),
+                    duration_ms: 10,
+                },
+            )
+            .unwrap();
+        }
+
+        let timestamps: Vec<String> = read_history_records(&history_path, 10)
+            .into_iter()
+            .map(|record| record.timestamp)
+            .collect();
+
+        assert_eq!(timestamps, ["2024-01-02T08:00:00Z", "2024-01-03T08:00:00Z"]);
+        assert!(!folder.path().join("history.jsonl.2").exists());
+    }
+
+    #[test]
+    fn archive_placeholders_are_filled_in() {
+        let now = UNIX_EPOCH + Duration::from_secs(1709622489);
+
+        assert_eq!(
+            archive_path(
+                "D:/archive/{date}",
+                "{stem}_{version}_{time}.{ext}",
+                Path::new("D:/sheets/sheet丙.xlsx"),
+                Some(2),
+                now,
+            ),
+            Path::new("D:/archive/2024-03-05").join("sheet丙_2_070809.xlsx")
+        );
+        assert_eq!(
+            archive_path("out", "{filename}", Path::new("a.xlsx"), None, now),
+            Path::new("out").join("a.xlsx")
+        );
+    }
+
+    #[test]
+    fn close_can_be_mapped_to_a_second_modify() {
+        let folder = tempfile::tempdir().unwrap();
+        let sheet_path = folder.path().join("sheet甲.xlsx");
+        let hidden_path = folder.path().join("~$sheet甲.xlsx");
+
+        std::fs::write(&sheet_path, "").unwrap();
+
+        let path_config = Arc::new(
+            from_str::<PathConfig>(&format!(
+                r#"
+                [settings]
+                listened_directory = '{0}'
+                script_directory = '{0}'
+                debounce_ms = 0
+
+                [settings.event_phases]
+                close = ["modify_data"]
+                "#,
+                folder.path().display()
+            ))
+            .unwrap(),
+        );
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+        let modify =
+            Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any))).add_path(sheet_path);
+
+        for event in [
+            Event::new(EventKind::Create(CreateKind::File)).add_path(hidden_path.clone()),
+            modify.clone(),
+            Event::new(EventKind::Remove(RemoveKind::File)).add_path(hidden_path),
+        ] {
+            handle_event(
+                &mut targets[0],
+                &event,
+                &path_config,
+                matcher.as_ref(),
+                &process_runner(),
+            );
+        }
+
+        assert!(targets[0].state.is_expected_hidden_file_opened);
+        assert!(targets[0].state.running.is_none());
+
+        handle_event(
+            &mut targets[0],
+            &modify,
+            &path_config,
+            matcher.as_ref(),
+            &process_runner(),
+        );
+
+        assert!(!targets[0].state.is_expected_hidden_file_opened);
+        assert!(targets[0].state.running.is_some());
+
+        join_running(&mut targets);
+    }
+
+    #[test]
+    fn a_burst_of_new_versions_is_processed_once_after_the_batch_window() {
+        let folder = tempfile::tempdir().unwrap();
+        let path_config = Arc::new(
+            from_str::<PathConfig>(&format!(
+                r#"
+                [settings]
+                listened_directory = '{0}'
+                script_directory = '{0}'
+                batch_window_secs = 60
+                "#,
+                folder.path().display()
+            ))
+            .unwrap(),
+        );
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+
+        for filename in ["sheet甲.xlsx", "sheet乙.xlsx", "sheet丙.xlsx"] {
+            let path = folder.path().join(filename);
+
+            std::fs::write(&path, "").unwrap();
+            handle_event(
+                &mut targets[0],
+                &Event::new(EventKind::Create(CreateKind::File)).add_path(path),
+                &path_config,
+                matcher.as_ref(),
+                &process_runner(),
+            );
+            flush_batch(
+                &mut targets[0],
+                &path_config,
+                matcher.as_ref(),
+                &process_runner(),
+            );
+        }
+
+        assert!(targets[0].state.batch_deadline.is_some());
+        assert!(targets[0].state.running.is_none());
+
+        targets[0].state.batch_deadline = Some(Instant::now());
+        flush_batch(
+            &mut targets[0],
+            &path_config,
+            matcher.as_ref(),
+            &process_runner(),
+        );
+
+        assert!(targets[0].state.batch_deadline.is_none());
+        assert!(targets[0].state.running.is_some());
+        assert_eq!(
+            targets[0].expected_path(matcher.as_ref(), false),
+            Some(folder.path().join("sheet丙.xlsx"))
+        );
+
+        join_running(&mut targets);
+    }
+
+    #[test]
+    fn event_trigger_target_processes_an_older_edited_version() {
+        let folder = tempfile::tempdir().unwrap();
+        let sheet_path = folder.path().join("sheet甲.xlsx");
+        let hidden_path = folder.path().join("~$sheet甲.xlsx");
+
+        std::fs::write(&sheet_path, "").unwrap();
+        std::fs::write(folder.path().join("sheet乙.xlsx"), "").unwrap();
+
+        let events = [
+            Event::new(EventKind::Create(CreateKind::File)).add_path(hidden_path.clone()),
+            Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                .add_path(sheet_path.clone()),
+            Event::new(EventKind::Remove(RemoveKind::File)).add_path(hidden_path.clone()),
+        ];
+
+        for (trigger_target, is_triggered) in [("latest", false), ("event", true)] {
+            let path_config = Arc::new(
+                from_str::<PathConfig>(&format!(
+                    r#"
+                    [settings]
+                    listened_directory = '{0}'
+                    script_directory = '{0}'
+                    debounce_ms = 0
+                    trigger_target = "{1}"
+                    "#,
+                    folder.path().display(),
+                    trigger_target
+                ))
+                .unwrap(),
+            );
+            let matcher = build_version_matcher(&path_config.settings).unwrap();
+            let mut targets = build_watch_targets(&path_config);
+
+            for event in &events {
+                handle_event(
+                    &mut targets[0],
+                    event,
+                    &path_config,
+                    matcher.as_ref(),
+                    &process_runner(),
+                );
+            }
+
+            assert_eq!(targets[0].state.running.is_some(), is_triggered);
+            assert!(targets[0].state.triggered_file.is_none());
+
+            join_running(&mut targets);
+        }
+    }
+
+    #[test]
+    fn scripts_are_routed_by_the_extension_of_the_file() {
+        let path_config = from_str::<PathConfig>(
+            r#"
+            [settings]
+            listened_directory = "D:/sheets"
+            script_directory = "D:/scripts"
+            ext_name = ["xlsx", "csv"]
+
+            [settings.ext_scripts]
+            xlsx = "data.py"
+            ".CSV" = "export.py"
+            "#,
+        )
+        .unwrap();
+        let targets = build_watch_targets(&path_config);
+        let settings = &path_config.settings;
+        let filename_for = |path: &str| {
+            targets[0]
+                .steps_for(settings, Some(Path::new(path)))
+                .map(|steps| steps[0].filename.clone())
+        };
+
+        assert_eq!(
+            filename_for("D:/sheets/sheet甲.xlsx").as_deref(),
+            Some("data.py")
+        );
+        assert_eq!(
+            filename_for("D:/sheets/sheet甲.csv").as_deref(),
+            Some("export.py")
+        );
+        assert_eq!(filename_for("D:/sheets/sheet甲.xlsm"), None);
+
+        let path_config = from_str::<PathConfig>(
+            r#"
+            [settings]
+            listened_directory = "D:/sheets"
+            script_directory = "D:/scripts"
+
+            [settings.ext_scripts]
+            xlsx = "data.py"
+
+            [[file]]
+            path = "D:/sheets/sheet甲.xlsx"
+
+            [[file.scripts]]
+            filename = "own.py"
+            "#,
+        )
+        .unwrap();
+        let targets = build_watch_targets(&path_config);
+
+        assert_eq!(
+            targets[0]
+                .steps_for(
+                    &path_config.settings,
+                    Some(Path::new("D:/sheets/sheet甲.xlsx"))
+                )
+                .map(|steps| steps[0].filename.clone())
+                .as_deref(),
+            Some("own.py")
+        );
+    }
+
+    #[test]
+    fn daily_reset_reports_directories_without_the_day_token() {
+        let folder = tempfile::tempdir().unwrap();
+
+        std::fs::write(folder.path().join("sheet戊午.xlsx"), "").unwrap();
+
+        let path_config = from_str::<PathConfig>(&format!(
+            r#"
+            [settings]
+            listened_directory = '{0}'
+            script_directory = '{0}'
+            daily_reset = true
+            "#,
+            folder.path().display()
+        ))
+        .unwrap();
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let targets = build_watch_targets(&path_config);
+
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 1, 1), 10957);
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+        assert!(targets_missing_token(&targets, matcher.as_ref(), &day_ganzhi(10957)).is_empty());
+        assert_eq!(
+            targets_missing_token(&targets, matcher.as_ref(), &day_ganzhi(10958)),
+            vec![folder.path().display().to_string()]
+        );
+    }
+
+    #[test]
+    fn versions_below_min_version_are_not_processed() {
+        let folder = tempfile::tempdir().unwrap();
+
+        std::fs::write(folder.path().join("sheet乙.xlsx"), "").unwrap();
+
+        for (min_version, is_triggered) in [("丙", false), ("乙", true)] {
+            let path_config = Arc::new(
+                from_str::<PathConfig>(&format!(
+                    r#"
+                    [settings]
+                    listened_directory = '{0}'
+                    script_directory = '{0}'
+                    min_version = "{1}"
+                    "#,
+                    folder.path().display(),
+                    min_version
+                ))
+                .unwrap(),
+            );
+            let matcher = build_version_matcher(&path_config.settings).unwrap();
+            let mut targets = build_watch_targets(&path_config);
+
+            run_target(
+                &mut targets[0],
+                &path_config,
+                matcher.as_ref(),
+                None,
+                &process_runner(),
+            );
+
+            assert_eq!(targets[0].state.running.is_some(), is_triggered);
+
+            join_running(&mut targets);
+        }
+
+        let mut settings = from_str::<PathConfig>(
+            r#"
+            [settings]
+            listened_directory = "D:/sheets"
+            script_directory = "D:/scripts"
+            min_version = "draft"
+            "#,
+        )
+        .unwrap()
+        .settings;
+        let matcher = build_version_matcher(&settings).unwrap();
+
+        assert!(min_version_index(&settings, matcher.as_ref()).is_err());
+
+        settings.min_version = Some("3".to_string());
+
+        assert_eq!(min_version_index(&settings, &NumericMatcher), Ok(Some(3)));
+    }
+
+    #[test]
+    fn changes_during_the_startup_grace_period_do_not_trigger() {
+        let folder = tempfile::tempdir().unwrap();
+        let sheet_path = folder.path().join("sheet甲.xlsx");
+        let hidden_path = folder.path().join("~$sheet甲.xlsx");
+
+        std::fs::write(&sheet_path, "").unwrap();
+        std::fs::write(&hidden_path, "").unwrap();
+
+        let path_config = Arc::new(
+            from_str::<PathConfig>(&format!(
+                r#"
+                [settings]
+                listened_directory = '{0}'
+                script_directory = '{0}'
+                debounce_ms = 0
+                "#,
+                folder.path().display()
+            ))
+            .unwrap(),
+        );
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+        let events = [
+            Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+                .add_path(sheet_path.clone()),
+            Event::new(EventKind::Remove(RemoveKind::File)).add_path(hidden_path.clone()),
+        ];
+
+        start_grace_period(
+            &mut targets[0],
+            &path_config.settings,
+            matcher.as_ref(),
+            Instant::now() + Duration::from_secs(60),
+        );
+
+        assert!(targets[0].state.is_expected_hidden_file_opened);
+
+        for event in &events {
+            handle_event(
+                &mut targets[0],
+                event,
+                &path_config,
+                matcher.as_ref(),
+                &process_runner(),
+            );
+        }
+
+        assert!(!targets[0].state.is_expected_hidden_file_opened);
+        assert!(targets[0].state.running.is_none());
+
+        targets[0].state.grace_until = Some(Instant::now());
+
+        for event in [
+            Event::new(EventKind::Create(CreateKind::File)).add_path(hidden_path.clone()),
+            events[0].clone(),
+            events[1].clone(),
+        ] {
+            handle_event(
+                &mut targets[0],
+                &event,
+                &path_config,
+                matcher.as_ref(),
+                &process_runner(),
+            );
+        }
+
+        assert!(targets[0].state.running.is_some());
+
+        join_running(&mut targets);
+    }
+
+    #[test]
+    fn write_access_covers_the_working_and_archive_directories() {
+        let folder = tempfile::tempdir().unwrap();
+        let archive_directory = folder.path().join("archive").join("2026");
+        let settings = from_str::<PathConfig>(&format!(
+            r#"
+            [settings]
+            listened_directory = '{0}'
+            script_directory = '{0}'
+            script_working_directory = '{0}'
+            archive_directory = '{1}'
+            "#,
+            folder.path().display(),
+            archive_directory.display()
+        ))
+        .unwrap()
+        .settings;
+        let directories = settings.writable_directories();
+
+        assert_eq!(
+            directories,
+            vec![
+                folder.path().to_str().unwrap(),
+                archive_directory.to_str().unwrap()
+            ]
+        );
+        assert!(directories
+            .iter()
+            .all(|directory| check_write_access(directory).is_ok()));
+        assert!(!archive_directory.exists());
+    }
+
+    #[test]
+    fn script_lock_is_exclusive_and_reclaims_stale_locks() {
+        let folder = tempfile::tempdir().unwrap();
+        let directory = folder.path().to_string_lossy().to_string();
+
+        std::fs::write(folder.path().join(".sheetwizard.lock"), "4242").unwrap();
+
+        let lock = acquire_script_lock(&directory).unwrap();
+
+        assert!(lock.is_some());
+        assert!(acquire_script_lock(&directory).is_err());
+
+        drop(lock);
+
+        assert!(acquire_script_lock(&directory).unwrap().is_some());
+
+        let shared = acquire_shared_script_lock(&directory).unwrap();
+
+        assert!(acquire_shared_script_lock(&directory).is_ok());
+        assert!(acquire_script_lock(&directory).is_err());
+
+        let other_folder = tempfile::tempdir().unwrap();
+        let other = acquire_shared_script_lock(&other_folder.path().to_string_lossy()).unwrap();
+
+        assert!(acquire_shared_script_lock(&directory).is_ok());
+
+        drop((shared, other));
+
+        assert!(acquire_script_lock(&directory).unwrap().is_some());
+    }
+
+    #[test]
+    fn runs_beyond_max_concurrent_runs_are_queued() {
+        #[derive(Default)]
+        struct BlockingRunner {
+            is_released: AtomicBool,
+        }
+
+        impl ScriptRunner for BlockingRunner {
+            fn run(&self, _: &Settings, _: &[ScriptStep], _: &RunContext) -> RunResult {
+                while !self.is_released.load(Ordering::SeqCst) {
+                    sleep(Duration::from_millis(10));
+                }
+
+                RunResult::default()
+            }
+        }
+
+        let folder = tempfile::tempdir().unwrap();
+        let path_config = Arc::new(
+            from_str::<PathConfig>(&format!(
+                r#"
+                [settings]
+                listened_directory = '{0}'
+                script_directory = '{0}'
+                filename_prefix = ["a", "b"]
+                max_concurrent_runs = 1
+                "#,
+                folder.path().display()
+            ))
+            .unwrap(),
+        );
+        let matcher = build_version_matcher(&path_config.settings).unwrap();
+        let mut targets = build_watch_targets(&path_config);
+        let blocking = Arc::new(BlockingRunner::default());
+        let runner: Arc<dyn ScriptRunner> = blocking.clone();
+
+        run_target(
+            &mut targets[0],
+            &path_config,
+            matcher.as_ref(),
+            None,
+            &runner,
+        );
+        run_target(
+            &mut targets[1],
+            &path_config,
+            matcher.as_ref(),
+            Some(7),
+            &runner,
+        );
+
+        assert!(targets[0].state.running.is_some());
+        assert!(targets[1].state.running.is_none());
+        assert!(targets[1].state.is_run_queued);
+
+        blocking.is_released.store(true, Ordering::SeqCst);
+
+        let run = targets[0].state.running.take().unwrap();
+
+        let _ = run.handle.join();
+        start_queued_run(&mut targets[1], &path_config, matcher.as_ref(), &runner);
+
+        assert!(!targets[1].state.is_run_queued);
+        assert_eq!(
+            targets[1]
+                .state
+                .running
+                .as_ref()
+                .map(|run| run.content_hash),
+            Some(Some(7))
+        );
+
+        join_running(&mut targets);
+    }
+
+    fn describe_events(events: &[Event]) -> Vec<(EventKind, PathBuf)> {
+        events
+            .iter()
+            .map(|event| (event.kind, event.paths[0].clone()))
+            .collect()
+    }
+
+    #[test]
+    fn rename_from_hidden_to_visible_marks_modified_before_close() {
+        let event = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(PathBuf::from("/sheets/~$sheet甲.xlsx"))
+            .add_path(PathBuf::from("/sheets/sheet甲.xlsx"));
+
+        assert_eq!(
+            describe_events(&split_rename_event(&event, RenameMode::Both)),
+            vec![
+                (
+                    EventKind::Create(CreateKind::File),
+                    PathBuf::from("/sheets/sheet甲.xlsx")
+                ),
+                (
+                    EventKind::Modify(ModifyKind::Data(DataChange::Any)),
+                    PathBuf::from("/sheets/sheet甲.xlsx")
+                ),
+                (
+                    EventKind::Remove(RemoveKind::File),
+                    PathBuf::from("/sheets/~$sheet甲.xlsx")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_renames_map_to_create_and_remove() {
+        let from = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::From)))
+            .add_path(PathBuf::from("/sheets/sheet甲.xlsx"));
+        let to = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::To)))
+            .add_path(PathBuf::from("/sheets/sheet甲.tmp"));
+
+        assert_eq!(
+            describe_events(&split_rename_event(&from, RenameMode::From)),
+            vec![(
+                EventKind::Remove(RemoveKind::File),
+                PathBuf::from("/sheets/sheet甲.xlsx")
+            )]
+        );
+        assert_eq!(
+            describe_events(&split_rename_event(&to, RenameMode::To))[0],
+            (
+                EventKind::Create(CreateKind::File),
+                PathBuf::from("/sheets/sheet甲.tmp")
+            )
+        );
+    }
+
+    #[test]
+    fn partial_settings_fall_back_to_defaults() {
+        let path_config = from_str::<PathConfig>(
+            r#"
+            [settings]
+            listened_directory = "D:/sheets"
+            script_directory = "D:/scripts"
+            "#,
+        )
+        .unwrap();
+        let settings = &path_config.settings;
+
+        assert_eq!(settings.listened_directory.values(), vec!["D:/sheets"]);
+        assert_eq!(settings.script_directory, "D:/scripts");
+        assert_eq!(settings.filename_prefix.values(), vec!["sheet"]);
+        assert_eq!(
+            settings.prefix_pairs(),
+            vec![("sheet", "~$sheet".to_string())]
+        );
+        assert_eq!(settings.ext_name.values(), vec!["xlsx"]);
+        assert_eq!(settings.script_filename(), "main.py");
+        assert_eq!(settings.env_name, "base");
+    }
+
+    #[test]
+    fn each_filename_prefix_gets_its_own_target() {
+        let path_config = from_str::<PathConfig>(
+            r#"
+            [settings]
+            listened_directory = "D:/sheets"
+            script_directory = "D:/scripts"
+            filename_prefix = ["报表", "附件"]
+            hidden_filename_prefix = ["~$报表"]
+            "#,
+        )
+        .unwrap();
+        let targets = build_watch_targets(&path_config);
+        let families: Vec<(String, String)> = targets
+            .iter()
+            .map(|target| match &target.kind {
+                TargetKind::Directory(query) => {
+                    (target.source(), query.hidden_filename_prefix.clone())
+                }
+                TargetKind::File { .. } => unreachable!(),
+            })
+            .collect();
+
+        assert_eq!(
+            families,
+            vec![
+                ("D:/sheets (报表)".to_string(), "~$报表".to_string()),
+                ("D:/sheets (附件)".to_string(), "~$附件".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn conda_env_list_yields_names_and_paths() {
+        let environments = parse_conda_env_list(concat!(
+            "# conda environments:\n",
+            "#\n",
+            "base                  *  C:\\miniconda3\n",
+            "sheets                   C:\\miniconda3\\envs\\sheets\n",
+            "                         D:\\envs\\unnamed\n",
+        ));
+
+        assert_eq!(
+            environments,
+            vec![
+                "base",
+                "C:\\miniconda3",
+                "sheets",
+                "C:\\miniconda3\\envs\\sheets",
+                "D:\\envs\\unnamed",
+            ]
+        );
+    }
+
+    #[test]
+    fn results_are_posted_to_the_webhook_as_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/sheets", listener.local_addr().unwrap());
+        let server = spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut request = String::new();
+
+            while !request.ends_with("\r\n\r\n") {
+                reader.read_line(&mut request).unwrap();
+            }
+
+            let length = request
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0; length];
+
+            reader.read_exact(&mut body).unwrap();
+            write!(&stream, "HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+
+            (request, String::from_utf8(body).unwrap())
+        });
+
+        post_webhook(&url, r#"{"success":true}"#).unwrap();
+
+        let (request, body) = server.join().unwrap();
+
+        assert!(request.starts_with("POST /sheets HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Type: application/json\r\n"));
+        assert_eq!(body, r#"{"success":true}"#);
+        assert!(parse_http_url("https://ops.local/sheets").is_err());
+        assert_eq!(
+            parse_http_url("http://ops.local"),
+            Ok(("ops.local", 80, "/"))
+        );
+    }
+
+    #[test]
+    fn retrying_the_config_load_is_read_from_the_raw_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("path.toml");
+        let config_path = config_path.to_str().unwrap();
+
+        assert!(!is_config_load_retried(config_path));
+
+        std::fs::write(config_path, "[[file]]\npath = \"D:/sheets/sheet甲.xlsx\"\n").unwrap();
+        assert!(!is_config_load_retried(config_path));
+
+        std::fs::write(
+            config_path,
+            "\u{feff}[settings]\nretry_config_load = true\nunknown = [\n",
+        )
+        .unwrap();
+        assert!(!is_config_load_retried(config_path));
+
+        std::fs::write(
+            config_path,
+            "\u{feff}[settings]\nretry_config_load = true\nlistened_directory = 1\n",
+        )
+        .unwrap();
+        assert!(is_config_load_retried(config_path));
+        assert!(load_config(config_path).is_err());
+    }
+
+    #[test]
+    fn missing_conda_is_told_apart_from_script_failures() {
+        assert!(detect_missing_environment(
+            "sheets",
+            "'conda' is not recognized as an internal or external command,\r\noperable program or batch file.",
+            1,
+        )
+        .is_some_and(|problem| problem.starts_with("conda was not found")));
+        assert!(detect_missing_environment(
+            "sheets",
+            "EnvironmentNameNotFound: Could not find conda environment: sheets",
+            1,
+        )
+        .is_some_and(|problem| problem.contains("\"sheets\"")));
+        assert!(detect_missing_environment("sheets", "", COMMAND_NOT_FOUND_EXIT_CODE).is_some());
+        assert!(detect_missing_environment(
+            "sheets",
+            "Traceback (most recent call last):\nValueError: bad sheet",
+            1,
+        )
+        .is_none());
+    }
 }
@@ -0,0 +1,833 @@
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use notify::Event;
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Selection {
+    #[default]
+    Version,
+    LatestMtime,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnrecognizedVersion {
+    #[default]
+    Ignore,
+    Warn,
+    Highest,
+}
+
+const TIANGAN: [&str; 10] = ["甲", "乙", "丙", "丁", "戊", "己", "庚", "辛", "壬", "癸"];
+const DIZHI: [&str; 12] = [
+    "子", "丑", "寅", "卯", "辰", "巳", "午", "未", "申", "酉", "戌", "亥",
+];
+const EPOCH_DAY_GANZHI: i64 = 17;
+
+fn default_tiangan_order() -> Vec<String> {
+    TIANGAN
+        .iter()
+        .map(|stem| stem.to_string())
+        .chain((0..60).map(|i| format!("{}{}", TIANGAN[i % 10], DIZHI[i % 12])))
+        .collect()
+}
+
+pub fn day_ganzhi(days_since_epoch: i64) -> String {
+    let index = (days_since_epoch + EPOCH_DAY_GANZHI).rem_euclid(60) as usize;
+
+    format!("{}{}", TIANGAN[index % 10], DIZHI[index % 12])
+}
+
+pub fn generate_tiangan_map(
+    tiangan_order: Option<&[String]>,
+) -> Result<HashMap<String, usize>, String> {
+    let tiangan = match tiangan_order {
+        Some(custom) => custom.to_vec(),
+        None => default_tiangan_order(),
+    };
+    let mut map = HashMap::new();
+
+    for (i, v) in tiangan.into_iter().enumerate() {
+        if map.contains_key(&v) {
+            return Err(format!("Duplicate token {} in tiangan_order", v));
+        }
+
+        map.insert(v, i);
+    }
+
+    Ok(map)
+}
+
+pub fn get_tiangan_from_filename(
+    filename: &str,
+    filename_prefix: &str,
+    tiangan_order: &HashMap<String, usize>,
+) -> Option<usize> {
+    if let Some(pos) = filename.strip_prefix(filename_prefix) {
+        tiangan_order.get(pos).cloned()
+    } else {
+        None
+    }
+}
+
+pub trait VersionMatcher {
+    fn version_of(&self, stem: &str, prefix: &str) -> Option<usize>;
+}
+
+pub struct TianganMatcher {
+    pub tiangan_order: HashMap<String, usize>,
+}
+
+impl VersionMatcher for TianganMatcher {
+    fn version_of(&self, stem: &str, prefix: &str) -> Option<usize> {
+        get_tiangan_from_filename(stem, prefix, &self.tiangan_order)
+    }
+}
+
+pub struct NumericMatcher;
+
+impl VersionMatcher for NumericMatcher {
+    fn version_of(&self, stem: &str, prefix: &str) -> Option<usize> {
+        stem.strip_prefix(prefix)?.parse().ok()
+    }
+}
+
+pub struct FileQuery<'a> {
+    pub folder_path: &'a str,
+    pub filename_prefix: &'a str,
+    pub hidden_filename_prefix: String,
+    pub ext_names: Vec<&'a str>,
+    pub ignore_patterns: &'a [String],
+    pub selection: Selection,
+    pub unrecognized_version: UnrecognizedVersion,
+}
+
+fn has_matching_extension(path: &Path, query: &FileQuery) -> bool {
+    path.extension()
+        .map(|cur_ext| {
+            query
+                .ext_names
+                .iter()
+                .any(|ext_name| cur_ext.eq_ignore_ascii_case(ext_name))
+        })
+        .unwrap_or(false)
+}
+
+fn matches_glob(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => (0..=text.len()).any(|skip| matches_glob(rest, &text[skip..])),
+        Some((&cur_char, rest)) => text.split_first().is_some_and(|(&text_char, text_rest)| {
+            (cur_char == '?' || cur_char == text_char) && matches_glob(rest, text_rest)
+        }),
+    }
+}
+
+fn is_ignored(path: &Path, query: &FileQuery) -> bool {
+    let filename = path.file_name().unwrap_or(OsStr::new("")).to_string_lossy();
+
+    query.ignore_patterns.iter().any(|pattern| {
+        matches_wildcards(pattern, &filename).unwrap_or_else(|| filename.contains(pattern.as_str()))
+    })
+}
+
+fn matches_wildcards(pattern: &str, filename: &str) -> Option<bool> {
+    if !pattern.contains(['*', '?']) {
+        return None;
+    }
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let filename: Vec<char> = filename.chars().collect();
+
+    Some(matches_glob(&pattern, &filename))
+}
+
+pub fn matches_filename_pattern(path: &Path, pattern: &str) -> bool {
+    let Some(filename) = path.file_name().and_then(OsStr::to_str) else {
+        return false;
+    };
+
+    matches_wildcards(pattern, filename).unwrap_or(filename == pattern)
+}
+
+pub fn has_non_utf8_name(path: &Path, query: &FileQuery) -> bool {
+    has_matching_extension(path, query)
+        && path.file_name().is_some_and(|name| name.to_str().is_none())
+}
+
+pub fn has_unrecognized_version(
+    path: &Path,
+    query: &FileQuery,
+    matcher: &dyn VersionMatcher,
+) -> bool {
+    let Some(stem) = path.file_stem().and_then(OsStr::to_str) else {
+        return false;
+    };
+
+    has_matching_extension(path, query)
+        && !is_ignored(path, query)
+        && stem.starts_with(query.filename_prefix)
+        && matcher.version_of(stem, query.filename_prefix).is_none()
+}
+
+pub fn get_versioned_files(
+    query: &FileQuery,
+    matcher: &dyn VersionMatcher,
+) -> Option<Vec<(usize, PathBuf)>> {
+    Some(
+        read_dir(query.folder_path)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| has_matching_extension(path, query))
+            .filter(|path| !is_ignored(path, query))
+            .filter_map(|path| {
+                let version =
+                    match matcher.version_of(path.file_stem()?.to_str()?, query.filename_prefix) {
+                        Some(version) => version,
+                        None if query.unrecognized_version == UnrecognizedVersion::Highest
+                            && has_unrecognized_version(&path, query, matcher) =>
+                        {
+                            usize::MAX
+                        }
+                        None => return None,
+                    };
+
+                Some((version, path))
+            })
+            .collect(),
+    )
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    path.metadata()
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn compare_candidates(
+    query: &FileQuery,
+    (version, path): &(usize, PathBuf),
+    (other_version, other_path): &(usize, PathBuf),
+) -> Ordering {
+    let by_version = || version.cmp(other_version);
+    let by_mtime = || modified_at(path).cmp(&modified_at(other_path));
+
+    match query.selection {
+        Selection::Version => by_version().then_with(by_mtime),
+        Selection::LatestMtime => by_mtime().then_with(by_version),
+    }
+    .then_with(|| path.file_name().cmp(&other_path.file_name()))
+}
+
+fn get_latest_file(query: &FileQuery, matcher: &dyn VersionMatcher) -> Option<PathBuf> {
+    get_versioned_files(query, matcher)?
+        .into_iter()
+        .max_by(|candidate, other| compare_candidates(query, candidate, other))
+        .map(|(_, path)| path)
+}
+
+pub fn get_top_n_files(query: &FileQuery, matcher: &dyn VersionMatcher, n: usize) -> Vec<PathBuf> {
+    let mut candidates = get_versioned_files(query, matcher).unwrap_or_default();
+
+    candidates.sort_by(|candidate, other| compare_candidates(query, other, candidate));
+
+    candidates
+        .into_iter()
+        .take(n)
+        .map(|(_, path)| path)
+        .collect()
+}
+
+pub fn expected_hidden_path(
+    visible: &Path,
+    filename_prefix: &str,
+    hidden_filename_prefix: &str,
+) -> Option<PathBuf> {
+    visible
+        .file_name()?
+        .to_str()?
+        .strip_prefix(filename_prefix)
+        .map(|rest| visible.with_file_name(format!("{}{}", hidden_filename_prefix, rest)))
+}
+
+pub fn get_filename_with_largest_tiangan(
+    query: &FileQuery,
+    matcher: &dyn VersionMatcher,
+    is_hidden_file: bool,
+) -> Option<PathBuf> {
+    get_latest_file(query, matcher).and_then(|path| {
+        if is_hidden_file {
+            expected_hidden_path(&path, query.filename_prefix, &query.hidden_filename_prefix)
+        } else {
+            Some(path)
+        }
+    })
+}
+
+#[derive(Default)]
+pub struct LatestFileCache {
+    latest: RefCell<Option<Option<PathBuf>>>,
+    scan_count: Cell<usize>,
+}
+
+impl LatestFileCache {
+    pub fn get(
+        &self,
+        query: &FileQuery,
+        matcher: &dyn VersionMatcher,
+        is_hidden_file: bool,
+    ) -> Option<PathBuf> {
+        let scan = || {
+            self.scan_count.set(self.scan_count.get() + 1);
+            get_latest_file(query, matcher)
+        };
+        let latest = match query.selection {
+            Selection::Version => self.latest.borrow_mut().get_or_insert_with(scan).clone(),
+            Selection::LatestMtime => scan(),
+        };
+
+        if is_hidden_file {
+            latest.and_then(|path| {
+                expected_hidden_path(&path, query.filename_prefix, &query.hidden_filename_prefix)
+            })
+        } else {
+            latest
+        }
+    }
+
+    pub fn invalidate(&self) {
+        self.latest.replace(None);
+    }
+
+    pub fn scan_count(&self) -> usize {
+        self.scan_count.get()
+    }
+}
+
+pub fn is_expected_file(
+    event: &Event,
+    query: &FileQuery,
+    matcher: &dyn VersionMatcher,
+    is_hidden_file: bool,
+) -> bool {
+    if let Some(expected_hidden_filename) =
+        get_filename_with_largest_tiangan(query, matcher, is_hidden_file)
+    {
+        event
+            .paths
+            .iter()
+            .any(|path| path == &expected_hidden_filename)
+    } else {
+        false
+    }
+}
+
+pub fn is_same_file(event: &Event, expected_filename: &str) -> bool {
+    event
+        .paths
+        .iter()
+        .any(|path| path.file_name() == Some(OsStr::new(expected_filename)))
+}
+
+pub fn get_filename_from_event(event: &Event) -> Option<String> {
+    event
+        .paths
+        .iter()
+        .find_map(|path| path.file_name()?.to_str().map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{write, File};
+    use std::time::Duration;
+
+    use notify::EventKind;
+    use tempfile::{tempdir, TempDir};
+
+    use super::*;
+
+    fn query_for(folder_path: &str) -> FileQuery<'_> {
+        FileQuery {
+            folder_path,
+            filename_prefix: "sheet",
+            hidden_filename_prefix: "~$sheet".to_string(),
+            ext_names: vec!["xlsx"],
+            ignore_patterns: &[],
+            selection: Selection::Version,
+            unrecognized_version: UnrecognizedVersion::Ignore,
+        }
+    }
+
+    fn tiangan_matcher() -> TianganMatcher {
+        TianganMatcher {
+            tiangan_order: generate_tiangan_map(None).unwrap(),
+        }
+    }
+
+    fn folder_with(filenames: &[&str]) -> (TempDir, String) {
+        let folder = tempdir().unwrap();
+
+        for filename in filenames {
+            write(folder.path().join(filename), b"").unwrap();
+        }
+
+        let folder_path = folder.path().to_string_lossy().to_string();
+
+        (folder, folder_path)
+    }
+
+    type SheetFile<'a> = (&'a str, &'a str, &'a str);
+
+    fn filename_of((prefix, stem, ext): SheetFile) -> String {
+        format!("{}{}.{}", prefix, stem, ext)
+    }
+
+    fn assert_latest(files: &[SheetFile], expected: Option<SheetFile>) {
+        let filenames: Vec<String> = files.iter().copied().map(filename_of).collect();
+        let filenames: Vec<&str> = filenames.iter().map(String::as_str).collect();
+        let (folder, folder_path) = folder_with(&filenames);
+        let query = query_for(&folder_path);
+
+        assert_eq!(
+            get_filename_with_largest_tiangan(&query, &tiangan_matcher(), false),
+            expected.map(|file| folder.path().join(filename_of(file)))
+        );
+        assert_eq!(
+            get_filename_with_largest_tiangan(&query, &tiangan_matcher(), true),
+            expected.map(|(_, stem, ext)| folder.path().join(filename_of(("~$sheet", stem, ext))))
+        );
+    }
+
+    #[test]
+    fn day_ganzhi_follows_the_sexagenary_cycle() {
+        assert_eq!(day_ganzhi(0), "辛巳");
+        assert_eq!(day_ganzhi(10957), "戊午");
+        assert_eq!(day_ganzhi(10957 + 60), "戊午");
+        assert_eq!(day_ganzhi(-1), "庚辰");
+    }
+
+    #[test]
+    fn tiangan_is_parsed_after_the_prefix() {
+        let tiangan_order = generate_tiangan_map(None).unwrap();
+
+        assert_eq!(
+            get_tiangan_from_filename("sheet甲", "sheet", &tiangan_order),
+            Some(0)
+        );
+        assert_eq!(
+            get_tiangan_from_filename("sheet癸", "sheet", &tiangan_order),
+            Some(9)
+        );
+        assert_eq!(
+            get_tiangan_from_filename("sheet申", "sheet", &tiangan_order),
+            None
+        );
+        assert_eq!(
+            get_tiangan_from_filename("other甲", "sheet", &tiangan_order),
+            None
+        );
+    }
+
+    #[test]
+    fn ganzhi_tokens_sort_after_stems_in_cycle_order() {
+        let tiangan_order = generate_tiangan_map(None).unwrap();
+
+        assert_eq!(tiangan_order.len(), 70);
+        assert!(tiangan_order["癸"] < tiangan_order["甲子"]);
+        assert!(tiangan_order["甲子"] < tiangan_order["乙丑"]);
+        assert_eq!(
+            get_tiangan_from_filename("sheet乙丑", "sheet", &tiangan_order),
+            Some(11)
+        );
+    }
+
+    #[test]
+    fn duplicate_custom_tokens_are_rejected() {
+        let custom = vec!["A".to_string(), "B".to_string(), "A".to_string()];
+
+        assert!(generate_tiangan_map(Some(&custom)).is_err());
+    }
+
+    #[test]
+    fn numeric_matcher_parses_plain_suffixes() {
+        assert_eq!(NumericMatcher.version_of("report_12", "report_"), Some(12));
+        assert_eq!(NumericMatcher.version_of("report_final", "report_"), None);
+        assert_eq!(NumericMatcher.version_of("summary_1", "report_"), None);
+    }
+
+    #[test]
+    fn cached_latest_file_is_only_rescanned_after_invalidation() {
+        let (folder, folder_path) = folder_with(&["sheet甲.xlsx", "sheet乙.xlsx"]);
+        let query = query_for(&folder_path);
+        let matcher = tiangan_matcher();
+        let cache = LatestFileCache::default();
+
+        for _ in 0..20 {
+            assert_eq!(
+                cache.get(&query, &matcher, false),
+                Some(folder.path().join("sheet乙.xlsx"))
+            );
+            assert_eq!(
+                cache.get(&query, &matcher, true),
+                Some(folder.path().join("~$sheet乙.xlsx"))
+            );
+        }
+
+        assert_eq!(cache.scan_count(), 1);
+
+        write(folder.path().join("sheet丙.xlsx"), b"").unwrap();
+
+        assert_eq!(
+            cache.get(&query, &matcher, false),
+            Some(folder.path().join("sheet乙.xlsx"))
+        );
+
+        cache.invalidate();
+
+        assert_eq!(
+            cache.get(&query, &matcher, false),
+            Some(folder.path().join("sheet丙.xlsx"))
+        );
+        assert_eq!(cache.scan_count(), 2);
+    }
+
+    #[test]
+    fn empty_folder_has_no_latest_file() {
+        assert_latest(&[], None);
+    }
+
+    #[test]
+    fn files_without_a_matching_extension_are_skipped() {
+        assert_latest(&[("sheet", "乙", "csv"), ("sheet", "丙", "xls")], None);
+        assert_latest(
+            &[("sheet", "甲", "xlsx"), ("sheet", "丙", "csv")],
+            Some(("sheet", "甲", "xlsx")),
+        );
+    }
+
+    #[test]
+    fn files_with_another_prefix_are_skipped() {
+        assert_latest(&[("report", "丙", "xlsx")], None);
+        assert_latest(
+            &[
+                ("sheet", "乙", "xlsx"),
+                ("report", "丙", "xlsx"),
+                ("Sheet", "丁", "xlsx"),
+            ],
+            Some(("sheet", "乙", "xlsx")),
+        );
+    }
+
+    #[test]
+    fn top_n_files_are_returned_highest_version_first() {
+        let (folder, folder_path) = folder_with(&[
+            "sheet乙.xlsx",
+            "sheet丁.xlsx",
+            "sheet甲.xlsx",
+            "sheet丙.xlsx",
+            "other戊.xlsx",
+        ]);
+        let query = query_for(&folder_path);
+        let filenames = |n| -> Vec<String> {
+            get_top_n_files(&query, &tiangan_matcher(), n)
+                .iter()
+                .map(|path| {
+                    path.strip_prefix(folder.path())
+                        .unwrap()
+                        .to_string_lossy()
+                        .to_string()
+                })
+                .collect()
+        };
+
+        assert_eq!(filenames(2), vec!["sheet丁.xlsx", "sheet丙.xlsx"]);
+        assert_eq!(
+            filenames(10),
+            vec![
+                "sheet丁.xlsx",
+                "sheet丙.xlsx",
+                "sheet乙.xlsx",
+                "sheet甲.xlsx"
+            ]
+        );
+        assert!(filenames(0).is_empty());
+    }
+
+    #[test]
+    fn only_the_leading_prefix_is_swapped_for_the_hidden_file() {
+        let (folder, folder_path) = folder_with(&["sheet_sheet乙.xlsx"]);
+        let query = FileQuery {
+            unrecognized_version: UnrecognizedVersion::Highest,
+            ..query_for(&folder_path)
+        };
+
+        assert_eq!(
+            get_filename_with_largest_tiangan(&query, &tiangan_matcher(), true),
+            Some(folder.path().join("~$sheet_sheet乙.xlsx"))
+        );
+        assert_eq!(
+            expected_hidden_path(Path::new("dir/报表甲.xlsx"), "报表", "~$报表"),
+            Some(PathBuf::from("dir/~$报表甲.xlsx"))
+        );
+        assert_eq!(
+            expected_hidden_path(Path::new("dir/other甲.xlsx"), "报表", "~$报表"),
+            None
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_filenames_are_skipped_rather_than_mangled() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let (folder, folder_path) = folder_with(&["sheet甲.xlsx"]);
+        let non_utf8_path = folder.path().join(OsStr::from_bytes(b"sheet\xff\xfe.xlsx"));
+        let query = FileQuery {
+            unrecognized_version: UnrecognizedVersion::Highest,
+            ..query_for(&folder_path)
+        };
+
+        write(&non_utf8_path, b"").unwrap();
+
+        assert!(has_non_utf8_name(&non_utf8_path, &query));
+        assert!(!has_unrecognized_version(
+            &non_utf8_path,
+            &query,
+            &tiangan_matcher()
+        ));
+        assert_eq!(
+            get_filename_with_largest_tiangan(&query, &tiangan_matcher(), false),
+            Some(folder.path().join("sheet甲.xlsx"))
+        );
+        assert_eq!(
+            expected_hidden_path(&non_utf8_path, "sheet", "~$sheet"),
+            None
+        );
+
+        let event = Event::new(EventKind::Any).add_path(non_utf8_path);
+
+        assert_eq!(get_filename_from_event(&event), None);
+        assert!(!is_same_file(&event, "sheet\u{fffd}\u{fffd}.xlsx"));
+    }
+
+    #[test]
+    fn tied_versions_prefer_the_newest_then_the_last_filename() {
+        let (folder, folder_path) = folder_with(&["sheet乙.xlsx", "sheet乙.xlsm", "sheet乙.xlsb"]);
+        let query = FileQuery {
+            ext_names: vec!["xlsx", "xlsm", "xlsb"],
+            ..query_for(&folder_path)
+        };
+        let set_modified = |filename: &str, secs: u64| {
+            File::options()
+                .write(true)
+                .open(folder.path().join(filename))
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        set_modified("sheet乙.xlsx", 1_000);
+        set_modified("sheet乙.xlsm", 1_000);
+        set_modified("sheet乙.xlsb", 2_000);
+
+        assert_eq!(
+            get_filename_with_largest_tiangan(&query, &tiangan_matcher(), false),
+            Some(folder.path().join("sheet乙.xlsb"))
+        );
+
+        set_modified("sheet乙.xlsb", 500);
+
+        assert_eq!(
+            get_filename_with_largest_tiangan(&query, &tiangan_matcher(), false),
+            Some(folder.path().join("sheet乙.xlsx"))
+        );
+        assert_eq!(
+            get_filename_with_largest_tiangan(&query, &tiangan_matcher(), true),
+            Some(folder.path().join("~$sheet乙.xlsx"))
+        );
+        assert_eq!(
+            get_top_n_files(&query, &tiangan_matcher(), 3),
+            ["sheet乙.xlsx", "sheet乙.xlsm", "sheet乙.xlsb"]
+                .map(|filename| folder.path().join(filename))
+        );
+    }
+
+    #[test]
+    fn mixed_folder_selects_the_true_maximum() {
+        let (folder, folder_path) = folder_with(&[
+            "sheet甲.xlsx",
+            "sheet癸.xlsx",
+            "sheet甲子.xlsx",
+            "sheet丙寅.xlsx",
+            "sheet乙丑.xlsx",
+        ]);
+        let latest =
+            get_filename_with_largest_tiangan(&query_for(&folder_path), &tiangan_matcher(), false);
+
+        assert_eq!(latest, Some(folder.path().join("sheet丙寅.xlsx")));
+    }
+
+    #[test]
+    fn hidden_file_is_derived_from_the_latest_file() {
+        let (folder, folder_path) = folder_with(&["sheet甲.xlsx", "sheet乙.xlsx"]);
+        let hidden =
+            get_filename_with_largest_tiangan(&query_for(&folder_path), &tiangan_matcher(), true);
+
+        assert_eq!(hidden, Some(folder.path().join("~$sheet乙.xlsx")));
+    }
+
+    #[test]
+    fn hidden_file_is_none_when_the_prefix_does_not_match() {
+        struct AnyVersion;
+
+        impl VersionMatcher for AnyVersion {
+            fn version_of(&self, _: &str, _: &str) -> Option<usize> {
+                Some(0)
+            }
+        }
+
+        let (folder, folder_path) = folder_with(&["report甲.xlsx"]);
+        let query = query_for(&folder_path);
+
+        assert_eq!(
+            get_filename_with_largest_tiangan(&query, &AnyVersion, false),
+            Some(folder.path().join("report甲.xlsx"))
+        );
+        assert_eq!(
+            get_filename_with_largest_tiangan(&query, &AnyVersion, true),
+            None
+        );
+        assert!(!is_expected_file(
+            &Event::new(EventKind::Any).add_path(folder.path().to_path_buf()),
+            &query,
+            &AnyVersion,
+            true
+        ));
+    }
+
+    #[test]
+    fn mixed_extensions_are_all_considered() {
+        let (folder, folder_path) = folder_with(&["sheet甲.xlsx", "sheet乙.XLSM", "sheet丙.csv"]);
+        let query = FileQuery {
+            ext_names: vec!["xlsx", "xlsm"],
+            ..query_for(&folder_path)
+        };
+        let latest = get_filename_with_largest_tiangan(&query, &tiangan_matcher(), false);
+
+        assert_eq!(latest, Some(folder.path().join("sheet乙.XLSM")));
+    }
+
+    #[test]
+    fn interleaved_prefixes_are_versioned_independently() {
+        let (folder, folder_path) = folder_with(&[
+            "报表甲.xlsx",
+            "附件甲.xlsx",
+            "报表乙.xlsx",
+            "附件丙.xlsx",
+            "报表丁.xlsx",
+        ]);
+        let query_with_prefix = |filename_prefix, hidden_filename_prefix: &str| FileQuery {
+            filename_prefix,
+            hidden_filename_prefix: hidden_filename_prefix.to_string(),
+            ..query_for(&folder_path)
+        };
+        let report_query = query_with_prefix("报表", "~$报表");
+        let attachment_query = query_with_prefix("附件", "~$附件");
+
+        assert_eq!(
+            get_filename_with_largest_tiangan(&report_query, &tiangan_matcher(), false),
+            Some(folder.path().join("报表丁.xlsx"))
+        );
+        assert_eq!(
+            get_filename_with_largest_tiangan(&attachment_query, &tiangan_matcher(), false),
+            Some(folder.path().join("附件丙.xlsx"))
+        );
+        assert_eq!(
+            get_filename_with_largest_tiangan(&attachment_query, &tiangan_matcher(), true),
+            Some(folder.path().join("~$附件丙.xlsx"))
+        );
+    }
+
+    #[test]
+    fn ignored_lock_files_are_not_ranked() {
+        let (folder, folder_path) = folder_with(&["甲.xlsx", "乙.xlsx", "~$乙.xlsx"]);
+        let ignore_patterns = vec!["~$*".to_string()];
+        let query = FileQuery {
+            filename_prefix: "",
+            unrecognized_version: UnrecognizedVersion::Highest,
+            ..query_for(&folder_path)
+        };
+        let filtered_query = FileQuery {
+            filename_prefix: "",
+            ignore_patterns: &ignore_patterns,
+            unrecognized_version: UnrecognizedVersion::Highest,
+            ..query_for(&folder_path)
+        };
+        let lock_path = folder.path().join("~$乙.xlsx");
+        let unfiltered = get_filename_with_largest_tiangan(&query, &tiangan_matcher(), false);
+        let filtered =
+            get_filename_with_largest_tiangan(&filtered_query, &tiangan_matcher(), false);
+
+        assert_eq!(unfiltered, Some(lock_path.clone()));
+        assert_eq!(filtered, Some(folder.path().join("乙.xlsx")));
+        assert!(has_unrecognized_version(
+            &lock_path,
+            &query,
+            &tiangan_matcher()
+        ));
+        assert!(!has_unrecognized_version(
+            &lock_path,
+            &filtered_query,
+            &tiangan_matcher()
+        ));
+    }
+
+    #[test]
+    fn expected_file_matches_only_the_latest_version() {
+        let (folder, folder_path) = folder_with(&["sheet甲.xlsx", "sheet乙.xlsx"]);
+        let query = query_for(&folder_path);
+        let event_for =
+            |filename: &str| Event::new(EventKind::Any).add_path(folder.path().join(filename));
+
+        assert!(is_expected_file(
+            &event_for("sheet乙.xlsx"),
+            &query,
+            &tiangan_matcher(),
+            false
+        ));
+        assert!(!is_expected_file(
+            &event_for("sheet甲.xlsx"),
+            &query,
+            &tiangan_matcher(),
+            false
+        ));
+        assert!(is_expected_file(
+            &event_for("~$sheet乙.xlsx"),
+            &query,
+            &tiangan_matcher(),
+            true
+        ));
+    }
+
+    #[test]
+    fn event_filename_is_compared_without_the_directory() {
+        let event = Event::new(EventKind::Any).add_path(PathBuf::from("/sheets/~$sheet甲.xlsx"));
+
+        assert_eq!(
+            get_filename_from_event(&event),
+            Some("~$sheet甲.xlsx".to_string())
+        );
+        assert!(is_same_file(&event, "~$sheet甲.xlsx"));
+        assert!(!is_same_file(&event, "~$sheet乙.xlsx"));
+        assert_eq!(get_filename_from_event(&Event::new(EventKind::Any)), None);
+    }
+}